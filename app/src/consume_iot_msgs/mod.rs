@@ -1,7 +1,8 @@
 use async_trait::async_trait;
-use infra::amqp::{
-    client::IAmqp,
-    types::{AmqpMessageType, PublishData, PublishPayload},
+use infra::{
+    amqp::types::{AmqpMessageType, PublishData, PublishPayload},
+    mqtt::types::TempMessage,
+    repositories::iot_repository::IoTRepository,
 };
 use opentelemetry::Context;
 use serde::{Deserialize, Serialize};
@@ -9,18 +10,18 @@ use std::sync::Arc;
 
 #[async_trait]
 pub trait ConsumeIotMessageService {
-    async fn consume(&self, ctx: &Context, msg: &[u8]) -> Result<(), ()>;
+    async fn consume(&self, ctx: &Context, msg: &TempMessage) -> Result<(), ()>;
 }
 
 pub struct ConsumeIoTMessageServiceImpl {
-    amqp: Arc<dyn IAmqp + Send + Sync>,
+    repository: Arc<dyn IoTRepository + Send + Sync>,
 }
 
 impl ConsumeIoTMessageServiceImpl {
     pub fn new(
-        amqp: Arc<dyn IAmqp + Send + Sync>,
+        repository: Arc<dyn IoTRepository + Send + Sync>,
     ) -> Arc<dyn ConsumeIotMessageService + Send + Sync> {
-        Arc::new(ConsumeIoTMessageServiceImpl { amqp })
+        Arc::new(ConsumeIoTMessageServiceImpl { repository })
     }
 }
 
@@ -41,11 +42,11 @@ impl SendToAmqp {
 
 #[async_trait]
 impl ConsumeIotMessageService for ConsumeIoTMessageServiceImpl {
-    async fn consume(&self, ctx: &Context, _msg: &[u8]) -> Result<(), ()> {
+    async fn consume(&self, ctx: &Context, msg: &TempMessage) -> Result<(), ()> {
         let data = SendToAmqp::new()?;
 
-        self.amqp
-            .publish(ctx, "exchange_top_fanout", "", &data)
+        self.repository
+            .save_and_publish(ctx, msg, "exchange_top_fanout", "", &data)
             .await
             .map_err(|_| ())?;
 
@@ -1,9 +1,7 @@
 use async_trait::async_trait;
 use infra::{
-    amqp::{
-        client::IAmqp,
-        types::{AmqpMessageType, PublishData, PublishPayload},
-    },
+    amqp::types::{AmqpMessageType, PublishPayload},
+    messaging::{client::Publisher, types::Message as BusMessage},
     mqtt::types::{Message, TempMessage},
 };
 use log::info;
@@ -18,20 +16,20 @@ pub trait DeliveryIoTMessageService {
 
 #[derive(Clone)]
 pub struct DeliveryIoTMessageServiceImpl {
-    amqp: Arc<dyn IAmqp + Send + Sync>,
+    publisher: Arc<dyn Publisher + Send + Sync>,
 }
 
 impl DeliveryIoTMessageServiceImpl {
     pub fn new(
-        amqp: Arc<dyn IAmqp + Send + Sync>,
+        publisher: Arc<dyn Publisher + Send + Sync>,
     ) -> Arc<dyn DeliveryIoTMessageService + Sync + Send + 'static> {
-        Arc::new(DeliveryIoTMessageServiceImpl { amqp })
+        Arc::new(DeliveryIoTMessageServiceImpl { publisher })
     }
 
     pub fn mock(
-        amqp: Arc<dyn IAmqp + Send + Sync>,
+        publisher: Arc<dyn Publisher + Send + Sync>,
     ) -> Option<Arc<dyn DeliveryIoTMessageService + Sync + Send + 'static>> {
-        Some(Arc::new(DeliveryIoTMessageServiceImpl { amqp }))
+        Some(Arc::new(DeliveryIoTMessageServiceImpl { publisher }))
     }
 }
 
@@ -48,8 +46,8 @@ impl PublishPayload for AmqpTempMessage {
 }
 
 impl AmqpTempMessage {
-    pub fn new(msg: &TempMessage) -> Result<PublishData, ()> {
-        PublishData::new(AmqpTempMessage {
+    pub fn new(msg: &TempMessage) -> Result<BusMessage, ()> {
+        BusMessage::new(AmqpTempMessage {
             temp: msg.temp,
             time: msg.time,
         })
@@ -66,14 +64,11 @@ impl DeliveryIoTMessageService for DeliveryIoTMessageServiceImpl {
             Message::Temp(temp) => AmqpTempMessage::new(temp),
         };
 
+        let payload = payload.unwrap().key("exchange_top_test1_queue_top_test1");
+
         match self
-            .amqp
-            .publish(
-                ctx,
-                "exchange_top_test1",
-                "exchange_top_test1_queue_top_test1",
-                &payload.unwrap(),
-            )
+            .publisher
+            .publish(ctx, "exchange_top_test1", &payload)
             .await
         {
             Ok(_) => println!("mqtt success"),
@@ -1,42 +1,75 @@
 use super::{
+    compression,
+    envelope::{now_millis, Envelope, TRACEPARENT_HEADER},
+    idempotency::IdempotencyStore,
+    pool::ChannelPool,
+    router::{DeliveryHeaders, DeliveryType, UnknownTypePolicy},
+    rpc::{reply_result, RpcRequest, DIRECT_REPLY_TO},
+    stream::{delivery_offset, StreamOffset, STREAM_OFFSET_HEADER},
     topology::{
-        AmqpTopology, ConsumerDefinition, ConsumerHandler, ExchangeDefinition,
-        ExchangeKind as MyExchangeKind, QueueDefinition,
+        delay_queue_name, AmqpTopology, ConsumerDefinition, ConsumerHandler, DelayedDefinition,
+        ExchangeDefinition, ExchangeKind as MyExchangeKind, QueueDeclaration, QueueDefinition,
     },
-    types::{Metadata, PublishData},
+    types::{Metadata, PublishData, DEFAULT_CONFIRM_TIMEOUT},
+};
+use crate::{
+    env::Config,
+    errors::{AmqpError, HandlerError},
+    otel::{self, semconv, tracing::SpanGuard},
 };
-use crate::{env::Config, errors::AmqpError, otel};
 use async_trait::async_trait;
+use futures_util::{future::join_all, stream::BoxStream, StreamExt};
 use lapin::{
     message::Delivery,
     options::{
-        BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicPublishOptions,
-        ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions,
+        BasicAckOptions, BasicCancelOptions, BasicConsumeOptions, BasicNackOptions,
+        BasicPublishOptions, BasicQosOptions, ExchangeBindOptions, ExchangeDeclareOptions,
+        QueueBindOptions, QueueDeclareOptions,
     },
     protocol::basic::AMQPProperties,
-    types::{AMQPValue, FieldTable, LongInt, LongString, ShortString},
-    Channel, Connection, ConnectionProperties, Consumer, ExchangeKind, Queue,
+    publisher_confirm::{Confirmation, PublisherConfirm},
+    types::{AMQPValue, FieldTable, LongLongInt, LongString, ShortString},
+    Channel, Connection, ConnectionProperties, ExchangeKind,
 };
 use log::{debug, error, warn};
 use opentelemetry::{
     global::{self, BoxedTracer},
-    trace::{FutureExt, Span, StatusCode},
+    trace::{FutureExt, SpanKind, StatusCode},
     Context,
 };
-use std::{collections::BTreeMap, sync::Arc};
+use rand::Rng;
+use std::{
+    borrow::Cow,
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::{oneshot, Mutex as AsyncMutex};
 use uuid::Uuid;
 
+pub const FAILURE_REASON_HEADER: &str = "x-failure-reason";
+pub const ORIGINAL_EXCHANGE_HEADER: &str = "x-original-exchange";
+pub const ORIGINAL_ROUTING_KEY_HEADER: &str = "x-original-routing-key";
+
+/// Deliveries of a consumer, ending once the consumer is canceled.
+pub type Deliveries = BoxStream<'static, lapin::Result<Delivery>>;
+
 #[async_trait]
 pub trait IAmqp {
-    fn channel(&self) -> &Channel;
-    fn connection(&self) -> &Connection;
+    /// Only available when backed by a live broker.
+    fn channel(&self) -> Result<&Channel, AmqpError>;
+    /// Only available when backed by a live broker.
+    fn connection(&self) -> Result<&Connection, AmqpError>;
     async fn declare_queue(
         &self,
         name: &str,
         delete: bool,
         durable: bool,
         exclusive: bool,
-    ) -> Result<Queue, AmqpError>;
+    ) -> Result<(), AmqpError>;
     async fn declare_exchange(
         &self,
         name: &str,
@@ -50,7 +83,10 @@ pub trait IAmqp {
         queue: &str,
         key: &str,
     ) -> Result<(), AmqpError>;
-    async fn consumer(&self, queue: &str, tag: &str) -> Result<Consumer, AmqpError>;
+    /// Starts consuming on a dedicated channel, consumer names are unique.
+    async fn consumer(&self, def: &ConsumerDefinition) -> Result<Deliveries, AmqpError>;
+    /// Cancels the consumer and closes its channel, requeuing what it left unacked.
+    async fn cancel_consumer(&self, def: &ConsumerDefinition) -> Result<(), AmqpError>;
     async fn publish(
         &self,
         ctx: &Context,
@@ -58,19 +94,48 @@ pub trait IAmqp {
         key: &str,
         data: &PublishData,
     ) -> Result<(), AmqpError>;
+    async fn publish_batch(
+        &self,
+        ctx: &Context,
+        exchange: &str,
+        key: &str,
+        data: &[PublishData],
+        timeout: Duration,
+    ) -> Result<(), AmqpError>;
+    /// Publishes a request with direct reply-to and waits for its reply.
+    async fn call(
+        &self,
+        ctx: &Context,
+        exchange: &str,
+        key: &str,
+        data: &PublishData,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, AmqpError>;
     async fn install_topology(&self, topology: &AmqpTopology) -> Result<(), AmqpError>;
+    /// Learns the delay routes of a topology installed elsewhere, e.g. by
+    /// `s.topology migrate`, without declaring anything.
+    fn register_topology(&self, topology: &AmqpTopology);
     async fn consume(
         &self,
         def: &ConsumerDefinition,
         handler: Arc<dyn ConsumerHandler + Send + Sync>,
         delivery: &Delivery,
     ) -> Result<(), AmqpError>;
+    async fn close(&self) -> Result<(), AmqpError>;
 }
 
 #[derive(Debug)]
 pub struct Amqp {
     conn: Connection,
     channel: Channel,
+    publishers: ChannelPool,
+    confirm_publishers: ChannelPool,
+    consumers: Mutex<HashMap<String, Channel>>,
+    delays: DelayRoutes,
+    rpc: AsyncMutex<Option<RpcChannel>>,
+    app_name: &'static str,
+    max_message_size: usize,
+    replies: Arc<Mutex<HashMap<String, oneshot::Sender<Delivery>>>>,
     tracer: BoxedTracer,
 }
 
@@ -93,9 +158,20 @@ impl Amqp {
             .map_err(|_| AmqpError::ChannelError {})?;
         debug!("channel created");
 
+        let publishers = ChannelPool::new(&conn, cfg.amqp_publisher_channels, false).await?;
+        let confirm_publishers = ChannelPool::new(&conn, cfg.amqp_publisher_channels, true).await?;
+
         Ok(Arc::new(Amqp {
             conn,
             channel,
+            publishers,
+            confirm_publishers,
+            consumers: Mutex::new(HashMap::default()),
+            delays: DelayRoutes::default(),
+            rpc: AsyncMutex::new(None),
+            app_name: cfg.app_name,
+            max_message_size: cfg.amqp_max_message_size,
+            replies: Arc::new(Mutex::new(HashMap::default())),
             tracer: global::tracer("amqp"),
         }))
     }
@@ -103,12 +179,12 @@ impl Amqp {
 
 #[async_trait]
 impl IAmqp for Amqp {
-    fn channel(&self) -> &Channel {
-        &self.channel
+    fn channel(&self) -> Result<&Channel, AmqpError> {
+        Ok(&self.channel)
     }
 
-    fn connection(&self) -> &Connection {
-        &self.conn
+    fn connection(&self) -> Result<&Connection, AmqpError> {
+        Ok(&self.conn)
     }
 
     async fn declare_queue(
@@ -117,7 +193,7 @@ impl IAmqp for Amqp {
         delete: bool,
         durable: bool,
         exclusive: bool,
-    ) -> Result<Queue, AmqpError> {
+    ) -> Result<(), AmqpError> {
         self.channel
             .queue_declare(
                 name,
@@ -131,6 +207,7 @@ impl IAmqp for Amqp {
                 FieldTable::default(),
             )
             .await
+            .map(|_| ())
             .map_err(|_| AmqpError::DeclareQueueError(name.to_owned()))
     }
 
@@ -176,21 +253,90 @@ impl IAmqp for Amqp {
             .map_err(|_| AmqpError::BindingExchangeToQueueError(exch.to_owned(), queue.to_owned()))
     }
 
-    async fn consumer(&self, queue: &str, tag: &str) -> Result<Consumer, AmqpError> {
-        self.channel
+    async fn consumer(&self, def: &ConsumerDefinition) -> Result<Deliveries, AmqpError> {
+        // channels are kept by consumer name, a second one could never be canceled
+        if self.consumers.lock().unwrap().contains_key(def.name) {
+            error!("consumer: {} is already running", def.name);
+            return Err(AmqpError::DuplicateConsumerError(def.name.to_owned()));
+        }
+
+        debug!("creating channel for consumer: {}", def.name);
+        let channel = self
+            .conn
+            .create_channel()
+            .await
+            .map_err(|_| AmqpError::ChannelError {})?;
+
+        channel
+            .basic_qos(def.prefetch, BasicQosOptions { global: false })
+            .await
+            .map_err(|_| AmqpError::ChannelError {})?;
+
+        let mut args = FieldTable::from(def.arguments());
+        if let Some(offset) = def.stream_offset {
+            let stored = match &def.offset_store {
+                Some(store) if def.stream_offset_force => {
+                    store.reset(def.name).await?;
+                    None
+                }
+                Some(store) => store.load(def.name).await?,
+                _ => None,
+            };
+            let offset = StreamOffset::resume(stored, offset);
+            debug!("consumer: {} reading stream from {:?}", def.name, offset);
+            args.insert(ShortString::from(STREAM_OFFSET_HEADER), offset.argument());
+        }
+
+        let consumer = channel
             .basic_consume(
-                queue,
-                tag,
+                def.queue,
+                def.name,
                 BasicConsumeOptions {
                     exclusive: false,
                     no_ack: false,
                     no_local: false,
                     nowait: false,
                 },
-                FieldTable::default(),
+                args,
             )
             .await
-            .map_err(|_| AmqpError::BindingConsumerError(tag.to_owned()))
+            .map_err(|_| AmqpError::BindingConsumerError(def.name.to_owned()))?;
+
+        let duplicate = match self.consumers.lock().unwrap().entry(def.name.to_owned()) {
+            Entry::Occupied(_) => Some(channel),
+            Entry::Vacant(entry) => {
+                entry.insert(channel);
+                None
+            }
+        };
+        if let Some(channel) = duplicate {
+            error!("consumer: {} is already running", def.name);
+            let _ = channel.close(200, "duplicate consumer").await;
+            return Err(AmqpError::DuplicateConsumerError(def.name.to_owned()));
+        }
+
+        Ok(consumer.boxed())
+    }
+
+    async fn cancel_consumer(&self, def: &ConsumerDefinition) -> Result<(), AmqpError> {
+        debug!("canceling consumer: {}", def.name);
+
+        let channel = match self.consumers.lock().unwrap().remove(def.name) {
+            Some(channel) => channel,
+            _ => return Ok(()),
+        };
+
+        let canceled = channel
+            .basic_cancel(def.name, BasicCancelOptions { nowait: false })
+            .await
+            .map_err(|_| AmqpError::CancelConsumerError(def.name.to_owned()));
+        if channel.status().connected() {
+            channel
+                .close(200, "consumer canceled")
+                .await
+                .map_err(|_| AmqpError::CancelConsumerError(def.name.to_owned()))?;
+        }
+        canceled
     }
 
     async fn publish(
@@ -200,188 +346,976 @@ impl IAmqp for Amqp {
         key: &str,
         data: &PublishData,
     ) -> Result<(), AmqpError> {
-        let cx = otel::tracing::ctx_from_ctx(&self.tracer, ctx, "amqp publishing");
+        let span =
+            otel::tracing::span_from_ctx(&self.tracer, ctx, "amqp publishing", SpanKind::Producer);
+        span.set_attributes(semconv::messaging(
+            semconv::RABBITMQ,
+            exchange,
+            semconv::PUBLISH,
+            data.message_id.as_deref(),
+        ));
 
-        let mut map = BTreeMap::new();
-        map.insert(
-            ShortString::from("traceparent"),
-            AMQPValue::LongString(LongString::from(otel::amqp::Traceparent::string_from_ctx(
-                &cx,
-            ))),
+        let result = self
+            .confirmed_publish(span.context(), exchange, key, data)
+            .await;
+        span.finish(result)
+    }
+
+    async fn publish_batch(
+        &self,
+        ctx: &Context,
+        exchange: &str,
+        key: &str,
+        data: &[PublishData],
+        timeout: Duration,
+    ) -> Result<(), AmqpError> {
+        let span = otel::tracing::span_from_ctx(
+            &self.tracer,
+            ctx,
+            "amqp batch publishing",
+            SpanKind::Producer,
         );
+        span.set_attributes(semconv::messaging(
+            semconv::RABBITMQ,
+            exchange,
+            semconv::PUBLISH,
+            None,
+        ));
 
-        self.channel
-            .basic_publish(
-                exchange,
-                key,
-                BasicPublishOptions {
-                    immediate: false,
-                    mandatory: false,
-                },
-                &data.payload,
-                AMQPProperties::default()
-                    .with_content_type(ShortString::from("application/json"))
-                    .with_kind(ShortString::from(data.clone().msg_type))
-                    .with_message_id(ShortString::from(Uuid::new_v4().to_string()))
-                    .with_headers(FieldTable::from(map)),
-            )
-            .with_context(cx)
-            .await
-            .map_err(|_| AmqpError::PublishingError)?;
+        let result = self
+            .confirmed_batch(span.context(), exchange, key, data, timeout)
+            .await;
+        span.finish(result)
+    }
 
-        Ok(())
+    async fn call(
+        &self,
+        ctx: &Context,
+        exchange: &str,
+        key: &str,
+        data: &PublishData,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, AmqpError> {
+        let span =
+            otel::tracing::span_from_ctx(&self.tracer, ctx, "amqp rpc call", SpanKind::Producer);
+        span.set_attributes(semconv::messaging(
+            semconv::RABBITMQ,
+            exchange,
+            semconv::PUBLISH,
+            data.message_id.as_deref(),
+        ));
+
+        let result = self
+            .rpc_call(span.context(), exchange, key, data, timeout)
+            .await;
+        span.finish(result)
     }
 
     async fn install_topology(&self, topology: &AmqpTopology) -> Result<(), AmqpError> {
+        topology.validate()?;
+
         for exch in topology.exchanges.clone() {
             self.install_exchanges(&exch).await?;
         }
 
+        for exch in topology.exchanges.iter() {
+            self.install_exchange_bindings(exch).await?;
+        }
+
         for queue in topology.queues.clone() {
             self.install_queues(&queue).await?;
         }
 
+        for delayed in topology.delayed.clone() {
+            self.install_delayed(&delayed).await?;
+        }
+
+        self.register_topology(topology);
         Ok(())
     }
 
+    fn register_topology(&self, topology: &AmqpTopology) {
+        self.delays.register(topology);
+    }
+
     async fn consume(
         &self,
         def: &ConsumerDefinition,
         handler: Arc<dyn ConsumerHandler + Send + Sync>,
         delivery: &Delivery,
     ) -> Result<(), AmqpError> {
-        let header = match delivery.properties.headers() {
-            Some(val) => val.to_owned(),
-            None => FieldTable::default(),
-        };
-
-        let metadata = Metadata::extract(&header);
-
-        debug!(
-            "queue: {} - consumer: {} - received message: {}",
-            def.name, def.queue, metadata.msg_type
-        );
+        let (settlement, span) = settle(
+            &self.tracer,
+            self.max_message_size,
+            &self.delays,
+            def,
+            handler,
+            delivery,
+        )
+        .await;
 
-        let (ctx, mut span) = otel::amqp::get_span(&self.tracer, metadata.traceparent, def.name);
-
-        if !metadata.msg_type.is_empty() && metadata.msg_type != def.msg_type.to_string() {
-            debug!("message type dos not match, skipping msg");
-            match delivery
-                .nack(BasicNackOptions {
-                    multiple: false,
-                    requeue: true,
-                })
-                .await
-            {
-                Ok(_) => {}
-                _ => error!("error whiling ack msg"),
-            };
-            return Ok(());
-        }
-
-        match handler
-            .exec(&ctx, delivery.data.as_slice())
-            .with_context(ctx.clone())
-            .await
-        {
-            Ok(_) => match delivery.ack(BasicAckOptions { multiple: false }).await {
+        match settlement {
+            Settlement::Handled => match delivery.ack(BasicAckOptions { multiple: false }).await {
                 Ok(_) => {
                     span.set_status(StatusCode::Ok, "success".to_owned());
-                    return Ok(());
+                    Ok(())
                 }
                 _ => {
                     error!("error whiling ack msg");
                     span.set_status(StatusCode::Error, "error to ack msg".to_owned());
-                    return Err(AmqpError::AckMessageError {});
+                    Err(AmqpError::AckMessageError {})
                 }
             },
-            _ if def.with_retry => {
-                warn!("error whiling handling msg, requeuing for latter");
-                if metadata.count <= def.retries {
-                    match delivery
-                        .nack(BasicNackOptions {
-                            multiple: false,
-                            requeue: false,
-                        })
-                        .await
-                    {
-                        Ok(_) => return Ok(()),
-                        _ => {
-                            error!("error whiling requeuing");
-                            span.set_status(StatusCode::Error, "error to requeuing msg".to_owned());
-                            return Err(AmqpError::RequeuingMessageError {});
-                        }
-                    }
-                } else {
-                    error!("too many attempts, sending to dlq");
-                    match self
-                        .channel
-                        .basic_publish(
-                            "",
-                            def.dlq_name,
-                            BasicPublishOptions::default(),
-                            &delivery.data,
-                            delivery.properties.clone(),
-                        )
-                        .await
-                    {
-                        Ok(_) => {
-                            match delivery.ack(BasicAckOptions { multiple: false }).await {
-                                Ok(_) => return Ok(()),
-                                _ => {
-                                    error!("error whiling ack msg to default queue");
-                                    span.set_status(
-                                        StatusCode::Error,
-                                        "msg was sent to dlq".to_owned(),
-                                    );
-                                    return Err(AmqpError::AckMessageError {});
-                                }
-                            };
-                        }
-                        _ => {
-                            error!("error whiling sending to dlq");
-                            span.set_status(StatusCode::Error, "msg was sent to dlq".to_owned());
-                            return Err(AmqpError::PublishingToDQLError {});
-                        }
-                    };
+            Settlement::Ack => match delivery.ack(BasicAckOptions { multiple: false }).await {
+                Ok(_) => Ok(()),
+                _ => {
+                    error!("error whiling ack msg");
+                    span.set_status(StatusCode::Error, "error to ack msg".to_owned());
+                    Err(AmqpError::AckMessageError {})
+                }
+            },
+            Settlement::Nack(requeue) => match delivery
+                .nack(BasicNackOptions {
+                    multiple: false,
+                    requeue,
+                })
+                .await
+            {
+                Ok(_) => Ok(()),
+                _ => {
+                    error!("error whiling nack msg");
+                    span.set_status(StatusCode::Error, "error to nack msg".to_owned());
+                    Err(AmqpError::NackMessageError {})
                 }
+            },
+            Settlement::Retry(queue) => self.send_to_retry(delivery, &queue, &span).await,
+            Settlement::Dlq(reason) => self.send_to_dlq(def, delivery, &reason, &span).await,
+        }
+    }
+
+    async fn close(&self) -> Result<(), AmqpError> {
+        debug!("closing amqp connection...");
+
+        let channels: Vec<Channel> = self
+            .consumers
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, c)| c)
+            .collect();
+        for channel in channels {
+            if channel.status().connected() {
+                channel
+                    .close(200, "shutdown")
+                    .await
+                    .map_err(|_| AmqpError::CloseError {})?;
+            }
+        }
+
+        self.conn
+            .close(200, "shutdown")
+            .await
+            .map_err(|_| AmqpError::CloseError {})?;
+
+        debug!("amqp connection closed");
+        Ok(())
+    }
+}
+
+impl Amqp {
+    async fn send_to_dlq(
+        &self,
+        def: &ConsumerDefinition,
+        delivery: &Delivery,
+        reason: &str,
+        span: &SpanGuard,
+    ) -> Result<(), AmqpError> {
+        let properties = with_failure_reason(&with_origin(delivery), reason);
+        if let Err(err) = self.republish(delivery, &def.dlq(), properties).await {
+            error!("error whiling sending to dlq, requeuing msg - {:?}", err);
+            span.set_status(StatusCode::Error, "error to send msg to dlq".to_owned());
+            self.requeue(delivery).await?;
+            return Err(AmqpError::PublishingToDQLError {});
+        }
+
+        match delivery.ack(BasicAckOptions { multiple: false }).await {
+            Ok(_) => Ok(()),
+            _ => {
+                error!("error whiling ack msg to default queue");
+                span.set_status(StatusCode::Error, "msg was sent to dlq".to_owned());
+                Err(AmqpError::AckMessageError {})
             }
+        }
+    }
+
+    async fn send_to_retry(
+        &self,
+        delivery: &Delivery,
+        queue: &str,
+        span: &SpanGuard,
+    ) -> Result<(), AmqpError> {
+        if let Err(err) = self.republish(delivery, queue, with_origin(delivery)).await {
+            error!("error whiling sending to retry, requeuing msg - {:?}", err);
+            span.set_status(StatusCode::Error, "error to requeuing msg".to_owned());
+            self.requeue(delivery).await?;
+            return Err(AmqpError::RequeuingMessageError {});
+        }
+
+        match delivery.ack(BasicAckOptions { multiple: false }).await {
+            Ok(_) => Ok(()),
             _ => {
-                match delivery
-                    .nack(BasicNackOptions {
-                        multiple: false,
-                        requeue: false,
-                    })
+                error!("error whiling ack msg to default queue");
+                span.set_status(StatusCode::Error, "error to requeuing msg".to_owned());
+                Err(AmqpError::AckMessageError {})
+            }
+        }
+    }
+
+    /// Copies the delivery to `queue`, mandatory and confirmed so the original
+    /// is only acked once the broker holds the copy.
+    async fn republish(
+        &self,
+        delivery: &Delivery,
+        queue: &str,
+        properties: AMQPProperties,
+    ) -> Result<(), AmqpError> {
+        let channel = self.confirm_publishers.get(&self.conn).await?;
+        let confirm = channel
+            .basic_publish(
+                "",
+                queue,
+                BasicPublishOptions {
+                    immediate: false,
+                    mandatory: true,
+                },
+                &delivery.data,
+                properties,
+            )
+            .await
+            .map_err(|_| AmqpError::PublishingError {})?;
+
+        let confirmation = tokio::time::timeout(DEFAULT_CONFIRM_TIMEOUT, confirm)
+            .await
+            .map_err(|_| AmqpError::ConfirmTimeoutError {})?
+            .map_err(|_| AmqpError::PublishingError {})?;
+        confirmation_result(confirmation)
+    }
+
+    async fn requeue(&self, delivery: &Delivery) -> Result<(), AmqpError> {
+        delivery
+            .nack(BasicNackOptions {
+                multiple: false,
+                requeue: true,
+            })
+            .await
+            .map_err(|_| {
+                error!("error whiling nack msg");
+                AmqpError::NackMessageError {}
+            })
+    }
+
+    async fn confirmed_publish(
+        &self,
+        cx: &Context,
+        exchange: &str,
+        key: &str,
+        data: &PublishData,
+    ) -> Result<(), AmqpError> {
+        let confirm = self.basic_publish(cx, exchange, key, data).await?;
+
+        match data.confirm {
+            Some(timeout) => {
+                let confirmation = tokio::time::timeout(timeout, confirm)
+                    .with_context(cx.clone())
                     .await
-                {
-                    Ok(_) => return Ok(()),
-                    _ => {
-                        error!("error whiling nack msg");
-                        span.set_status(StatusCode::Error, "error to nack msg".to_owned());
-                        return Err(AmqpError::NackMessageError {});
+                    .map_err(|_| AmqpError::ConfirmTimeoutError {})?
+                    .map_err(|_| AmqpError::PublishingError {})?;
+
+                confirmation_result(confirmation)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn confirmed_batch(
+        &self,
+        cx: &Context,
+        exchange: &str,
+        key: &str,
+        data: &[PublishData],
+        timeout: Duration,
+    ) -> Result<(), AmqpError> {
+        let mut confirms = vec![];
+        for msg in data {
+            let msg = msg.clone().with_confirm(timeout);
+            confirms.push(self.basic_publish(cx, exchange, key, &msg).await?);
+        }
+
+        let confirmations = tokio::time::timeout(timeout, join_all(confirms))
+            .with_context(cx.clone())
+            .await
+            .map_err(|_| AmqpError::ConfirmTimeoutError {})?;
+
+        for confirmation in confirmations {
+            let confirmation = confirmation.map_err(|_| AmqpError::PublishingError {})?;
+            confirmation_result(confirmation)?;
+        }
+
+        Ok(())
+    }
+
+    async fn rpc_call(
+        &self,
+        cx: &Context,
+        exchange: &str,
+        key: &str,
+        data: &PublishData,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, AmqpError> {
+        let correlation_id = Uuid::new_v4().to_string();
+        let data = data
+            .clone()
+            .correlation_id(&correlation_id)
+            .reply_to(DIRECT_REPLY_TO);
+
+        // direct reply-to only delivers replies on the channel that published
+        let channel = self.rpc_channel().await?;
+        let body = checked_body(&data, self.max_message_size)?;
+        let (exchange, key, properties) =
+            properties(cx, self.app_name, &self.delays, exchange, key, &data)?;
+
+        let (tx, rx) = oneshot::channel();
+        self.replies
+            .lock()
+            .unwrap()
+            .insert(correlation_id.clone(), tx);
+        let published = channel
+            .basic_publish(
+                &exchange,
+                &key,
+                BasicPublishOptions {
+                    immediate: false,
+                    mandatory: data.mandatory,
+                },
+                &body,
+                properties,
+            )
+            .with_context(cx.clone())
+            .await;
+        if published.is_err() {
+            self.replies.lock().unwrap().remove(&correlation_id);
+            return Err(AmqpError::PublishingError {});
+        }
+
+        match tokio::time::timeout(timeout, rx)
+            .with_context(cx.clone())
+            .await
+        {
+            Ok(Ok(reply)) => reply_result(reply, self.max_message_size),
+            Ok(Err(_)) => Err(AmqpError::RpcError("reply consumer closed".to_owned())),
+            Err(_) => {
+                warn!("rpc call: {} timed out", correlation_id);
+                self.replies.lock().unwrap().remove(&correlation_id);
+                Err(AmqpError::RpcTimeoutError {})
+            }
+        }
+    }
+
+    async fn basic_publish(
+        &self,
+        cx: &Context,
+        exchange: &str,
+        key: &str,
+        data: &PublishData,
+    ) -> Result<PublisherConfirm, AmqpError> {
+        let body = checked_body(data, self.max_message_size)?;
+        let (exchange, key, properties) =
+            properties(cx, self.app_name, &self.delays, exchange, key, data)?;
+
+        let channel = match data.confirm {
+            Some(_) => self.confirm_publishers.get(&self.conn).await?,
+            _ => self.publishers.get(&self.conn).await?,
+        };
+
+        channel
+            .basic_publish(
+                &exchange,
+                &key,
+                BasicPublishOptions {
+                    immediate: false,
+                    mandatory: data.mandatory,
+                },
+                &body,
+                properties,
+            )
+            .with_context(cx.clone())
+            .await
+            .map_err(|_| AmqpError::PublishingError)
+    }
+}
+
+impl Amqp {
+    /// Channel publishing the rpc requests and consuming their replies, every
+    /// reply is handed to the `call` waiting for its correlation id.
+    /// The reply consumer is created again once its channel is closed or its
+    /// consumer stopped, calls would only time out otherwise.
+    async fn rpc_channel(&self) -> Result<Channel, AmqpError> {
+        let mut rpc = self.rpc.lock().await;
+        if let Some(current) = rpc.as_ref() {
+            if current.channel.status().connected() && current.consuming.load(Ordering::Acquire) {
+                return Ok(current.channel.clone());
+            }
+        }
+
+        debug!("creating rpc reply consumer...");
+        let channel = self
+            .conn
+            .create_channel()
+            .await
+            .map_err(|_| AmqpError::ChannelError {})?;
+
+        let mut consumer = channel
+            .basic_consume(
+                DIRECT_REPLY_TO,
+                "",
+                BasicConsumeOptions {
+                    exclusive: false,
+                    no_ack: true,
+                    no_local: false,
+                    nowait: false,
+                },
+                FieldTable::default(),
+            )
+            .await
+            .map_err(|_| AmqpError::BindingConsumerError(DIRECT_REPLY_TO.to_owned()))?;
+
+        let consuming = Arc::new(AtomicBool::new(true));
+        let replies = self.replies.clone();
+        let running = consuming.clone();
+        tokio::spawn(async move {
+            while let Some(Ok(delivery)) = consumer.next().await {
+                let id = match delivery.properties.correlation_id() {
+                    Some(id) => id.to_string(),
+                    _ => continue,
+                };
+                match replies.lock().unwrap().remove(&id) {
+                    Some(tx) => {
+                        let _ = tx.send(delivery);
                     }
+                    _ => debug!("discarding late reply: {}", id),
                 }
             }
+            running.store(false, Ordering::Release);
+            // the pending calls fail right away rather than at their timeout
+            replies.lock().unwrap().clear();
+            debug!("rpc reply consumer stopped");
+        });
+
+        *rpc = Some(RpcChannel {
+            channel: channel.clone(),
+            consuming,
+        });
+        Ok(channel)
+    }
+}
+
+/// Channel of the direct reply-to consumer, `consuming` is cleared once the
+/// consumer stops.
+#[derive(Debug)]
+struct RpcChannel {
+    channel: Channel,
+    consuming: Arc<AtomicBool>,
+}
+
+/// Payload as sent to the broker, compressed when asked.
+pub(crate) fn checked_body<'d>(
+    data: &'d PublishData,
+    max_message_size: usize,
+) -> Result<Cow<'d, [u8]>, AmqpError> {
+    let body = data.body()?;
+    if body.len() > max_message_size {
+        error!(
+            "message of {} bytes exceeds the max size of {} bytes",
+            body.len(),
+            max_message_size
+        );
+        return Err(AmqpError::MessageTooLargeError(
+            body.len(),
+            max_message_size,
+        ));
+    }
+
+    Ok(body)
+}
+
+/// Resolves the route and the properties of a message to publish.
+pub(crate) fn properties(
+    cx: &Context,
+    app_name: &str,
+    delays: &DelayRoutes,
+    exchange: &str,
+    key: &str,
+    data: &PublishData,
+) -> Result<(String, String, AMQPProperties), AmqpError> {
+    let mut map = BTreeMap::new();
+    for (key, value) in data.headers.iter() {
+        map.insert(
+            ShortString::from(key.as_str()),
+            AMQPValue::LongString(LongString::from(value.as_str())),
+        );
+    }
+
+    otel::amqp::inject(cx, &mut map);
+    let traceparent = match map.get(TRACEPARENT_HEADER) {
+        Some(AMQPValue::LongString(value)) => {
+            String::from_utf8_lossy(value.as_bytes()).into_owned()
+        }
+        _ => "".to_owned(),
+    };
+
+    let (exchange, key) = match data.delay {
+        Some(delay) => delays.route(exchange, key, delay, &mut map)?,
+        _ => (exchange.to_owned(), key.to_owned()),
+    };
+
+    let envelope = Envelope {
+        message_id: data
+            .message_id
+            .clone()
+            .unwrap_or_else(|| Uuid::new_v4().to_string()),
+        msg_type: data.msg_type.clone(),
+        schema_version: data.schema_version,
+        produced_at: now_millis(),
+        producer: data.producer.clone().unwrap_or_else(|| app_name.to_owned()),
+        device_id: data.device_id.clone(),
+        traceparent,
+        content_type: data.content_type.clone(),
+    };
+
+    let mut properties = envelope.write(AMQPProperties::default(), map);
+    if let Some(id) = &data.correlation_id {
+        properties = properties.with_correlation_id(ShortString::from(id.as_str()));
+    }
+    if let Some(reply_to) = &data.reply_to {
+        properties = properties.with_reply_to(ShortString::from(reply_to.as_str()));
+    }
+    if let Some(priority) = data.priority {
+        properties = properties.with_priority(priority);
+    }
+    if let Some(encoding) = data.encoding() {
+        properties = properties.with_content_encoding(ShortString::from(encoding));
+    }
+
+    Ok((exchange, key, properties))
+}
+
+/// Delayed exchanges and delay holding queues of the known topologies.
+#[derive(Debug, Default)]
+pub(crate) struct DelayRoutes {
+    pub(crate) exchanges: Mutex<HashSet<String>>,
+    pub(crate) queues: Mutex<HashSet<String>>,
+}
+
+impl DelayRoutes {
+    pub(crate) fn register(&self, topology: &AmqpTopology) {
+        self.exchanges.lock().unwrap().extend(
+            topology
+                .exchanges
+                .iter()
+                .filter(|exch| exch.delayed)
+                .map(|exch| exch.name.to_owned()),
+        );
+        self.queues.lock().unwrap().extend(
+            topology
+                .delayed
+                .iter()
+                .flat_map(|delayed| delayed.holding_queues())
+                .map(|(name, _)| name),
+        );
+    }
+
+    /// Resolves where a delayed message must be published: straight to a
+    /// plugin delayed exchange with the `x-delay` header, or to the holding
+    /// queue declared for this exchange, routing key and delay.
+    fn route(
+        &self,
+        exchange: &str,
+        key: &str,
+        delay: Duration,
+        headers: &mut BTreeMap<ShortString, AMQPValue>,
+    ) -> Result<(String, String), AmqpError> {
+        if self.exchanges.lock().unwrap().contains(exchange) {
+            headers.insert(
+                ShortString::from("x-delay"),
+                AMQPValue::LongLongInt(LongLongInt::from(delay.as_millis() as i64)),
+            );
+            return Ok((exchange.to_owned(), key.to_owned()));
+        }
+
+        let queue = delay_queue_name(exchange, key, delay);
+        if self.queues.lock().unwrap().contains(&queue) {
+            return Ok(("".to_owned(), queue));
+        }
+
+        error!("no delay route declared for: {}", queue);
+        Err(AmqpError::DelayNotDeclaredError(queue))
+    }
+
+    /// Holding queue of the default exchange dead-lettering to `queue` once
+    /// `delay` elapses, if declared.
+    fn retry_queue(&self, queue: &str, delay: Duration) -> Option<String> {
+        let name = delay_queue_name("", queue, delay);
+        match self.queues.lock().unwrap().contains(&name) {
+            true => Some(name),
+            _ => None,
+        }
+    }
+}
+
+/// What is left to do with a delivery once its handler ran.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Settlement {
+    /// Handled successfully and acked
+    Handled,
+    /// Acked without being handled, i.e. dropped or already processed
+    Ack,
+    /// Nacked, requeued when `true` and dead-lettered otherwise
+    Nack(bool),
+    /// Republished to the retry queue
+    Retry(String),
+    /// Republished to the DLQ with the failure reason
+    Dlq(String),
+}
+
+/// Runs the handler of a delivery and decides how it is settled, so every
+/// `IAmqp` applies the same retry and DLQ rules.
+pub(crate) async fn settle(
+    tracer: &BoxedTracer,
+    max_message_size: usize,
+    delays: &DelayRoutes,
+    def: &ConsumerDefinition,
+    handler: Arc<dyn ConsumerHandler + Send + Sync>,
+    delivery: &Delivery,
+) -> (Settlement, SpanGuard) {
+    let header = match delivery.properties.headers() {
+        Some(val) => val.to_owned(),
+        None => FieldTable::default(),
+    };
+
+    let metadata = Metadata::from_properties(&delivery.properties);
+    let attempts = metadata.attempts(def.queue);
+    let offset = delivery_offset(&header);
+    let msg_type = DeliveryType(metadata.msg_type.clone());
+    let envelope = metadata.envelope.clone();
+
+    debug!(
+        "queue: {} - consumer: {} - received message: {}",
+        def.name, def.queue, msg_type.0
+    );
+
+    let span = otel::amqp::get_span(tracer, &header, def.name);
+    span.set_attributes(semconv::messaging(
+        semconv::RABBITMQ,
+        def.queue,
+        semconv::PROCESS,
+        Some(&envelope.message_id),
+    ));
+    let ctx = match RpcRequest::from_delivery(delivery) {
+        Some(req) => span.context().with_value(req),
+        _ => span.context().clone(),
+    };
+
+    let message_id = envelope.message_id.clone();
+    let ctx = ctx
+        .with_value(msg_type.clone())
+        .with_value(envelope)
+        .with_value(DeliveryHeaders::from_delivery(delivery));
+
+    if !def.accepts(&msg_type.0) || !handler.handles(&ctx) {
+        let settlement = unknown_type(def, &msg_type.0);
+        save_offset(def, offset, &settlement).await;
+        return (settlement, span);
+    }
+
+    if let Some(store) = &def.idempotency {
+        if already_processed(store, def, &message_id).await {
+            debug!("message: {} already processed, skipping", message_id);
+            save_offset(def, offset, &Settlement::Ack).await;
+            return (Settlement::Ack, span);
+        }
+    }
+
+    let encoding = delivery.properties.content_encoding().as_ref();
+    let data = compression::decode(
+        encoding.map(|e| e.as_str()),
+        &delivery.data,
+        max_message_size,
+    );
+    let result = match data {
+        Ok(data) => handler.exec(&ctx, &data).with_context(ctx.clone()).await,
+        Err(err) => Err(HandlerError::Reject(err.to_string())),
+    };
+
+    if let Some(store) = &def.idempotency {
+        if !message_id.is_empty() && matches!(result, Ok(_) | Err(HandlerError::Drop(_))) {
+            if let Err(err) = store.mark(def.name, &message_id).await {
+                error!("{:?}", err);
+            }
+        }
+    }
+
+    match &result {
+        Ok(_) | Err(HandlerError::Drop(_)) => {}
+        Err(err) => span.record_error(err),
+    }
+
+    let settlement = match result {
+        Ok(_) => Settlement::Handled,
+        Err(HandlerError::Drop(reason)) => {
+            warn!("dropping msg - {}", reason);
+            Settlement::Ack
+        }
+        Err(HandlerError::Reject(reason)) if def.with_dlq || def.with_retry => {
+            error!(
+                "unprocessable msg, sending to dlq without retrying - {}",
+                reason
+            );
+            Settlement::Dlq(reason)
+        }
+        Err(HandlerError::Retry(reason)) if def.with_retry => {
+            warn!(
+                "error whiling handling msg, requeuing for latter - {}",
+                reason
+            );
+            retry_or_dlq(def, delays, attempts, None, reason)
+        }
+        Err(HandlerError::RetryAfter(delay, reason)) if def.with_retry => {
+            warn!(
+                "error whiling handling msg, requeuing in {:?} - {}",
+                delay, reason
+            );
+            retry_or_dlq(def, delays, attempts, Some(delay), reason)
+        }
+        Err(HandlerError::Retry(reason) | HandlerError::RetryAfter(_, reason)) if def.with_dlq => {
+            error!(
+                "error whiling handling msg without retry, sending to dlq - {}",
+                reason
+            );
+            Settlement::Dlq(reason)
+        }
+        Err(err) => {
+            error!("error whiling handling msg - {}", err);
+            Settlement::Nack(false)
+        }
+    };
+
+    save_offset(def, offset, &settlement).await;
+    (settlement, span)
+}
+
+/// Streams keep every message, the stored offset is what moves a restarted
+/// consumer past the ones already settled. Only a requeued delivery comes
+/// back, its offset stays pending until then.
+async fn save_offset(def: &ConsumerDefinition, offset: Option<u64>, settlement: &Settlement) {
+    let (store, offset) = match (&def.offset_store, offset) {
+        (Some(store), Some(offset)) if *settlement != Settlement::Nack(true) => (store, offset),
+        _ => return,
+    };
+
+    if let Some(offset) = def.offset_tracker.settled(offset) {
+        if let Err(err) = store.save(def.name, offset).await {
+            error!("{:?}", err);
         }
     }
 }
 
+fn retry_or_dlq(
+    def: &ConsumerDefinition,
+    delays: &DelayRoutes,
+    attempts: i64,
+    delay: Option<Duration>,
+    reason: String,
+) -> Settlement {
+    if attempts < def.retries {
+        return Settlement::Retry(retry_route(def, delays, attempts, delay));
+    }
+
+    error!("too many attempts, sending to dlq");
+    Settlement::Dlq(reason)
+}
+
+/// Store failures let the message through, handling twice beats losing it.
+async fn already_processed(
+    store: &Arc<dyn IdempotencyStore + Send + Sync>,
+    def: &ConsumerDefinition,
+    message_id: &str,
+) -> bool {
+    if message_id.is_empty() {
+        return false;
+    }
+
+    match store.seen(def.name, message_id).await {
+        Ok(seen) => seen,
+        Err(err) => {
+            error!("{:?}", err);
+            false
+        }
+    }
+}
+
+fn unknown_type(def: &ConsumerDefinition, msg_type: &str) -> Settlement {
+    let reason = format!("unknown message type `{}`", msg_type);
+
+    match def.unknown_type {
+        UnknownTypePolicy::Requeue => {
+            debug!("message type dos not match, requeuing msg");
+            Settlement::Nack(true)
+        }
+        UnknownTypePolicy::Drop => {
+            warn!("dropping msg - {}", reason);
+            Settlement::Ack
+        }
+        UnknownTypePolicy::Dlq if def.with_dlq || def.with_retry => {
+            warn!("sending msg to dlq - {}", reason);
+            Settlement::Dlq(reason)
+        }
+        UnknownTypePolicy::Dlq => {
+            warn!("rejecting msg without dlq - {}", reason);
+            Settlement::Nack(false)
+        }
+    }
+}
+
+/// Picks the retry queue for the next attempt.
+///
+/// Attempts walk the tiers in order and stay on the last one once exhausted,
+/// a jittered tier picks one of its queues at random. An explicit `delay` goes
+/// to the holding queue declared for it, or else to the first tier at least as
+/// long, delays over the last tier are cut to it.
+fn retry_route(
+    def: &ConsumerDefinition,
+    delays: &DelayRoutes,
+    attempts: i64,
+    delay: Option<Duration>,
+) -> String {
+    let tiers = def.retry_queues();
+    let last = tiers.len() - 1;
+
+    let idx = match delay {
+        Some(delay) => {
+            if let Some(queue) = delays.retry_queue(def.queue, delay) {
+                return queue;
+            }
+
+            let ms = delay.as_millis();
+            match def.retry_tiers.iter().position(|ttl| *ttl as u128 >= ms) {
+                Some(idx) => {
+                    warn!(
+                        "no retry delay of {:?} declared for: {}, retrying after {} ms",
+                        delay, def.queue, def.retry_tiers[idx]
+                    );
+                    idx
+                }
+                _ => {
+                    warn!(
+                        "retry delay of {:?} exceeds the last tier of: {}, retrying after {:?} ms",
+                        delay,
+                        def.queue,
+                        def.retry_tiers.last()
+                    );
+                    last
+                }
+            }
+        }
+        _ => (attempts.max(0) as usize).min(last),
+    };
+
+    let queues = &tiers[idx];
+    queues[rand::thread_rng().gen_range(0..queues.len())].clone()
+}
+
+/// Copies the message properties adding the `x-failure-reason` header, so the
+/// cause is kept alongside the dead-lettered message.
+pub(crate) fn with_failure_reason(properties: &AMQPProperties, reason: &str) -> AMQPProperties {
+    let mut headers = match properties.headers() {
+        Some(headers) => headers.inner().clone(),
+        None => BTreeMap::new(),
+    };
+    headers.insert(
+        ShortString::from(FAILURE_REASON_HEADER),
+        AMQPValue::LongString(LongString::from(reason)),
+    );
+
+    properties.clone().with_headers(FieldTable::from(headers))
+}
+
+/// Copies the message properties recording the exchange and routing key the
+/// message was first published to. Retry and DLQ hops go through the default
+/// exchange, so only the first hop knows the original destination.
+pub(crate) fn with_origin(delivery: &Delivery) -> AMQPProperties {
+    let mut headers = match delivery.properties.headers() {
+        Some(headers) => headers.inner().clone(),
+        None => BTreeMap::new(),
+    };
+
+    if !headers.contains_key(ORIGINAL_EXCHANGE_HEADER) {
+        headers.insert(
+            ShortString::from(ORIGINAL_EXCHANGE_HEADER),
+            AMQPValue::LongString(LongString::from(delivery.exchange.to_string())),
+        );
+        headers.insert(
+            ShortString::from(ORIGINAL_ROUTING_KEY_HEADER),
+            AMQPValue::LongString(LongString::from(delivery.routing_key.to_string())),
+        );
+    }
+
+    delivery
+        .properties
+        .clone()
+        .with_headers(FieldTable::from(headers))
+}
+
+pub(crate) fn confirmation_result(confirmation: Confirmation) -> Result<(), AmqpError> {
+    match confirmation {
+        Confirmation::Ack(Some(returned)) => {
+            error!(
+                "message returned by the broker - exchange: {} - key: {} - reason: {}",
+                returned.delivery.exchange, returned.delivery.routing_key, returned.reply_text
+            );
+            Err(AmqpError::ReturnedMessageError(
+                returned.reply_code,
+                returned.reply_text.to_string(),
+            ))
+        }
+        Confirmation::Nack(_) => {
+            error!("message nacked by the broker");
+            Err(AmqpError::PublishNackedError {})
+        }
+        _ => Ok(()),
+    }
+}
+
 impl Amqp {
     async fn install_exchanges<'i>(&self, exch: &'i ExchangeDefinition) -> Result<(), AmqpError> {
         debug!("creating exchange: {}", exch.name);
 
+        let kind = match exch.delayed {
+            true => ExchangeKind::Custom("x-delayed-message".to_owned()),
+            _ => MyExchangeKind::map(exch.kind.clone()),
+        };
+
         self.channel
             .exchange_declare(
                 exch.name,
-                MyExchangeKind::map(exch.kind.clone()),
+                kind,
                 ExchangeDeclareOptions {
-                    auto_delete: false,
-                    durable: true,
-                    internal: false,
+                    auto_delete: exch.auto_delete,
+                    durable: exch.durable,
+                    internal: exch.internal,
                     nowait: false,
                     passive: false,
                 },
-                FieldTable::default(),
+                FieldTable::from(exch.arguments()),
             )
             .await
             .map_err(|_| AmqpError::DeclareExchangeError(exch.name.to_owned()))?;
@@ -390,29 +1324,53 @@ impl Amqp {
 
         Ok(())
     }
+
+    async fn install_exchange_bindings<'i>(
+        &self,
+        exch: &'i ExchangeDefinition,
+    ) -> Result<(), AmqpError> {
+        for bind in exch.bindings.iter() {
+            debug!(
+                "binding exchange: {} to exchange: {}",
+                bind.source, bind.destination
+            );
+
+            self.channel
+                .exchange_bind(
+                    bind.destination,
+                    bind.source,
+                    bind.routing_key,
+                    ExchangeBindOptions { nowait: false },
+                    FieldTable::default(),
+                )
+                .await
+                .map_err(|_| {
+                    AmqpError::BindingExchangeToExchangeError(
+                        bind.source.to_owned(),
+                        bind.destination.to_owned(),
+                    )
+                })?;
+        }
+
+        Ok(())
+    }
+
+    async fn install_delayed<'i>(&self, def: &'i DelayedDefinition) -> Result<(), AmqpError> {
+        for declaration in def.declarations() {
+            self.declare(&declaration).await?;
+        }
+
+        Ok(())
+    }
 }
 
 impl Amqp {
     async fn install_queues<'i>(&self, def: &'i QueueDefinition) -> Result<(), AmqpError> {
         debug!("creating and binding queue: {}", def.name);
 
-        let queue_map = self.install_retry(def).await?;
-        let queue_map = self.install_dlq(def, queue_map).await?;
-
-        self.channel
-            .queue_declare(
-                def.name,
-                QueueDeclareOptions {
-                    passive: false,
-                    durable: true,
-                    exclusive: false,
-                    auto_delete: false,
-                    nowait: false,
-                },
-                FieldTable::from(queue_map),
-            )
-            .await
-            .map_err(|_| AmqpError::DeclareQueueError(def.name.to_owned()))?;
+        for declaration in def.declarations() {
+            self.declare(&declaration).await?;
+        }
 
         for bind in def.clone().bindings {
             self.channel
@@ -421,7 +1379,7 @@ impl Amqp {
                     bind.exchange,
                     bind.routing_key,
                     QueueBindOptions { nowait: false },
-                    FieldTable::default(),
+                    FieldTable::from(bind.arguments()),
                 )
                 .await
                 .map_err(|_| {
@@ -437,125 +1395,277 @@ impl Amqp {
         Ok(())
     }
 
-    async fn install_retry<'i>(
-        &self,
-        def: &'i QueueDefinition,
-    ) -> Result<BTreeMap<ShortString, AMQPValue>, AmqpError> {
-        if !def.with_retry {
-            return Ok(BTreeMap::new());
-        }
+    async fn declare(&self, declaration: &QueueDeclaration) -> Result<(), AmqpError> {
+        debug!("declaring queue: {}", declaration.name);
 
-        debug!("creating retry...");
-        let mut retry_map = BTreeMap::new();
-        retry_map.insert(
-            ShortString::from("x-dead-letter-exchange"),
-            AMQPValue::LongString(LongString::from("")),
-        );
-        retry_map.insert(
-            ShortString::from("x-dead-letter-routing-key"),
-            AMQPValue::LongString(LongString::from(def.name)),
-        );
-        retry_map.insert(
-            ShortString::from("x-message-ttl"),
-            AMQPValue::LongInt(LongInt::from(def.retry_ttl.unwrap())),
-        );
-
-        let name = self.retry_name(def.name);
         self.channel
             .queue_declare(
-                &name,
+                &declaration.name,
                 QueueDeclareOptions {
                     passive: false,
-                    durable: true,
-                    exclusive: false,
-                    auto_delete: false,
+                    durable: declaration.durable,
+                    exclusive: declaration.exclusive,
+                    auto_delete: declaration.auto_delete,
                     nowait: false,
                 },
-                FieldTable::from(retry_map),
+                FieldTable::from(declaration.arguments.clone()),
             )
             .await
-            .map_err(|_| AmqpError::DeclareQueueError(name.clone()))?;
+            .map_err(|_| AmqpError::DeclareQueueError(declaration.name.clone()))?;
 
-        let mut queue_map = BTreeMap::new();
-        queue_map.insert(
-            ShortString::from("x-dead-letter-exchange"),
-            AMQPValue::LongString(LongString::from("")),
-        );
+        Ok(())
+    }
+}
+
+/// Delivery from the default exchange, as the tests of the module need one.
+#[cfg(test)]
+pub(crate) fn delivery(properties: AMQPProperties, data: &[u8]) -> Delivery {
+    Delivery {
+        delivery_tag: 1,
+        exchange: ShortString::from(""),
+        routing_key: ShortString::from("queue"),
+        redelivered: false,
+        properties,
+        data: data.to_vec(),
+        acker: lapin::acker::Acker::default(),
+    }
+}
 
-        queue_map.insert(
-            ShortString::from("x-dead-letter-routing-key"),
-            AMQPValue::LongString(LongString::from(name)),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amqp::{memory::InMemoryAmqp, types::AmqpMessageType};
+    use lapin::message::BasicReturnMessage;
+
+    struct Succeeding;
+
+    #[async_trait]
+    impl ConsumerHandler for Succeeding {
+        async fn exec(&self, ctx: &Context, data: &[u8]) -> Result<(), HandlerError> {
+            assert_eq!(ctx.get::<DeliveryType>().unwrap().0, "Temp");
+            assert_eq!(data, b"{}");
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_consume_successfully() {
+        let amqp = InMemoryAmqp::new(&Config::mock());
+        let topology = AmqpTopology::new().queue(
+            QueueDefinition::name("queue")
+                .msg_type(AmqpMessageType::Temp)
+                .with_dlq()
+                .with_retry(1000, 3),
         );
-        debug!("retry created");
+        amqp.install_topology(&topology).await.unwrap();
+        let def = topology.get_consumers_def("queue").unwrap();
+
+        let data = PublishData::raw("Temp", b"{}".to_vec());
+        amqp.publish(&Context::new(), "", "queue", &data)
+            .await
+            .unwrap();
 
-        Ok(queue_map)
+        let delivery = amqp.get("queue").unwrap();
+        amqp.consume(&def, Arc::new(Succeeding), &delivery)
+            .await
+            .unwrap();
+
+        assert_eq!(amqp.unacked("queue"), 0);
+        assert!(amqp.messages("queue").is_empty());
+        assert!(amqp.messages("queue-retry").is_empty());
+        assert!(amqp.messages("queue-dlq").is_empty());
     }
 
-    async fn install_dlq<'i>(
-        &self,
-        def: &'i QueueDefinition,
-        queue_map_from_retry: BTreeMap<ShortString, AMQPValue>,
-    ) -> Result<BTreeMap<ShortString, AMQPValue>, AmqpError> {
-        if !def.with_dlq && !def.with_retry {
-            return Ok(BTreeMap::new());
-        }
+    struct FailingOn(&'static [u8]);
 
-        debug!("creating DLQ...");
-        let mut queue_map = queue_map_from_retry;
-        let name = self.dlq_name(def.name);
+    #[async_trait]
+    impl ConsumerHandler for FailingOn {
+        async fn exec(&self, _ctx: &Context, data: &[u8]) -> Result<(), HandlerError> {
+            match data == self.0 {
+                true => Err(HandlerError::Retry("failure".to_owned())),
+                _ => Ok(()),
+            }
+        }
+    }
 
-        if !def.with_retry {
-            queue_map.insert(
-                ShortString::from("x-dead-letter-exchange"),
-                AMQPValue::LongString(LongString::from("")),
-            );
+    #[tokio::test]
+    async fn test_settle_saves_stream_offsets() {
+        let store = crate::amqp::stream::InMemoryOffsetStore::new();
+        let def = ConsumerDefinition::name("consumer")
+            .queue("telemetry")
+            .with_dlq()
+            .stream(StreamOffset::First)
+            .offset_store(store.clone());
+        let handler = Arc::new(FailingOn(b"fail"));
 
-            queue_map.insert(
-                ShortString::from("x-dead-letter-routing-key"),
-                AMQPValue::LongString(LongString::from(name.clone())),
-            );
+        let offsets: [(u64, &[u8]); 4] = [(0, b"{}"), (1, b"fail"), (2, b"{}"), (3, b"{}")];
+        for (offset, _) in offsets {
+            def.offset_tracker.delivered(offset);
         }
 
-        self.channel
-            .queue_declare(
-                &name,
-                QueueDeclareOptions {
-                    passive: false,
-                    durable: true,
-                    exclusive: false,
-                    auto_delete: false,
-                    nowait: false,
-                },
-                FieldTable::default(),
+        for (offset, data) in offsets {
+            let mut headers = BTreeMap::new();
+            headers.insert(
+                ShortString::from(STREAM_OFFSET_HEADER),
+                AMQPValue::LongLongInt(offset as i64),
+            );
+            let properties = AMQPProperties::default().with_headers(FieldTable::from(headers));
+            let (settlement, _) = settle(
+                &global::tracer("amqp"),
+                1024,
+                &DelayRoutes::default(),
+                &def,
+                handler.clone(),
+                &delivery(properties, data),
             )
-            .await
-            .map_err(|_| AmqpError::DeclareQueueError(name))?;
-        debug!("DLQ created");
+            .await;
 
-        Ok(queue_map)
+            match offset {
+                1 => assert_eq!(settlement, Settlement::Dlq("failure".to_owned())),
+                _ => assert_eq!(settlement, Settlement::Handled),
+            }
+            assert_eq!(store.load("consumer").await.unwrap(), Some(offset));
+        }
     }
 
-    fn retry_name(&self, queue: &str) -> String {
-        format!("{}-retry", queue)
-    }
+    #[test]
+    fn test_with_failure_reason() {
+        let props = with_failure_reason(&AMQPProperties::default(), "invalid payload");
+        let headers = props.headers().clone().unwrap();
+        assert_eq!(
+            headers.inner().get(FAILURE_REASON_HEADER),
+            Some(&AMQPValue::LongString(LongString::from("invalid payload")))
+        );
 
-    fn _retry_key(&self, queue: &str) -> String {
-        format!("{}-retry-key", queue)
+        let mut map = BTreeMap::new();
+        map.insert(
+            ShortString::from("traceparent"),
+            AMQPValue::LongString(LongString::from("traceparent")),
+        );
+        let props = AMQPProperties::default().with_headers(FieldTable::from(map));
+        let props = with_failure_reason(&props, "too many attempts");
+        let headers = props.headers().clone().unwrap();
+        assert_eq!(headers.inner().len(), 2);
     }
 
-    fn dlq_name(&self, queue: &str) -> String {
-        format!("{}-dlq", queue)
+    #[test]
+    fn test_with_origin() {
+        let mut delivery = delivery(AMQPProperties::default(), b"");
+        delivery.exchange = ShortString::from("exchange");
+        delivery.routing_key = ShortString::from("key");
+
+        let props = with_origin(&delivery);
+        let headers = props.headers().clone().unwrap();
+        assert_eq!(
+            headers.inner().get(ORIGINAL_EXCHANGE_HEADER),
+            Some(&AMQPValue::LongString(LongString::from("exchange")))
+        );
+        assert_eq!(
+            headers.inner().get(ORIGINAL_ROUTING_KEY_HEADER),
+            Some(&AMQPValue::LongString(LongString::from("key")))
+        );
+
+        delivery.exchange = ShortString::from("");
+        delivery.routing_key = ShortString::from("queue");
+        delivery.properties = props;
+        let headers = with_origin(&delivery).headers().clone().unwrap();
+        assert_eq!(
+            headers.inner().get(ORIGINAL_EXCHANGE_HEADER),
+            Some(&AMQPValue::LongString(LongString::from("exchange")))
+        );
     }
 
-    fn _dlq_key(&self, queue: &str) -> String {
-        format!("{}-dlq-key", queue)
+    #[test]
+    fn test_confirmation_result() {
+        assert!(confirmation_result(Confirmation::Ack(None)).is_ok());
+        assert!(confirmation_result(Confirmation::NotRequested).is_ok());
+        assert_eq!(
+            confirmation_result(Confirmation::Nack(None)),
+            Err(AmqpError::PublishNackedError {})
+        );
+
+        let returned = BasicReturnMessage {
+            delivery: delivery(AMQPProperties::default(), b""),
+            reply_code: 312,
+            reply_text: ShortString::from("NO_ROUTE"),
+        };
+        assert_eq!(
+            confirmation_result(Confirmation::Ack(Some(Box::new(returned)))),
+            Err(AmqpError::ReturnedMessageError(312, "NO_ROUTE".to_owned()))
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    // use super::*;
+    #[test]
+    fn test_delay_routes() {
+        let delays = DelayRoutes::default();
+        let delay = Duration::from_secs(30);
+        let mut headers = BTreeMap::new();
+        assert_eq!(
+            delays.route("exchange", "key", delay, &mut headers),
+            Err(AmqpError::DelayNotDeclaredError(
+                "exchange.key.delay-30000".to_owned()
+            ))
+        );
+
+        delays.register(
+            &AmqpTopology::new()
+                .exchange(ExchangeDefinition::name("delayed").delayed())
+                .delayed(DelayedDefinition::new("exchange", "key").delay(delay)),
+        );
+        assert_eq!(
+            delays.route("exchange", "key", delay, &mut headers),
+            Ok(("".to_owned(), "exchange.key.delay-30000".to_owned()))
+        );
+        assert!(headers.is_empty());
+        assert_eq!(
+            delays.route("delayed", "key", delay, &mut headers),
+            Ok(("delayed".to_owned(), "key".to_owned()))
+        );
+        assert_eq!(headers.get("x-delay"), Some(&AMQPValue::LongLongInt(30000)));
+    }
 
     #[test]
-    fn test_consume_successfully() {}
+    fn test_retry_route() {
+        let delays = DelayRoutes::default();
+        let def = ConsumerDefinition {
+            queue: "queue",
+            ..ConsumerDefinition::default()
+        };
+        assert_eq!(retry_route(&def, &delays, 3, None), "queue-retry");
+
+        let def = ConsumerDefinition {
+            queue: "queue",
+            retry_tiers: vec![1000, 10000, 60000],
+            ..ConsumerDefinition::default()
+        };
+        assert_eq!(retry_route(&def, &delays, 0, None), "queue-retry-1000");
+        assert_eq!(retry_route(&def, &delays, 5, None), "queue-retry-60000");
+        assert_eq!(
+            retry_route(&def, &delays, 0, Some(Duration::from_secs(5))),
+            "queue-retry-10000"
+        );
+        assert_eq!(
+            retry_route(&def, &delays, 0, Some(Duration::from_secs(120))),
+            "queue-retry-60000"
+        );
+
+        delays
+            .queues
+            .lock()
+            .unwrap()
+            .insert(delay_queue_name("", "queue", Duration::from_secs(5)));
+        assert_eq!(
+            retry_route(&def, &delays, 0, Some(Duration::from_secs(5))),
+            ".queue.delay-5000"
+        );
+
+        let def = ConsumerDefinition {
+            retry_jitter: 0.5,
+            ..def
+        };
+        let tier = def.retry_queues().remove(1);
+        for _ in 0..20 {
+            assert!(tier.contains(&retry_route(&def, &delays, 1, None)));
+        }
+    }
 }
@@ -0,0 +1,184 @@
+use crate::errors::AmqpError;
+use flate2::{read::GzDecoder, write::GzEncoder};
+use log::error;
+use std::{
+    borrow::Cow,
+    io::{Read, Write},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    /// Value of the `content_encoding` property.
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Lz4 => "lz4",
+        }
+    }
+
+    pub fn from_encoding(encoding: &str) -> Option<Compression> {
+        match encoding {
+            "gzip" => Some(Compression::Gzip),
+            "zstd" => Some(Compression::Zstd),
+            "lz4" => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, AmqpError> {
+        let compressed = match self {
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(data).and_then(|_| encoder.finish())
+            }
+            Compression::Zstd => zstd::encode_all(data, 0),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+        };
+
+        compressed.map_err(|err| {
+            error!("failure to compress payload - {:?}", err);
+            AmqpError::CompressionError(self.encoding().to_owned())
+        })
+    }
+
+    /// Decompresses up to `max_message_size` bytes, bigger payloads are
+    /// refused before being fully inflated.
+    pub fn decompress(&self, data: &[u8], max_message_size: usize) -> Result<Vec<u8>, AmqpError> {
+        let limit = max_message_size as u64 + 1;
+        let mut decompressed = vec![];
+        let read = match self {
+            Compression::Gzip => GzDecoder::new(data)
+                .take(limit)
+                .read_to_end(&mut decompressed),
+            Compression::Zstd => zstd::Decoder::new(data)
+                .and_then(|decoder| decoder.take(limit).read_to_end(&mut decompressed)),
+            Compression::Lz4 => match lz4_size(data) {
+                Some(size) if size > max_message_size => {
+                    return Err(too_large(size, max_message_size))
+                }
+                _ => lz4_flex::decompress_size_prepended(data)
+                    .map(|inflated| {
+                        decompressed = inflated;
+                        decompressed.len()
+                    })
+                    .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err)),
+            },
+        };
+
+        match read {
+            Ok(_) if decompressed.len() > max_message_size => {
+                Err(too_large(decompressed.len(), max_message_size))
+            }
+            Ok(_) => Ok(decompressed),
+            Err(err) => {
+                error!("failure to decompress payload - {:?}", err);
+                Err(AmqpError::CompressionError(self.encoding().to_owned()))
+            }
+        }
+    }
+}
+
+/// Size lz4_flex prepends to the block, as a little endian u32.
+fn lz4_size(data: &[u8]) -> Option<usize> {
+    data.get(..4)
+        .map(|size| u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize)
+}
+
+fn too_large(size: usize, max_message_size: usize) -> AmqpError {
+    error!(
+        "decompressed message of {} bytes exceeds the max size of {} bytes",
+        size, max_message_size
+    );
+    AmqpError::MessageTooLargeError(size, max_message_size)
+}
+
+/// Payload as the handlers expect it, messages without `content_encoding` or
+/// with the `identity` one are passed through.
+pub fn decode<'d>(
+    encoding: Option<&str>,
+    data: &'d [u8],
+    max_message_size: usize,
+) -> Result<Cow<'d, [u8]>, AmqpError> {
+    match encoding {
+        None | Some("") | Some("identity") => Ok(Cow::Borrowed(data)),
+        Some(encoding) => match Compression::from_encoding(encoding) {
+            Some(compression) => compression
+                .decompress(data, max_message_size)
+                .map(Cow::Owned),
+            _ => Err(AmqpError::CompressionError(encoding.to_owned())),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: usize = 1024 * 1024;
+
+    #[test]
+    fn test_round_trip() {
+        let data = br#"{"temp":21.5,"device":"device"}"#.repeat(100);
+
+        for compression in [Compression::Gzip, Compression::Zstd, Compression::Lz4] {
+            let compressed = compression.compress(&data).unwrap();
+            assert!(compressed.len() < data.len());
+
+            let decoded = decode(Some(compression.encoding()), &compressed, MAX).unwrap();
+            assert_eq!(decoded.as_ref(), data.as_slice());
+        }
+    }
+
+    #[test]
+    fn test_decode_plain_and_unknown() {
+        assert!(matches!(decode(None, b"{}", MAX), Ok(Cow::Borrowed(b"{}"))));
+        assert!(matches!(
+            decode(Some("identity"), b"{}", MAX),
+            Ok(Cow::Borrowed(b"{}"))
+        ));
+        assert_eq!(
+            decode(Some("br"), b"{}", MAX),
+            Err(AmqpError::CompressionError("br".to_owned()))
+        );
+        assert_eq!(
+            decode(Some("gzip"), b"{}", MAX),
+            Err(AmqpError::CompressionError("gzip".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_decode_too_large() {
+        let data = vec![0; 4096];
+
+        for compression in [Compression::Gzip, Compression::Zstd, Compression::Lz4] {
+            let compressed = compression.compress(&data).unwrap();
+            assert!(compressed.len() < 1024);
+
+            assert_eq!(
+                decode(Some(compression.encoding()), &compressed, 1024),
+                Err(AmqpError::MessageTooLargeError(
+                    match compression {
+                        Compression::Lz4 => 4096,
+                        _ => 1025,
+                    },
+                    1024
+                ))
+            );
+            assert!(decode(Some(compression.encoding()), &compressed, 4096).is_ok());
+        }
+
+        // the prepended size is checked before allocating
+        let bomb = [0xff, 0xff, 0xff, 0xff, 0x00];
+        assert_eq!(
+            decode(Some("lz4"), &bomb, 1024),
+            Err(AmqpError::MessageTooLargeError(u32::MAX as usize, 1024))
+        );
+    }
+}
@@ -0,0 +1,454 @@
+use super::{
+    client::{Deliveries, IAmqp},
+    router::TypeRouter,
+    rpc::{RpcHandler, RpcServer},
+    stream::delivery_offset,
+    topology::{ConsumerDefinition, ConsumerHandler, TypedConsumer, TypedConsumerHandler},
+};
+use crate::errors::{AmqpError, HandlerError};
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use log::{debug, error, info};
+use opentelemetry::Context;
+use serde::de::DeserializeOwned;
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+};
+use tokio::{
+    signal,
+    sync::{watch, Semaphore},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConsumerState {
+    Starting,
+    Running,
+    Draining,
+    Stopped,
+    Failed(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsumerHealth {
+    pub state: ConsumerState,
+    pub processed: u64,
+    pub failures: u64,
+}
+
+/// Shared view of the consumers state, cheap to clone into health endpoints.
+#[derive(Debug, Clone, Default)]
+pub struct DispatcherHealth {
+    consumers: Arc<RwLock<HashMap<String, ConsumerHealth>>>,
+}
+
+impl DispatcherHealth {
+    pub fn get(&self, consumer: &str) -> Option<ConsumerHealth> {
+        self.consumers.read().unwrap().get(consumer).cloned()
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, ConsumerHealth> {
+        self.consumers.read().unwrap().clone()
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.consumers
+            .read()
+            .unwrap()
+            .values()
+            .all(|c| c.state == ConsumerState::Running)
+    }
+
+    fn set_state(&self, consumer: &str, state: ConsumerState) {
+        let mut consumers = self.consumers.write().unwrap();
+        match consumers.get_mut(consumer) {
+            Some(health) => health.state = state,
+            _ => {
+                consumers.insert(
+                    consumer.to_owned(),
+                    ConsumerHealth {
+                        state,
+                        processed: 0,
+                        failures: 0,
+                    },
+                );
+            }
+        }
+    }
+
+    fn record(&self, consumer: &str, success: bool) {
+        if let Some(health) = self.consumers.write().unwrap().get_mut(consumer) {
+            health.processed += 1;
+            if !success {
+                health.failures += 1;
+            }
+        }
+    }
+}
+
+/// Owns the delivery loop of every registered consumer.
+///
+/// Each consumer runs in its own task with at most `def.concurrency` messages
+/// in flight. On shutdown consumers stop taking deliveries, in-flight messages
+/// are allowed to finish, then consumers are canceled and the connection is
+/// closed.
+pub struct AmqpDispatcher {
+    amqp: Arc<dyn IAmqp + Send + Sync>,
+    consumers: Vec<(ConsumerDefinition, Arc<dyn ConsumerHandler + Send + Sync>)>,
+    health: DispatcherHealth,
+}
+
+impl AmqpDispatcher {
+    pub fn new(amqp: Arc<dyn IAmqp + Send + Sync>) -> Self {
+        AmqpDispatcher {
+            amqp,
+            consumers: vec![],
+            health: DispatcherHealth::default(),
+        }
+    }
+
+    pub fn register(
+        mut self,
+        def: ConsumerDefinition,
+        handler: Arc<dyn ConsumerHandler + Send + Sync>,
+    ) -> Self {
+        self.consumers.push((def, handler));
+        self
+    }
+
+    /// Registers a handler that receives the payload already deserialized as `T`.
+    pub fn register_typed<T>(
+        self,
+        def: ConsumerDefinition,
+        handler: Arc<dyn TypedConsumerHandler<T> + Send + Sync>,
+    ) -> Self
+    where
+        T: DeserializeOwned + Send + Sync + 'static,
+    {
+        self.register(def, TypedConsumer::new(handler))
+    }
+
+    /// Registers a handler per message type on the same queue, `def` accepts
+    /// exactly the routed types.
+    pub fn register_router(self, def: ConsumerDefinition, router: TypeRouter) -> Self {
+        let def = def.accept_only(router.types());
+        self.register(def, router.boxed())
+    }
+
+    /// Registers a handler whose result is replied to the `IAmqp::call` caller.
+    pub fn register_rpc(
+        self,
+        def: ConsumerDefinition,
+        handler: Arc<dyn RpcHandler + Send + Sync>,
+    ) -> Self {
+        let server = RpcServer::new(self.amqp.clone(), handler);
+        self.register(def, server)
+    }
+
+    pub fn health(&self) -> DispatcherHealth {
+        self.health.clone()
+    }
+
+    /// Runs until SIGTERM or Ctrl+C is received.
+    pub async fn run(self) -> Result<(), AmqpError> {
+        self.run_until(shutdown_signal()).await
+    }
+
+    pub async fn run_until<F>(self, signal: F) -> Result<(), AmqpError>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let (tx, rx) = watch::channel(false);
+        let tx = Arc::new(tx);
+        let shutdown = tx.clone();
+        tokio::spawn(async move {
+            signal.await;
+            info!("shutdown requested, stopping consumers...");
+            let _ = shutdown.send(true);
+        });
+
+        let mut tasks = vec![];
+        let mut started = Ok(());
+        for (def, handler) in self.consumers {
+            let def = Arc::new(def);
+            self.health.set_state(def.name, ConsumerState::Starting);
+
+            let consumer = match self.amqp.consumer(&def).await {
+                Ok(consumer) => consumer,
+                Err(err) => {
+                    error!("failure to start consumer: {} - {:?}", def.name, err);
+                    self.health
+                        .set_state(def.name, ConsumerState::Failed(err.to_string()));
+                    started = Err(err);
+                    break;
+                }
+            };
+
+            tasks.push(tokio::spawn(consume(
+                self.amqp.clone(),
+                def,
+                handler,
+                consumer,
+                rx.clone(),
+                self.health.clone(),
+            )));
+        }
+
+        // the consumers already started drain before the connection closes
+        if started.is_err() {
+            let _ = tx.send(true);
+        }
+
+        for task in tasks {
+            if let Err(err) = task.await {
+                error!("consumer task failure - {:?}", err);
+            }
+        }
+
+        let closed = self.amqp.close().await;
+        started.and(closed)
+    }
+}
+
+/// Keeps whether the handler failed, `IAmqp::consume` is `Ok` as well once a
+/// failed message was retried or dead-lettered.
+struct Recorded {
+    handler: Arc<dyn ConsumerHandler + Send + Sync>,
+    failed: AtomicBool,
+}
+
+#[async_trait]
+impl ConsumerHandler for Recorded {
+    async fn exec(&self, ctx: &Context, data: &[u8]) -> Result<(), HandlerError> {
+        let result = self.handler.exec(ctx, data).await;
+        match &result {
+            Ok(_) | Err(HandlerError::Drop(_)) => {}
+            Err(_) => self.failed.store(true, Ordering::Relaxed),
+        }
+        result
+    }
+
+    fn handles(&self, ctx: &Context) -> bool {
+        self.handler.handles(ctx)
+    }
+}
+
+async fn consume(
+    amqp: Arc<dyn IAmqp + Send + Sync>,
+    def: Arc<ConsumerDefinition>,
+    handler: Arc<dyn ConsumerHandler + Send + Sync>,
+    mut consumer: Deliveries,
+    mut shutdown: watch::Receiver<bool>,
+    health: DispatcherHealth,
+) {
+    let concurrency = def.concurrency.max(1);
+    let semaphore = Arc::new(Semaphore::new(concurrency));
+    health.set_state(def.name, ConsumerState::Running);
+    debug!("consumer: {} running", def.name);
+
+    let mut failure = None;
+    loop {
+        let delivery = tokio::select! {
+            _ = shutdown.changed() => break,
+            delivery = consumer.next() => delivery,
+        };
+
+        match delivery {
+            Some(Ok(delivery)) => {
+                // a delivery left unacked is requeued once the channel closes
+                let permit = tokio::select! {
+                    _ = shutdown.changed() => break,
+                    permit = semaphore.clone().acquire_owned() => match permit {
+                        Ok(permit) => permit,
+                        _ => break,
+                    },
+                };
+
+                if def.offset_store.is_some() {
+                    let offset = delivery
+                        .properties
+                        .headers()
+                        .as_ref()
+                        .and_then(delivery_offset);
+                    if let Some(offset) = offset {
+                        def.offset_tracker.delivered(offset);
+                    }
+                }
+
+                let def = def.clone();
+                let amqp = amqp.clone();
+                let handler = handler.clone();
+                let health = health.clone();
+                tokio::spawn(async move {
+                    let recorded = Arc::new(Recorded {
+                        handler,
+                        failed: AtomicBool::new(false),
+                    });
+                    let res = amqp.consume(&def, recorded.clone(), &delivery).await;
+                    if res.is_err() {
+                        error!("errors consume msg");
+                    }
+                    let failed = recorded.failed.load(Ordering::Relaxed);
+                    health.record(def.name, res.is_ok() && !failed);
+                    drop(permit);
+                });
+            }
+            Some(Err(err)) => {
+                error!("error receiving delivery msg - {:?}", err);
+                failure = Some(err.to_string());
+                break;
+            }
+            None => {
+                error!("consumer: {} was canceled by the broker", def.name);
+                failure = Some("canceled by the broker".to_owned());
+                break;
+            }
+        }
+    }
+
+    health.set_state(def.name, ConsumerState::Draining);
+    let _ = semaphore.acquire_many(concurrency as u32).await;
+
+    // canceling closes the channel, in-flight messages are settled by now and
+    // the prefetched ones not taken are requeued
+    if failure.is_none() {
+        if let Err(err) = amqp.cancel_consumer(&def).await {
+            error!("{:?}", err);
+        }
+    }
+    match failure {
+        Some(reason) => health.set_state(def.name, ConsumerState::Failed(reason)),
+        _ => health.set_state(def.name, ConsumerState::Stopped),
+    }
+    debug!("consumer: {} stopped", def.name);
+}
+
+/// Resolves on ctrl-c or SIGTERM, every caller is notified of the same signal.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            _ => std::future::pending::<()>().await,
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        amqp::{
+            memory::InMemoryAmqp,
+            topology::{AmqpTopology, QueueDefinition},
+            types::{AmqpMessageType, PublishData},
+        },
+        env::Config,
+    };
+    use std::time::Duration;
+
+    struct Failing;
+
+    #[async_trait]
+    impl ConsumerHandler for Failing {
+        async fn exec(&self, _ctx: &Context, _data: &[u8]) -> Result<(), HandlerError> {
+            Err(HandlerError::Retry("failure".to_owned()))
+        }
+    }
+
+    async fn amqp() -> (Arc<InMemoryAmqp>, ConsumerDefinition) {
+        let amqp = InMemoryAmqp::new(&Config::mock());
+        let topology = AmqpTopology::new().queue(
+            QueueDefinition::name("queue")
+                .msg_type(AmqpMessageType::Temp)
+                .with_dlq(),
+        );
+        amqp.install_topology(&topology).await.unwrap();
+        (amqp, topology.get_consumers_def("queue").unwrap())
+    }
+
+    #[test]
+    fn test_dispatcher_health() {
+        let health = DispatcherHealth::default();
+        assert!(health.get("consumer").is_none());
+        assert!(health.is_healthy());
+
+        health.set_state("consumer", ConsumerState::Starting);
+        assert!(!health.is_healthy());
+
+        health.set_state("consumer", ConsumerState::Running);
+        assert!(health.is_healthy());
+
+        health.record("consumer", true);
+        health.record("consumer", false);
+        let consumer = health.get("consumer").unwrap();
+        assert_eq!(consumer.processed, 2);
+        assert_eq!(consumer.failures, 1);
+
+        health.set_state("consumer", ConsumerState::Failed("error".to_owned()));
+        assert!(!health.is_healthy());
+        assert_eq!(health.snapshot().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_until_records_handler_failures() {
+        let (amqp, def) = amqp().await;
+        let data = PublishData::raw("Temp", b"{}".to_vec());
+        amqp.publish(&Context::new(), "", "queue", &data)
+            .await
+            .unwrap();
+
+        let dispatcher = AmqpDispatcher::new(amqp.clone()).register(def, Arc::new(Failing));
+        let health = dispatcher.health();
+        dispatcher
+            .run_until(tokio::time::sleep(Duration::from_millis(100)))
+            .await
+            .unwrap();
+
+        let consumer = health.get("queue").unwrap();
+        assert_eq!(consumer.state, ConsumerState::Stopped);
+        assert_eq!((consumer.processed, consumer.failures), (1, 1));
+        assert_eq!(amqp.messages("queue-dlq").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_until_stops_started_consumers_on_failure() {
+        let (amqp, def) = amqp().await;
+
+        let dispatcher = AmqpDispatcher::new(amqp)
+            .register(def, Arc::new(Failing))
+            .register(
+                ConsumerDefinition::name("missing").queue("missing"),
+                Arc::new(Failing),
+            );
+        let health = dispatcher.health();
+
+        assert_eq!(
+            dispatcher.run_until(std::future::pending()).await,
+            Err(AmqpError::BindingConsumerError("missing".to_owned()))
+        );
+        assert_eq!(health.get("queue").unwrap().state, ConsumerState::Stopped);
+        assert!(matches!(
+            health.get("missing").unwrap().state,
+            ConsumerState::Failed(_)
+        ));
+    }
+}
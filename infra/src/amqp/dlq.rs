@@ -0,0 +1,485 @@
+use super::{
+    client::{
+        confirmation_result, IAmqp, FAILURE_REASON_HEADER, ORIGINAL_EXCHANGE_HEADER,
+        ORIGINAL_ROUTING_KEY_HEADER,
+    },
+    compression,
+    envelope::DEVICE_ID_HEADER,
+    types::{Metadata, XDeath},
+};
+use crate::{env::Config, errors::AmqpError};
+use lapin::{
+    message::Delivery,
+    options::{
+        BasicAckOptions, BasicGetOptions, BasicNackOptions, BasicPublishOptions,
+        ConfirmSelectOptions,
+    },
+    protocol::basic::AMQPProperties,
+    types::{AMQPValue, FieldTable, ShortString},
+    Channel,
+};
+use log::{debug, error, warn};
+use serde::Serialize;
+use std::{borrow::Cow, collections::BTreeMap, sync::Arc};
+
+/// Headers describing the previous life of a message, dropped on replay so the
+/// message starts over with a fresh retry count.
+const REPLAY_STRIPPED_HEADERS: [&str; 4] = [
+    "x-death",
+    FAILURE_REASON_HEADER,
+    ORIGINAL_EXCHANGE_HEADER,
+    ORIGINAL_ROUTING_KEY_HEADER,
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DlqMessage {
+    #[serde(skip)]
+    pub delivery_tag: u64,
+    pub message_id: String,
+    pub msg_type: String,
+    pub traceparent: String,
+    pub failure_reason: String,
+    pub device_id: Option<String>,
+    pub exchange: Option<String>,
+    pub routing_key: Option<String>,
+    /// Last dead-lettering time from `x-death`, or the producer timestamp
+    /// for messages sent to the DLQ without going through the broker DLX
+    pub timestamp: Option<u64>,
+    pub deaths: Vec<XDeath>,
+    pub payload: serde_json::Value,
+}
+
+impl DlqMessage {
+    pub fn from_delivery(delivery: &Delivery, max_message_size: usize) -> DlqMessage {
+        let header = match delivery.properties.headers() {
+            Some(val) => val.to_owned(),
+            None => FieldTable::default(),
+        };
+        let metadata = Metadata::extract(&header);
+
+        let string = |key: &str| {
+            header
+                .inner()
+                .get(key)
+                .and_then(|value| value.as_long_string())
+                .map(|st| st.to_string())
+        };
+
+        // replays republish the original bytes, only the view is decompressed
+        let encoding = delivery.properties.content_encoding().as_ref();
+        let data = compression::decode(
+            encoding.map(|e| e.as_str()),
+            &delivery.data,
+            max_message_size,
+        )
+        .unwrap_or(Cow::Borrowed(&delivery.data));
+        let payload = match serde_json::from_slice::<serde_json::Value>(&data) {
+            Ok(value) => value,
+            _ => serde_json::Value::String(String::from_utf8_lossy(&data).to_string()),
+        };
+
+        let device_id = match string(DEVICE_ID_HEADER) {
+            Some(device) => Some(device),
+            _ => payload
+                .get("device_id")
+                .and_then(|device| device.as_str())
+                .map(|device| device.to_owned()),
+        };
+
+        let (exchange, routing_key) = match (
+            string(ORIGINAL_EXCHANGE_HEADER),
+            string(ORIGINAL_ROUTING_KEY_HEADER),
+        ) {
+            (Some(exchange), Some(key)) => (Some(exchange), Some(key)),
+            _ => match metadata.deaths.iter().rev().find(|d| d.reason != "expired") {
+                Some(death) => (
+                    Some(death.exchange.clone()),
+                    death.routing_keys.first().cloned(),
+                ),
+                _ => (None, None),
+            },
+        };
+
+        // x-death is ordered newest first
+        let timestamp = match metadata.deaths.first().and_then(|d| d.time) {
+            Some(time) => Some(time),
+            _ => *delivery.properties.timestamp(),
+        };
+
+        DlqMessage {
+            delivery_tag: delivery.delivery_tag,
+            message_id: delivery
+                .properties
+                .message_id()
+                .as_ref()
+                .map(|id| id.to_string())
+                .unwrap_or_default(),
+            msg_type: metadata.msg_type,
+            traceparent: metadata.traceparent,
+            failure_reason: string(FAILURE_REASON_HEADER).unwrap_or_default(),
+            device_id,
+            exchange,
+            routing_key,
+            timestamp,
+            deaths: metadata.deaths,
+            payload,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DlqFilter {
+    pub msg_type: Option<String>,
+    pub device_id: Option<String>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+}
+
+impl DlqFilter {
+    pub fn msg_type(mut self, msg_type: &str) -> Self {
+        self.msg_type = Some(msg_type.to_owned());
+        self
+    }
+
+    pub fn device_id(mut self, device_id: &str) -> Self {
+        self.device_id = Some(device_id.to_owned());
+        self
+    }
+
+    /// Unix timestamp in seconds, inclusive.
+    pub fn since(mut self, since: u64) -> Self {
+        self.since = Some(since);
+        self
+    }
+
+    /// Unix timestamp in seconds, inclusive.
+    pub fn until(mut self, until: u64) -> Self {
+        self.until = Some(until);
+        self
+    }
+
+    pub fn matches(&self, msg: &DlqMessage) -> bool {
+        if let Some(msg_type) = &self.msg_type {
+            if &msg.msg_type != msg_type {
+                return false;
+            }
+        }
+
+        if let Some(device_id) = &self.device_id {
+            if msg.device_id.as_ref() != Some(device_id) {
+                return false;
+            }
+        }
+
+        match (self.since, self.until, msg.timestamp) {
+            (None, None, _) => true,
+            (_, _, None) => false,
+            (since, until, Some(timestamp)) => {
+                !matches!(since, Some(since) if timestamp < since)
+                    && !matches!(until, Some(until) if timestamp > until)
+            }
+        }
+    }
+}
+
+/// Reads and replays messages parked in a DLQ.
+///
+/// Messages are fetched with `basic_get` and kept unacked while the operation
+/// runs, anything not replayed is requeued untouched at the end.
+pub struct DlqInspector {
+    channel: Channel,
+    max_message_size: usize,
+}
+
+impl DlqInspector {
+    pub async fn new(cfg: &Config, amqp: Arc<dyn IAmqp + Send + Sync>) -> Result<Self, AmqpError> {
+        let channel = amqp
+            .connection()?
+            .create_channel()
+            .await
+            .map_err(|_| AmqpError::ChannelError {})?;
+
+        channel
+            .confirm_select(ConfirmSelectOptions { nowait: false })
+            .await
+            .map_err(|_| AmqpError::ChannelError {})?;
+
+        Ok(DlqInspector {
+            channel,
+            max_message_size: cfg.amqp_max_message_size,
+        })
+    }
+
+    pub async fn list(
+        &self,
+        queue: &str,
+        filter: &DlqFilter,
+        limit: usize,
+    ) -> Result<Vec<DlqMessage>, AmqpError> {
+        let deliveries = self.fetch(queue, limit).await?;
+
+        let messages = deliveries
+            .iter()
+            .map(|delivery| DlqMessage::from_delivery(delivery, self.max_message_size))
+            .filter(|msg| filter.matches(msg))
+            .collect();
+
+        self.release(&deliveries).await?;
+
+        Ok(messages)
+    }
+
+    /// Publishes the matching messages back to the exchange and routing key
+    /// they were originally sent to, returning the replayed ones.
+    pub async fn replay(
+        &self,
+        queue: &str,
+        filter: &DlqFilter,
+        limit: usize,
+    ) -> Result<Vec<DlqMessage>, AmqpError> {
+        let deliveries = self.fetch(queue, limit).await?;
+
+        let mut replayed = vec![];
+        let mut kept = vec![];
+        for delivery in deliveries {
+            let msg = DlqMessage::from_delivery(&delivery, self.max_message_size);
+            if !filter.matches(&msg) {
+                kept.push(delivery);
+                continue;
+            }
+
+            let (exchange, key) = match (&msg.exchange, &msg.routing_key) {
+                (Some(exchange), Some(key)) => (exchange.clone(), key.clone()),
+                _ => {
+                    warn!("unknown origin for message: {}, skipping", msg.message_id);
+                    kept.push(delivery);
+                    continue;
+                }
+            };
+
+            if let Err(err) = self.republish(&exchange, &key, &delivery).await {
+                error!("failure to replay message: {} - {}", msg.message_id, err);
+                kept.push(delivery);
+                continue;
+            }
+
+            delivery
+                .ack(BasicAckOptions { multiple: false })
+                .await
+                .map_err(|_| AmqpError::AckMessageError {})?;
+
+            debug!(
+                "message: {} replayed to {}/{}",
+                msg.message_id, exchange, key
+            );
+            replayed.push(msg);
+        }
+
+        self.release(&kept).await?;
+
+        Ok(replayed)
+    }
+
+    async fn fetch(&self, queue: &str, limit: usize) -> Result<Vec<Delivery>, AmqpError> {
+        let mut deliveries = vec![];
+
+        while deliveries.len() < limit {
+            match self
+                .channel
+                .basic_get(queue, BasicGetOptions { no_ack: false })
+                .await
+                .map_err(|_| AmqpError::FetchMessageError(queue.to_owned()))?
+            {
+                Some(msg) => deliveries.push(msg.delivery),
+                _ => break,
+            }
+        }
+
+        debug!("{} messages fetched from: {}", deliveries.len(), queue);
+
+        Ok(deliveries)
+    }
+
+    async fn release(&self, deliveries: &[Delivery]) -> Result<(), AmqpError> {
+        for delivery in deliveries {
+            delivery
+                .nack(BasicNackOptions {
+                    multiple: false,
+                    requeue: true,
+                })
+                .await
+                .map_err(|_| AmqpError::NackMessageError {})?;
+        }
+
+        Ok(())
+    }
+
+    async fn republish(
+        &self,
+        exchange: &str,
+        key: &str,
+        delivery: &Delivery,
+    ) -> Result<(), AmqpError> {
+        let confirmation = self
+            .channel
+            .basic_publish(
+                exchange,
+                key,
+                BasicPublishOptions {
+                    immediate: false,
+                    mandatory: true,
+                },
+                &delivery.data,
+                replay_properties(&delivery.properties),
+            )
+            .await
+            .map_err(|_| AmqpError::PublishingError {})?
+            .await
+            .map_err(|_| AmqpError::PublishingError {})?;
+
+        confirmation_result(confirmation)
+    }
+}
+
+fn replay_properties(properties: &AMQPProperties) -> AMQPProperties {
+    let headers: BTreeMap<ShortString, AMQPValue> = match properties.headers() {
+        Some(headers) => headers
+            .inner()
+            .iter()
+            .filter(|(key, _)| !REPLAY_STRIPPED_HEADERS.contains(&key.as_str()))
+            .map(|(key, value)| (ShortString::from(key.as_str()), value.clone()))
+            .collect(),
+        None => BTreeMap::new(),
+    };
+
+    properties.clone().with_headers(FieldTable::from(headers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amqp::client::delivery;
+    use lapin::types::{FieldArray, LongString};
+
+    fn dead_letter(headers: BTreeMap<ShortString, AMQPValue>, data: &[u8]) -> Delivery {
+        let properties = AMQPProperties::default()
+            .with_message_id(ShortString::from("id"))
+            .with_headers(FieldTable::from(headers));
+        delivery(properties, data)
+    }
+
+    fn string(value: &str) -> AMQPValue {
+        AMQPValue::LongString(LongString::from(value))
+    }
+
+    #[test]
+    fn test_dlq_message_from_delivery() {
+        let mut death = BTreeMap::new();
+        death.insert(ShortString::from("queue"), string("queue"));
+        death.insert(ShortString::from("reason"), string("rejected"));
+        death.insert(ShortString::from("exchange"), string("exchange"));
+        death.insert(
+            ShortString::from("routing-keys"),
+            AMQPValue::FieldArray(FieldArray::from(vec![string("key")])),
+        );
+        death.insert(ShortString::from("time"), AMQPValue::Timestamp(1000));
+
+        let mut headers = BTreeMap::new();
+        headers.insert(ShortString::from("type"), string("Temp"));
+        headers.insert(ShortString::from(FAILURE_REASON_HEADER), string("invalid"));
+        headers.insert(
+            ShortString::from("x-death"),
+            AMQPValue::FieldArray(FieldArray::from(vec![AMQPValue::FieldTable(
+                FieldTable::from(death),
+            )])),
+        );
+
+        let msg = DlqMessage::from_delivery(
+            &dead_letter(headers.clone(), b"{\"device_id\":\"d1\"}"),
+            1024,
+        );
+        assert_eq!(msg.message_id, "id");
+        assert_eq!(msg.msg_type, "Temp");
+        assert_eq!(msg.failure_reason, "invalid");
+        assert_eq!(msg.device_id, Some("d1".to_owned()));
+        assert_eq!(msg.exchange, Some("exchange".to_owned()));
+        assert_eq!(msg.routing_key, Some("key".to_owned()));
+        assert_eq!(msg.timestamp, Some(1000));
+
+        headers.insert(
+            ShortString::from(ORIGINAL_EXCHANGE_HEADER),
+            string("origin"),
+        );
+        headers.insert(
+            ShortString::from(ORIGINAL_ROUTING_KEY_HEADER),
+            string("origin_key"),
+        );
+        headers.insert(ShortString::from(DEVICE_ID_HEADER), string("d2"));
+        let msg = DlqMessage::from_delivery(&dead_letter(headers.clone(), b"not json"), 1024);
+        assert_eq!(msg.exchange, Some("origin".to_owned()));
+        assert_eq!(msg.routing_key, Some("origin_key".to_owned()));
+        assert_eq!(msg.device_id, Some("d2".to_owned()));
+        assert_eq!(
+            msg.payload,
+            serde_json::Value::String("not json".to_owned())
+        );
+
+        let msg = DlqMessage::from_delivery(&dead_letter(BTreeMap::new(), b"{}"), 1024);
+        assert_eq!(msg.exchange, None);
+        assert_eq!(msg.timestamp, None);
+
+        let mut produced = dead_letter(headers, b"{}");
+        produced.properties = produced.properties.with_timestamp(500);
+        assert_eq!(
+            DlqMessage::from_delivery(&produced, 1024).timestamp,
+            Some(1000)
+        );
+        let mut produced = dead_letter(BTreeMap::new(), b"{}");
+        produced.properties = produced.properties.with_timestamp(500);
+        assert_eq!(
+            DlqMessage::from_delivery(&produced, 1024).timestamp,
+            Some(500)
+        );
+    }
+
+    #[test]
+    fn test_dlq_filter() {
+        let mut headers = BTreeMap::new();
+        headers.insert(ShortString::from("type"), string("Temp"));
+        headers.insert(ShortString::from(DEVICE_ID_HEADER), string("d1"));
+        let mut msg = DlqMessage::from_delivery(&dead_letter(headers, b"{}"), 1024);
+
+        assert!(DlqFilter::default().matches(&msg));
+        assert!(DlqFilter::default().msg_type("Temp").matches(&msg));
+        assert!(!DlqFilter::default().msg_type("GPS").matches(&msg));
+        assert!(DlqFilter::default().device_id("d1").matches(&msg));
+        assert!(!DlqFilter::default().device_id("d2").matches(&msg));
+        assert!(!DlqFilter::default().since(10).matches(&msg));
+
+        msg.timestamp = Some(100);
+        assert!(DlqFilter::default().since(10).until(100).matches(&msg));
+        assert!(!DlqFilter::default().since(101).matches(&msg));
+        assert!(!DlqFilter::default().until(99).matches(&msg));
+    }
+
+    #[test]
+    fn test_replay_properties() {
+        let mut headers = BTreeMap::new();
+        headers.insert(ShortString::from("traceparent"), string("traceparent"));
+        headers.insert(ShortString::from(FAILURE_REASON_HEADER), string("invalid"));
+        headers.insert(
+            ShortString::from(ORIGINAL_EXCHANGE_HEADER),
+            string("exchange"),
+        );
+        headers.insert(
+            ShortString::from("x-death"),
+            AMQPValue::FieldArray(FieldArray::default()),
+        );
+
+        let props = replay_properties(&dead_letter(headers, b"{}").properties);
+        let headers = props.headers().clone().unwrap();
+        assert_eq!(headers.inner().len(), 1);
+        assert!(headers.inner().contains_key("traceparent"));
+        assert_eq!(props.message_id(), &Some(ShortString::from("id")));
+    }
+}
@@ -0,0 +1,221 @@
+//! Envelope shared by every message published through `IAmqp`.
+//!
+//! | field          | AMQP property  | header           |
+//! |----------------|----------------|------------------|
+//! | message id     | `message_id`   |                  |
+//! | type           | `type`         | `type`           |
+//! | schema version |                | `schema-version` |
+//! | produced at    | `timestamp`    | `produced-at`    |
+//! | producer       | `app_id`       |                  |
+//! | device id      |                | `device-id`      |
+//! | traceparent    |                | `traceparent`    |
+//! | content type   | `content_type` |                  |
+//!
+//! `produced-at` holds milliseconds while the `timestamp` property only holds
+//! seconds. The type is written to both places, headers exchanges and older
+//! consumers read the header while other AMQP clients set the property.
+//!
+//! Messages published before the envelope have no `schema-version` header and
+//! are read as version 1.
+use lapin::{
+    protocol::basic::AMQPProperties,
+    types::{AMQPValue, FieldTable, LongString, ShortString},
+};
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub const TYPE_HEADER: &str = "type";
+pub const SCHEMA_VERSION_HEADER: &str = "schema-version";
+pub const PRODUCED_AT_HEADER: &str = "produced-at";
+pub const DEVICE_ID_HEADER: &str = "device-id";
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+pub const DEFAULT_SCHEMA_VERSION: u16 = 1;
+pub const DEFAULT_CONTENT_TYPE: &str = "application/json";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub message_id: String,
+    pub msg_type: String,
+    pub schema_version: u16,
+    /// Unix timestamp in milliseconds
+    pub produced_at: u64,
+    pub producer: String,
+    pub device_id: Option<String>,
+    pub traceparent: String,
+    pub content_type: String,
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Envelope {
+            message_id: "".to_owned(),
+            msg_type: "".to_owned(),
+            schema_version: DEFAULT_SCHEMA_VERSION,
+            produced_at: 0,
+            producer: "".to_owned(),
+            device_id: None,
+            traceparent: "".to_owned(),
+            content_type: DEFAULT_CONTENT_TYPE.to_owned(),
+        }
+    }
+}
+
+impl Envelope {
+    /// Reads the envelope only from the headers, the fields held by properties
+    /// keep their default.
+    pub fn from_headers(header: &FieldTable) -> Envelope {
+        let string = |key: &str| match header.inner().get(key) {
+            Some(AMQPValue::LongString(value)) => Some(value.to_string()),
+            Some(AMQPValue::ShortString(value)) => Some(value.to_string()),
+            _ => None,
+        };
+        let number = |key: &str| match header.inner().get(key) {
+            Some(AMQPValue::ShortShortUInt(value)) => Some(*value as u64),
+            Some(AMQPValue::ShortUInt(value)) => Some(*value as u64),
+            Some(AMQPValue::LongUInt(value)) => Some(*value as u64),
+            Some(AMQPValue::LongInt(value)) => Some(*value as u64),
+            Some(AMQPValue::LongLongInt(value)) => Some(*value as u64),
+            Some(AMQPValue::Timestamp(value)) => Some(*value),
+            _ => None,
+        };
+
+        Envelope {
+            msg_type: string(TYPE_HEADER).unwrap_or_default(),
+            schema_version: number(SCHEMA_VERSION_HEADER)
+                .map(|version| version as u16)
+                .unwrap_or(DEFAULT_SCHEMA_VERSION),
+            produced_at: number(PRODUCED_AT_HEADER).unwrap_or_default(),
+            device_id: string(DEVICE_ID_HEADER),
+            traceparent: string(TRACEPARENT_HEADER).unwrap_or_default(),
+            ..Envelope::default()
+        }
+    }
+
+    pub fn from_properties(properties: &AMQPProperties) -> Envelope {
+        let header = properties.headers().clone().unwrap_or_default();
+        let mut envelope = Envelope::from_headers(&header);
+
+        if let Some(id) = properties.message_id() {
+            envelope.message_id = id.to_string();
+        }
+        if envelope.msg_type.is_empty() {
+            if let Some(kind) = properties.kind() {
+                envelope.msg_type = kind.to_string();
+            }
+        }
+        if envelope.produced_at == 0 {
+            if let Some(secs) = properties.timestamp() {
+                envelope.produced_at = secs * 1000;
+            }
+        }
+        if let Some(app_id) = properties.app_id() {
+            envelope.producer = app_id.to_string();
+        }
+        if let Some(content_type) = properties.content_type() {
+            envelope.content_type = content_type.to_string();
+        }
+
+        envelope
+    }
+
+    /// Writes the envelope over `properties`, its headers are merged into
+    /// `headers` which become the message headers.
+    pub fn write(
+        &self,
+        properties: AMQPProperties,
+        mut headers: BTreeMap<ShortString, AMQPValue>,
+    ) -> AMQPProperties {
+        let string = |value: &str| AMQPValue::LongString(LongString::from(value));
+
+        headers.insert(ShortString::from(TYPE_HEADER), string(&self.msg_type));
+        headers.insert(
+            ShortString::from(SCHEMA_VERSION_HEADER),
+            AMQPValue::ShortUInt(self.schema_version),
+        );
+        headers.insert(
+            ShortString::from(PRODUCED_AT_HEADER),
+            AMQPValue::LongLongInt(self.produced_at as i64),
+        );
+        headers.insert(
+            ShortString::from(TRACEPARENT_HEADER),
+            string(&self.traceparent),
+        );
+        if let Some(device_id) = &self.device_id {
+            headers.insert(ShortString::from(DEVICE_ID_HEADER), string(device_id));
+        }
+
+        properties
+            .with_message_id(ShortString::from(self.message_id.as_str()))
+            .with_kind(ShortString::from(self.msg_type.as_str()))
+            .with_timestamp(self.produced_at / 1000)
+            .with_app_id(ShortString::from(self.producer.as_str()))
+            .with_content_type(ShortString::from(self.content_type.as_str()))
+            .with_headers(FieldTable::from(headers))
+    }
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope() -> Envelope {
+        Envelope {
+            message_id: "id".to_owned(),
+            msg_type: "Temp".to_owned(),
+            schema_version: 2,
+            produced_at: 1660000000123,
+            producer: "amqp".to_owned(),
+            device_id: Some("device".to_owned()),
+            traceparent: "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".to_owned(),
+            content_type: DEFAULT_CONTENT_TYPE.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_envelope_round_trip() {
+        let mut custom = BTreeMap::new();
+        custom.insert(
+            ShortString::from("x-custom"),
+            AMQPValue::LongString(LongString::from("value")),
+        );
+
+        let properties = envelope().write(AMQPProperties::default(), custom);
+        assert_eq!(Envelope::from_properties(&properties), envelope());
+        assert_eq!(properties.kind(), &Some(ShortString::from("Temp")));
+        assert_eq!(properties.timestamp(), &Some(1660000000));
+
+        let header = properties.headers().clone().unwrap();
+        assert!(header.inner().contains_key("x-custom"));
+
+        let from_headers = Envelope::from_headers(&header);
+        assert_eq!(from_headers.msg_type, "Temp");
+        assert_eq!(from_headers.schema_version, 2);
+        assert_eq!(from_headers.device_id, Some("device".to_owned()));
+        assert_eq!(from_headers.message_id, "");
+    }
+
+    #[test]
+    fn test_envelope_v1() {
+        let properties = AMQPProperties::default()
+            .with_message_id(ShortString::from("id"))
+            .with_kind(ShortString::from("GPS"))
+            .with_timestamp(1660000000);
+
+        let envelope = Envelope::from_properties(&properties);
+        assert_eq!(envelope.msg_type, "GPS");
+        assert_eq!(envelope.schema_version, DEFAULT_SCHEMA_VERSION);
+        assert_eq!(envelope.produced_at, 1660000000000);
+        assert_eq!(envelope.device_id, None);
+        assert_eq!(envelope.content_type, DEFAULT_CONTENT_TYPE);
+    }
+}
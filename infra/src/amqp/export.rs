@@ -0,0 +1,428 @@
+use super::topology::{AmqpTopology, QueueDeclaration};
+use lapin::types::{AMQPValue, ShortString};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+/// An edge of the topology graph, `to` is a queue unless `to_exchange`.
+struct Edge {
+    from: String,
+    from_exchange: bool,
+    to: String,
+    to_exchange: bool,
+    label: String,
+    dead_letter: bool,
+}
+
+impl AmqpTopology {
+    /// RabbitMQ definitions importable through the management plugin,
+    /// including the retry, DLQ and delay queues installed implicitly.
+    pub fn to_definitions(&self, vhost: &str) -> Value {
+        let exchanges = self
+            .exchanges
+            .iter()
+            .map(|exch| {
+                let kind = match exch.delayed {
+                    true => "x-delayed-message",
+                    _ => exch.kind.as_str(),
+                };
+                json!({
+                    "name": exch.name,
+                    "vhost": vhost,
+                    "type": kind,
+                    "durable": exch.durable,
+                    "auto_delete": exch.auto_delete,
+                    "internal": exch.internal,
+                    "arguments": arguments_to_json(&exch.arguments()),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let queues = self
+            .queue_declarations()
+            .iter()
+            .map(|queue| {
+                json!({
+                    "name": queue.name,
+                    "vhost": vhost,
+                    "durable": queue.durable,
+                    "auto_delete": queue.auto_delete,
+                    "arguments": arguments_to_json(&queue.arguments),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut bindings = vec![];
+        for exch in self.exchanges.iter() {
+            for bind in exch.bindings.iter() {
+                bindings.push(json!({
+                    "source": bind.source,
+                    "vhost": vhost,
+                    "destination": bind.destination,
+                    "destination_type": "exchange",
+                    "routing_key": bind.routing_key,
+                    "arguments": {},
+                }));
+            }
+        }
+        for queue in self.queues.iter() {
+            for bind in queue.bindings.iter() {
+                bindings.push(json!({
+                    "source": bind.exchange,
+                    "vhost": vhost,
+                    "destination": bind.queue,
+                    "destination_type": "queue",
+                    "routing_key": bind.routing_key,
+                    "arguments": arguments_to_json(&bind.arguments()),
+                }));
+            }
+        }
+
+        json!({
+            "exchanges": exchanges,
+            "queues": queues,
+            "bindings": bindings,
+        })
+    }
+
+    pub fn to_graphviz(&self) -> String {
+        let mut out = String::from("digraph topology {\n    rankdir=LR;\n");
+
+        for exch in self.exchanges.iter() {
+            out.push_str(&format!(
+                "    \"{}\" [label=\"{}\\n({})\", shape=box];\n",
+                node_id(exch.name, true),
+                exch.name,
+                exch.kind.as_str()
+            ));
+        }
+        for queue in self.queue_declarations() {
+            out.push_str(&format!(
+                "    \"{}\" [label=\"{}\", shape=ellipse];\n",
+                node_id(&queue.name, false),
+                queue.name
+            ));
+        }
+        for edge in self.edges() {
+            let style = match edge.dead_letter {
+                true => ", style=dashed",
+                _ => "",
+            };
+            out.push_str(&format!(
+                "    \"{}\" -> \"{}\" [label=\"{}\"{}];\n",
+                node_id(&edge.from, edge.from_exchange),
+                node_id(&edge.to, edge.to_exchange),
+                edge.label.replace('"', "\\\""),
+                style
+            ));
+        }
+
+        out.push_str("}\n");
+        out
+    }
+
+    pub fn to_mermaid(&self) -> String {
+        let mut out = String::from("flowchart LR\n");
+
+        for exch in self.exchanges.iter() {
+            out.push_str(&format!(
+                "    {}[\"{} ({})\"]\n",
+                mermaid_id(exch.name, true),
+                exch.name,
+                exch.kind.as_str()
+            ));
+        }
+        for queue in self.queue_declarations() {
+            out.push_str(&format!(
+                "    {}([\"{}\"])\n",
+                mermaid_id(&queue.name, false),
+                queue.name
+            ));
+        }
+        for edge in self.edges() {
+            let arrow = match edge.dead_letter {
+                true => "-.->",
+                _ => "-->",
+            };
+            let label = match edge.label.is_empty() {
+                true => "".to_owned(),
+                _ => format!("|\"{}\"|", edge.label.replace('"', "'")),
+            };
+            out.push_str(&format!(
+                "    {} {}{} {}\n",
+                mermaid_id(&edge.from, edge.from_exchange),
+                arrow,
+                label,
+                mermaid_id(&edge.to, edge.to_exchange)
+            ));
+        }
+
+        out
+    }
+
+    fn queue_declarations(&self) -> Vec<QueueDeclaration> {
+        self.queues
+            .iter()
+            .flat_map(|queue| queue.declarations())
+            .chain(
+                self.delayed
+                    .iter()
+                    .flat_map(|delayed| delayed.declarations()),
+            )
+            .collect()
+    }
+
+    fn edges(&self) -> Vec<Edge> {
+        let mut edges = vec![];
+
+        for exch in self.exchanges.iter() {
+            for bind in exch.bindings.iter() {
+                edges.push(Edge {
+                    from: bind.source.to_owned(),
+                    from_exchange: true,
+                    to: bind.destination.to_owned(),
+                    to_exchange: true,
+                    label: bind.routing_key.to_owned(),
+                    dead_letter: false,
+                });
+            }
+
+            if let Some(alternate) = exch.alternate_exchange {
+                edges.push(Edge {
+                    from: exch.name.to_owned(),
+                    from_exchange: true,
+                    to: alternate.to_owned(),
+                    to_exchange: true,
+                    label: "unroutable".to_owned(),
+                    dead_letter: true,
+                });
+            }
+        }
+
+        for queue in self.queues.iter() {
+            for bind in queue.bindings.iter() {
+                let label = match bind.x_match {
+                    Some(x_match) => bind.headers.iter().fold(
+                        format!("x-match={}", x_match.as_str()),
+                        |label, (key, value)| format!("{} {}={}", label, key, value),
+                    ),
+                    _ => bind.routing_key.to_owned(),
+                };
+                edges.push(Edge {
+                    from: bind.exchange.to_owned(),
+                    from_exchange: true,
+                    to: bind.queue.to_owned(),
+                    to_exchange: false,
+                    label,
+                    dead_letter: false,
+                });
+            }
+        }
+
+        for queue in self.queue_declarations() {
+            let string = |key: &str| match queue.arguments.get(key) {
+                Some(AMQPValue::LongString(value)) => Some(value.to_string()),
+                _ => None,
+            };
+
+            let (exchange, key) = match (
+                string("x-dead-letter-exchange"),
+                string("x-dead-letter-routing-key"),
+            ) {
+                (Some(exchange), Some(key)) => (exchange, key),
+                _ => continue,
+            };
+
+            let label = match queue.arguments.get("x-message-ttl") {
+                Some(AMQPValue::LongInt(ttl)) => format!("ttl {}ms", ttl),
+                Some(AMQPValue::LongLongInt(ttl)) => format!("ttl {}ms", ttl),
+                _ => "dead-letter".to_owned(),
+            };
+
+            edges.push(match exchange.is_empty() {
+                true => Edge {
+                    from: queue.name.clone(),
+                    from_exchange: false,
+                    to: key,
+                    to_exchange: false,
+                    label,
+                    dead_letter: true,
+                },
+                _ => Edge {
+                    from: queue.name.clone(),
+                    from_exchange: false,
+                    to: exchange,
+                    to_exchange: true,
+                    label: format!("{} {}", label, key),
+                    dead_letter: true,
+                },
+            });
+        }
+
+        // consumers republish exhausted retries and rejects to the DLQ, the
+        // broker only dead-letters there when the queue has no retry
+        for queue in self.queues.iter().filter(|queue| queue.with_dlq) {
+            let routed = edges
+                .iter()
+                .any(|edge| edge.from == queue.name && edge.to == queue.dlq_name);
+            if !routed {
+                edges.push(Edge {
+                    from: queue.name.to_owned(),
+                    from_exchange: false,
+                    to: queue.dlq_name.to_owned(),
+                    to_exchange: false,
+                    label: "retries exhausted or rejected".to_owned(),
+                    dead_letter: true,
+                });
+            }
+        }
+
+        edges
+    }
+}
+
+fn node_id(name: &str, exchange: bool) -> String {
+    match exchange {
+        true => format!("exchange:{}", name),
+        _ => format!("queue:{}", name),
+    }
+}
+
+/// Mermaid ids can't hold dots or dashes, names are kept in the labels.
+fn mermaid_id(name: &str, exchange: bool) -> String {
+    let name = name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c,
+            _ => '_',
+        })
+        .collect::<String>();
+
+    match exchange {
+        true => format!("e_{}", name),
+        _ => format!("q_{}", name),
+    }
+}
+
+fn arguments_to_json(args: &BTreeMap<ShortString, AMQPValue>) -> Value {
+    Value::Object(
+        args.iter()
+            .map(|(key, value)| (key.to_string(), amqp_to_json(value)))
+            .collect::<Map<_, _>>(),
+    )
+}
+
+fn amqp_to_json(value: &AMQPValue) -> Value {
+    match value {
+        AMQPValue::Boolean(v) => json!(v),
+        AMQPValue::ShortShortInt(v) => json!(v),
+        AMQPValue::ShortShortUInt(v) => json!(v),
+        AMQPValue::ShortInt(v) => json!(v),
+        AMQPValue::ShortUInt(v) => json!(v),
+        AMQPValue::LongInt(v) => json!(v),
+        AMQPValue::LongUInt(v) => json!(v),
+        AMQPValue::LongLongInt(v) => json!(v),
+        AMQPValue::Float(v) => json!(v),
+        AMQPValue::Double(v) => json!(v),
+        AMQPValue::Timestamp(v) => json!(v),
+        AMQPValue::ShortString(v) => json!(v.as_str()),
+        AMQPValue::LongString(v) => json!(v.to_string()),
+        AMQPValue::FieldArray(v) => Value::Array(v.as_slice().iter().map(amqp_to_json).collect()),
+        AMQPValue::FieldTable(v) => arguments_to_json(v.inner()),
+        _ => Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amqp::topology::{
+        DelayedDefinition, ExchangeDefinition, QueueBindingDefinition, QueueDefinition,
+    };
+    use std::time::Duration;
+
+    fn topology() -> AmqpTopology {
+        AmqpTopology::new()
+            .exchange(ExchangeDefinition::name("exchange").direct())
+            .queue(
+                QueueDefinition::name("queue")
+                    .with_dlq()
+                    .with_retry(1000, 3)
+                    .binding(QueueBindingDefinition::new("exchange", "queue", "key")),
+            )
+            .delayed(DelayedDefinition::new("exchange", "key").delay(Duration::from_secs(30)))
+    }
+
+    #[test]
+    fn test_to_definitions() {
+        let definitions = topology().to_definitions("/");
+
+        assert_eq!(definitions["exchanges"][0]["name"], "exchange");
+        assert_eq!(definitions["exchanges"][0]["type"], "direct");
+        assert_eq!(definitions["exchanges"][0]["vhost"], "/");
+
+        let queues = definitions["queues"].as_array().unwrap();
+        let names = queues
+            .iter()
+            .map(|q| q["name"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "queue-retry",
+                "queue-dlq",
+                "queue",
+                "exchange.key.delay-30000"
+            ]
+        );
+        assert_eq!(queues[0]["arguments"]["x-message-ttl"], 1000);
+        assert_eq!(
+            queues[2]["arguments"]["x-dead-letter-routing-key"],
+            "queue-retry"
+        );
+
+        assert_eq!(definitions["bindings"][0]["source"], "exchange");
+        assert_eq!(definitions["bindings"][0]["destination"], "queue");
+        assert_eq!(definitions["bindings"][0]["destination_type"], "queue");
+        assert_eq!(definitions["bindings"][0]["routing_key"], "key");
+    }
+
+    #[test]
+    fn test_to_graphviz() {
+        let dot = topology().to_graphviz();
+
+        assert!(dot.starts_with("digraph topology {"));
+        assert!(dot.contains("\"exchange:exchange\" [label=\"exchange\\n(direct)\", shape=box];"));
+        assert!(dot.contains("\"exchange:exchange\" -> \"queue:queue\" [label=\"key\"];"));
+        assert!(dot.contains(
+            "\"queue:queue\" -> \"queue:queue-retry\" [label=\"dead-letter\", style=dashed];"
+        ));
+        assert!(dot.contains(
+            "\"queue:queue-retry\" -> \"queue:queue\" [label=\"ttl 1000ms\", style=dashed];"
+        ));
+        assert!(dot.contains(
+            "\"queue:queue\" -> \"queue:queue-dlq\" [label=\"retries exhausted or rejected\", style=dashed];"
+        ));
+        assert!(dot.contains(
+            "\"queue:exchange.key.delay-30000\" -> \"exchange:exchange\" [label=\"ttl 30000ms key\", style=dashed];"
+        ));
+    }
+
+    #[test]
+    fn test_to_mermaid() {
+        let mermaid = topology().to_mermaid();
+
+        assert!(mermaid.starts_with("flowchart LR\n"));
+        assert!(mermaid.contains("    e_exchange[\"exchange (direct)\"]\n"));
+        assert!(mermaid.contains("    q_queue_dlq([\"queue-dlq\"])\n"));
+        assert!(mermaid.contains("    e_exchange -->|\"key\"| q_queue\n"));
+        assert!(mermaid.contains("    q_queue -.->|\"dead-letter\"| q_queue_retry\n"));
+        assert!(
+            mermaid.contains("    q_queue -.->|\"retries exhausted or rejected\"| q_queue_dlq\n")
+        );
+
+        // without retries the broker dead-letters to the DLQ itself
+        let topology = AmqpTopology::new().queue(QueueDefinition::name("queue").with_dlq());
+        let mermaid = topology.to_mermaid();
+        assert_eq!(mermaid.matches("q_queue -.->").count(), 1);
+    }
+}
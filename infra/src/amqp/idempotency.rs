@@ -0,0 +1,234 @@
+use crate::errors::AmqpError;
+use async_trait::async_trait;
+use deadpool_postgres::Pool;
+use log::{debug, error};
+use lru::LruCache;
+use std::{
+    fmt::{self, Debug},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::task::JoinHandle;
+
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Message ids already handled by each consumer, kept for the store retention.
+///
+/// A redelivered message whose id was marked is acked without calling the
+/// handler. Ids are marked once the handler succeeds or drops the message.
+#[async_trait]
+pub trait IdempotencyStore: Debug {
+    async fn seen(&self, consumer: &str, message_id: &str) -> Result<bool, AmqpError>;
+    async fn mark(&self, consumer: &str, message_id: &str) -> Result<(), AmqpError>;
+    /// Removes the ids older than the retention, returning how many.
+    async fn purge(&self) -> Result<u64, AmqpError>;
+}
+
+/// Keeps the last `capacity` ids of the process, redeliveries after a restart
+/// are processed again.
+#[derive(Debug)]
+pub struct InMemoryIdempotencyStore {
+    ids: Mutex<LruCache<(String, String), Instant>>,
+    retention: Duration,
+}
+
+impl InMemoryIdempotencyStore {
+    pub fn new(capacity: usize, retention: Duration) -> Arc<dyn IdempotencyStore + Send + Sync> {
+        Arc::new(InMemoryIdempotencyStore {
+            ids: Mutex::new(LruCache::new(capacity.max(1))),
+            retention,
+        })
+    }
+}
+
+#[async_trait]
+impl IdempotencyStore for InMemoryIdempotencyStore {
+    async fn seen(&self, consumer: &str, message_id: &str) -> Result<bool, AmqpError> {
+        let key = (consumer.to_owned(), message_id.to_owned());
+        let mut ids = self.ids.lock().unwrap();
+
+        match ids.get(&key) {
+            Some(marked) if marked.elapsed() <= self.retention => Ok(true),
+            Some(_) => {
+                ids.pop(&key);
+                Ok(false)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn mark(&self, consumer: &str, message_id: &str) -> Result<(), AmqpError> {
+        self.ids
+            .lock()
+            .unwrap()
+            .put((consumer.to_owned(), message_id.to_owned()), Instant::now());
+        Ok(())
+    }
+
+    async fn purge(&self) -> Result<u64, AmqpError> {
+        let mut ids = self.ids.lock().unwrap();
+        let expired = ids
+            .iter()
+            .filter(|(_, marked)| marked.elapsed() > self.retention)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        for key in expired.iter() {
+            ids.pop(key);
+        }
+
+        Ok(expired.len() as u64)
+    }
+}
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS processed_messages (
+    consumer TEXT NOT NULL,
+    message_id TEXT NOT NULL,
+    processed_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (consumer, message_id)
+)";
+
+const SELECT_ID: &str = "SELECT 1 FROM processed_messages
+    WHERE consumer = $1 AND message_id = $2
+    AND processed_at > now() - make_interval(secs => $3)";
+
+const INSERT_ID: &str = "INSERT INTO processed_messages (consumer, message_id)
+    VALUES ($1, $2)
+    ON CONFLICT (consumer, message_id) DO UPDATE SET processed_at = now()";
+
+const DELETE_EXPIRED: &str =
+    "DELETE FROM processed_messages WHERE processed_at < now() - make_interval(secs => $1)";
+
+/// Shares the processed ids between every instance of the consumers through
+/// the `processed_messages` table.
+pub struct PostgresIdempotencyStore {
+    pool: Arc<Pool>,
+    retention: Duration,
+}
+
+// the pool of deadpool-postgres 0.10 has no Debug
+impl fmt::Debug for PostgresIdempotencyStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PostgresIdempotencyStore")
+            .field("retention", &self.retention)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PostgresIdempotencyStore {
+    /// Creates the `processed_messages` table when missing.
+    pub async fn new(
+        pool: Arc<Pool>,
+        retention: Duration,
+    ) -> Result<Arc<dyn IdempotencyStore + Send + Sync>, AmqpError> {
+        let store = PostgresIdempotencyStore { pool, retention };
+
+        let client = store.client().await?;
+        client.batch_execute(CREATE_TABLE).await.map_err(|err| {
+            error!("failure to create processed_messages table - {:?}", err);
+            AmqpError::IdempotencyStoreError("processed_messages".to_owned())
+        })?;
+
+        Ok(Arc::new(store))
+    }
+
+    async fn client(&self) -> Result<deadpool_postgres::Object, AmqpError> {
+        self.pool.get().await.map_err(|err| {
+            error!("failure to get a db connection - {:?}", err);
+            AmqpError::IdempotencyStoreError("connection".to_owned())
+        })
+    }
+}
+
+#[async_trait]
+impl IdempotencyStore for PostgresIdempotencyStore {
+    async fn seen(&self, consumer: &str, message_id: &str) -> Result<bool, AmqpError> {
+        let client = self.client().await?;
+        let retention = self.retention.as_secs_f64();
+
+        let rows = client
+            .query(SELECT_ID, &[&consumer, &message_id, &retention])
+            .await
+            .map_err(|err| {
+                error!("failure to query processed message - {:?}", err);
+                AmqpError::IdempotencyStoreError(message_id.to_owned())
+            })?;
+
+        Ok(!rows.is_empty())
+    }
+
+    async fn mark(&self, consumer: &str, message_id: &str) -> Result<(), AmqpError> {
+        let client = self.client().await?;
+
+        client
+            .execute(INSERT_ID, &[&consumer, &message_id])
+            .await
+            .map_err(|err| {
+                error!("failure to mark processed message - {:?}", err);
+                AmqpError::IdempotencyStoreError(message_id.to_owned())
+            })?;
+
+        Ok(())
+    }
+
+    async fn purge(&self) -> Result<u64, AmqpError> {
+        let client = self.client().await?;
+        let retention = self.retention.as_secs_f64();
+
+        client
+            .execute(DELETE_EXPIRED, &[&retention])
+            .await
+            .map_err(|err| {
+                error!("failure to purge processed messages - {:?}", err);
+                AmqpError::IdempotencyStoreError("purge".to_owned())
+            })
+    }
+}
+
+/// Purges the store every `period` until the task is aborted.
+pub fn purge_every(
+    store: Arc<dyn IdempotencyStore + Send + Sync>,
+    period: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            match store.purge().await {
+                Ok(purged) => debug!("purged {} processed message ids", purged),
+                Err(err) => error!("{:?}", err),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_in_memory_store() {
+        let store = InMemoryIdempotencyStore::new(2, DEFAULT_RETENTION);
+        assert!(!store.seen("consumer", "1").await.unwrap());
+
+        store.mark("consumer", "1").await.unwrap();
+        assert!(store.seen("consumer", "1").await.unwrap());
+        assert!(!store.seen("other", "1").await.unwrap());
+
+        store.mark("consumer", "2").await.unwrap();
+        store.mark("consumer", "3").await.unwrap();
+        assert!(!store.seen("consumer", "1").await.unwrap());
+        assert!(store.seen("consumer", "3").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_store_retention() {
+        let store = InMemoryIdempotencyStore::new(10, Duration::from_millis(10));
+        store.mark("consumer", "1").await.unwrap();
+        store.mark("consumer", "2").await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!store.seen("consumer", "1").await.unwrap());
+        assert_eq!(store.purge().await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,1559 @@
+use super::{
+    client::{
+        checked_body, properties, settle, with_failure_reason, with_origin, DelayRoutes,
+        Deliveries, IAmqp, Settlement,
+    },
+    rpc::{reply_result, DIRECT_REPLY_TO},
+    topology::{
+        AmqpTopology, ConsumerDefinition, ConsumerHandler, ExchangeDefinition, ExchangeKind,
+        HeadersMatch, QueueDeclaration, QueueDefinition,
+    },
+    types::PublishData,
+};
+use crate::{
+    env::Config,
+    errors::AmqpError,
+    otel::{self, semconv},
+};
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt};
+use lapin::{
+    acker::Acker,
+    message::Delivery,
+    protocol::basic::AMQPProperties,
+    types::{AMQPValue, FieldArray, FieldTable, LongLongInt, LongString, ShortString},
+    Channel, Connection,
+};
+use log::{debug, warn};
+use opentelemetry::{
+    global::{self, BoxedTracer},
+    trace::{SpanKind, StatusCode},
+    Context,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::{mpsc, oneshot};
+use uuid::Uuid;
+
+/// In-process broker with the RabbitMQ semantics the services rely on, so
+/// routing, retries and DLQs can be asserted without a live broker.
+///
+/// Exchanges route as their kind with exchange bindings and alternate
+/// exchanges, queues dead-letter expired and rejected messages recording
+/// `x-death`, and deliveries stay unacked until `consume` settles them.
+/// Time is virtual: TTLs and delays only elapse through `advance`.
+pub struct InMemoryAmqp {
+    broker: Mutex<Broker>,
+    delays: DelayRoutes,
+    app_name: &'static str,
+    max_message_size: usize,
+    tracer: BoxedTracer,
+}
+
+#[derive(Default)]
+struct Broker {
+    clock: u64,
+    next_tag: u64,
+    exchanges: HashMap<String, Exchange>,
+    bindings: Vec<Binding>,
+    queues: HashMap<String, MemoryQueue>,
+    consumers: BTreeMap<String, Subscription>,
+    scheduled: Vec<Scheduled>,
+    replies: HashMap<String, oneshot::Sender<Delivery>>,
+}
+
+struct Exchange {
+    kind: ExchangeKind,
+    delayed: bool,
+    alternate: Option<String>,
+}
+
+struct Binding {
+    source: String,
+    destination: Destination,
+    key: String,
+    arguments: BTreeMap<ShortString, AMQPValue>,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+enum Destination {
+    Queue(String),
+    Exchange(String),
+}
+
+#[derive(Default)]
+struct MemoryQueue {
+    arguments: BTreeMap<ShortString, AMQPValue>,
+    ready: VecDeque<Message>,
+    unacked: BTreeMap<u64, (Option<String>, Message)>,
+}
+
+#[derive(Clone)]
+struct Message {
+    exchange: String,
+    routing_key: String,
+    properties: AMQPProperties,
+    data: Vec<u8>,
+    redelivered: bool,
+    expires_at: Option<u64>,
+}
+
+struct Subscription {
+    queue: String,
+    prefetch: usize,
+    priority: i32,
+    tx: mpsc::UnboundedSender<Delivery>,
+}
+
+/// Message held by a delayed exchange until its `x-delay` elapses.
+struct Scheduled {
+    due: u64,
+    exchange: String,
+    message: Message,
+}
+
+impl InMemoryAmqp {
+    pub fn new(cfg: &Config) -> Arc<InMemoryAmqp> {
+        Arc::new(InMemoryAmqp {
+            broker: Mutex::new(Broker::default()),
+            delays: DelayRoutes::default(),
+            app_name: cfg.app_name,
+            max_message_size: cfg.amqp_max_message_size,
+            tracer: global::tracer("amqp"),
+        })
+    }
+
+    /// Moves the virtual clock, releasing the delayed messages and
+    /// dead-lettering the expired ones that are due.
+    pub fn advance(&self, by: Duration) {
+        let mut broker = self.broker.lock().unwrap();
+        broker.clock += by.as_millis() as u64;
+        broker.tick();
+    }
+
+    /// Fetches the next ready message of `queue` like `basic_get`, it stays
+    /// unacked until settled with `consume`.
+    pub fn get(&self, queue: &str) -> Option<Delivery> {
+        let mut broker = self.broker.lock().unwrap();
+        let message = broker.queues.get_mut(queue)?.ready.pop_front()?;
+        Some(broker.deliver(queue, None, message))
+    }
+
+    /// Ready messages of `queue` in delivery order.
+    pub fn messages(&self, queue: &str) -> Vec<Delivery> {
+        let broker = self.broker.lock().unwrap();
+        match broker.queues.get(queue) {
+            Some(q) => q.ready.iter().map(|m| m.delivery(0)).collect(),
+            _ => vec![],
+        }
+    }
+
+    pub fn unacked(&self, queue: &str) -> usize {
+        let broker = self.broker.lock().unwrap();
+        broker.queues.get(queue).map_or(0, |q| q.unacked.len())
+    }
+
+    async fn rpc_call(
+        &self,
+        cx: &Context,
+        exchange: &str,
+        key: &str,
+        data: &PublishData,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, AmqpError> {
+        let correlation_id = Uuid::new_v4().to_string();
+        let data = data
+            .clone()
+            .correlation_id(&correlation_id)
+            .reply_to(DIRECT_REPLY_TO);
+
+        let (tx, rx) = oneshot::channel();
+        self.broker
+            .lock()
+            .unwrap()
+            .replies
+            .insert(correlation_id.clone(), tx);
+
+        if let Err(err) = self.publish_message(cx, exchange, key, &data) {
+            self.broker.lock().unwrap().replies.remove(&correlation_id);
+            return Err(err);
+        }
+
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(reply)) => reply_result(reply, self.max_message_size),
+            Ok(Err(_)) => Err(AmqpError::RpcError("reply consumer closed".to_owned())),
+            Err(_) => {
+                warn!("rpc call: {} timed out", correlation_id);
+                self.broker.lock().unwrap().replies.remove(&correlation_id);
+                Err(AmqpError::RpcTimeoutError {})
+            }
+        }
+    }
+
+    fn publish_message(
+        &self,
+        cx: &Context,
+        exchange: &str,
+        key: &str,
+        data: &PublishData,
+    ) -> Result<(), AmqpError> {
+        let body = checked_body(data, self.max_message_size)?.into_owned();
+        let (exchange, key, properties) =
+            properties(cx, self.app_name, &self.delays, exchange, key, data)?;
+
+        let message = Message {
+            exchange: exchange.clone(),
+            routing_key: key,
+            properties,
+            data: body,
+            redelivered: false,
+            expires_at: None,
+        };
+
+        let routed = self.broker.lock().unwrap().publish(&exchange, message)?;
+        if !routed && data.mandatory && data.confirm.is_some() {
+            return Err(AmqpError::ReturnedMessageError(312, "NO_ROUTE".to_owned()));
+        }
+
+        Ok(())
+    }
+
+    fn declare(&self, declaration: &QueueDeclaration) {
+        debug!("declaring queue: {}", declaration.name);
+
+        let mut broker = self.broker.lock().unwrap();
+        let queue = broker.queues.entry(declaration.name.clone()).or_default();
+        queue.arguments = declaration.arguments.clone();
+    }
+
+    fn install_exchange(&self, exch: &ExchangeDefinition) {
+        debug!("creating exchange: {}", exch.name);
+
+        self.broker.lock().unwrap().exchanges.insert(
+            exch.name.to_owned(),
+            Exchange {
+                kind: exch.kind.clone(),
+                delayed: exch.delayed,
+                alternate: exch.alternate_exchange.map(str::to_owned),
+            },
+        );
+    }
+
+    fn install_queue(&self, def: &QueueDefinition) {
+        for declaration in def.declarations() {
+            self.declare(&declaration);
+        }
+
+        let mut broker = self.broker.lock().unwrap();
+        for bind in def.bindings.iter() {
+            broker.bind(
+                bind.exchange,
+                Destination::Queue(bind.queue.to_owned()),
+                bind.routing_key,
+                bind.arguments(),
+            );
+        }
+    }
+
+    /// Applies the settlement of a delivery to the broker.
+    fn settle_delivery(
+        &self,
+        def: &ConsumerDefinition,
+        delivery: &Delivery,
+        settlement: Settlement,
+    ) -> Result<(), AmqpError> {
+        let mut broker = self.broker.lock().unwrap();
+        let (queue, message) =
+            broker
+                .take_unacked(delivery.delivery_tag)
+                .ok_or(match settlement {
+                    Settlement::Nack(_) => AmqpError::NackMessageError {},
+                    _ => AmqpError::AckMessageError {},
+                })?;
+
+        match settlement {
+            Settlement::Handled | Settlement::Ack => {}
+            Settlement::Nack(true) => broker.requeue(&queue, message),
+            Settlement::Nack(false) => broker.dead_letter(&queue, message, "rejected"),
+            Settlement::Retry(retry) => {
+                let data = message.data.clone();
+                if !broker.republish(&retry, with_origin(delivery), data)? {
+                    broker.requeue(&queue, message);
+                    return Err(AmqpError::RequeuingMessageError {});
+                }
+            }
+            Settlement::Dlq(reason) => {
+                let properties = with_failure_reason(&with_origin(delivery), &reason);
+                let data = message.data.clone();
+                if !broker.republish(&def.dlq(), properties, data)? {
+                    broker.requeue(&queue, message);
+                    return Err(AmqpError::PublishingToDQLError {});
+                }
+            }
+        }
+
+        broker.dispatch(&queue);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl IAmqp for InMemoryAmqp {
+    fn channel(&self) -> Result<&Channel, AmqpError> {
+        Err(AmqpError::ChannelError {})
+    }
+
+    fn connection(&self) -> Result<&Connection, AmqpError> {
+        Err(AmqpError::ConnectionError {})
+    }
+
+    async fn declare_queue(
+        &self,
+        name: &str,
+        _delete: bool,
+        _durable: bool,
+        _exclusive: bool,
+    ) -> Result<(), AmqpError> {
+        let mut broker = self.broker.lock().unwrap();
+        broker.queues.entry(name.to_owned()).or_default();
+        Ok(())
+    }
+
+    async fn declare_exchange(
+        &self,
+        name: &str,
+        _delete: bool,
+        _durable: bool,
+        _internal: bool,
+    ) -> Result<(), AmqpError> {
+        let mut broker = self.broker.lock().unwrap();
+        broker.exchanges.insert(
+            name.to_owned(),
+            Exchange {
+                kind: ExchangeKind::Direct,
+                delayed: false,
+                alternate: None,
+            },
+        );
+        Ok(())
+    }
+
+    async fn binding_exchange_queue(
+        &self,
+        exch: &str,
+        queue: &str,
+        key: &str,
+    ) -> Result<(), AmqpError> {
+        let mut broker = self.broker.lock().unwrap();
+        if !broker.exchanges.contains_key(exch) || !broker.queues.contains_key(queue) {
+            return Err(AmqpError::BindingExchangeToQueueError(
+                exch.to_owned(),
+                queue.to_owned(),
+            ));
+        }
+
+        broker.bind(
+            exch,
+            Destination::Queue(queue.to_owned()),
+            key,
+            BTreeMap::new(),
+        );
+        Ok(())
+    }
+
+    async fn consumer(&self, def: &ConsumerDefinition) -> Result<Deliveries, AmqpError> {
+        debug!("creating consumer: {}", def.name);
+
+        let mut broker = self.broker.lock().unwrap();
+        if !broker.queues.contains_key(def.queue) {
+            return Err(AmqpError::BindingConsumerError(def.name.to_owned()));
+        }
+        if broker.consumers.contains_key(def.name) {
+            return Err(AmqpError::DuplicateConsumerError(def.name.to_owned()));
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        broker.consumers.insert(
+            def.name.to_owned(),
+            Subscription {
+                queue: def.queue.to_owned(),
+                prefetch: def.prefetch.max(1) as usize,
+                priority: def.priority.unwrap_or_default(),
+                tx,
+            },
+        );
+        broker.dispatch(def.queue);
+
+        Ok(stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|delivery| (Ok(delivery), rx))
+        })
+        .boxed())
+    }
+
+    async fn cancel_consumer(&self, def: &ConsumerDefinition) -> Result<(), AmqpError> {
+        debug!("canceling consumer: {}", def.name);
+        let mut broker = self.broker.lock().unwrap();
+        let subscription = match broker.consumers.remove(def.name) {
+            Some(subscription) => subscription,
+            _ => return Ok(()),
+        };
+
+        // closing the consumer channel requeues what it left unacked
+        let queue = broker.queue(&subscription.queue);
+        let held: Vec<u64> = queue
+            .unacked
+            .iter()
+            .filter(|(_, (consumer, _))| consumer.as_deref() == Some(def.name))
+            .map(|(tag, _)| *tag)
+            .collect();
+        for tag in held.into_iter().rev() {
+            if let Some((_, message)) = queue.unacked.remove(&tag) {
+                queue.ready.push_front(Message {
+                    redelivered: true,
+                    ..message
+                });
+            }
+        }
+
+        broker.dispatch(&subscription.queue);
+        Ok(())
+    }
+
+    async fn publish(
+        &self,
+        ctx: &Context,
+        exchange: &str,
+        key: &str,
+        data: &PublishData,
+    ) -> Result<(), AmqpError> {
+        let span =
+            otel::tracing::span_from_ctx(&self.tracer, ctx, "amqp publishing", SpanKind::Producer);
+        span.set_attributes(semconv::messaging(
+            semconv::RABBITMQ,
+            exchange,
+            semconv::PUBLISH,
+            data.message_id.as_deref(),
+        ));
+
+        let result = self.publish_message(span.context(), exchange, key, data);
+        span.finish(result)
+    }
+
+    async fn publish_batch(
+        &self,
+        ctx: &Context,
+        exchange: &str,
+        key: &str,
+        data: &[PublishData],
+        timeout: Duration,
+    ) -> Result<(), AmqpError> {
+        let span = otel::tracing::span_from_ctx(
+            &self.tracer,
+            ctx,
+            "amqp batch publishing",
+            SpanKind::Producer,
+        );
+        span.set_attributes(semconv::messaging(
+            semconv::RABBITMQ,
+            exchange,
+            semconv::PUBLISH,
+            None,
+        ));
+
+        let result = data.iter().try_for_each(|msg| {
+            let msg = msg.clone().with_confirm(timeout);
+            self.publish_message(span.context(), exchange, key, &msg)
+        });
+        span.finish(result)
+    }
+
+    async fn call(
+        &self,
+        ctx: &Context,
+        exchange: &str,
+        key: &str,
+        data: &PublishData,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, AmqpError> {
+        let span =
+            otel::tracing::span_from_ctx(&self.tracer, ctx, "amqp rpc call", SpanKind::Producer);
+        span.set_attributes(semconv::messaging(
+            semconv::RABBITMQ,
+            exchange,
+            semconv::PUBLISH,
+            data.message_id.as_deref(),
+        ));
+
+        let result = self
+            .rpc_call(span.context(), exchange, key, data, timeout)
+            .await;
+        span.finish(result)
+    }
+
+    async fn install_topology(&self, topology: &AmqpTopology) -> Result<(), AmqpError> {
+        topology.validate()?;
+
+        for exch in topology.exchanges.iter() {
+            self.install_exchange(exch);
+        }
+
+        {
+            let mut broker = self.broker.lock().unwrap();
+            for bind in topology.exchanges.iter().flat_map(|e| e.bindings.iter()) {
+                broker.bind(
+                    bind.source,
+                    Destination::Exchange(bind.destination.to_owned()),
+                    bind.routing_key,
+                    BTreeMap::new(),
+                );
+            }
+        }
+
+        for queue in topology.queues.iter() {
+            self.install_queue(queue);
+        }
+
+        for delayed in topology.delayed.iter() {
+            for declaration in delayed.declarations() {
+                self.declare(&declaration);
+            }
+        }
+
+        self.register_topology(topology);
+        Ok(())
+    }
+
+    fn register_topology(&self, topology: &AmqpTopology) {
+        self.delays.register(topology);
+    }
+
+    async fn consume(
+        &self,
+        def: &ConsumerDefinition,
+        handler: Arc<dyn ConsumerHandler + Send + Sync>,
+        delivery: &Delivery,
+    ) -> Result<(), AmqpError> {
+        let (settlement, span) = settle(
+            &self.tracer,
+            self.max_message_size,
+            &self.delays,
+            def,
+            handler,
+            delivery,
+        )
+        .await;
+
+        let handled = settlement == Settlement::Handled;
+        let result = self.settle_delivery(def, delivery, settlement);
+        match &result {
+            Ok(_) if handled => span.set_status(StatusCode::Ok, "success".to_owned()),
+            Ok(_) => {}
+            Err(err) => span.record_error(err),
+        }
+        result
+    }
+
+    async fn close(&self) -> Result<(), AmqpError> {
+        let mut broker = self.broker.lock().unwrap();
+        broker.consumers.clear();
+        broker.replies.clear();
+
+        // closing the channels requeues whatever was left unacked
+        let queues = broker.queues.keys().cloned().collect::<Vec<_>>();
+        for name in queues {
+            let queue = broker.queue(&name);
+            let unacked = std::mem::take(&mut queue.unacked);
+            for (_, (_, message)) in unacked.into_iter().rev() {
+                queue.ready.push_front(Message {
+                    redelivered: true,
+                    ..message
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Broker {
+    fn queue(&mut self, name: &str) -> &mut MemoryQueue {
+        self.queues.entry(name.to_owned()).or_default()
+    }
+
+    fn bind(
+        &mut self,
+        source: &str,
+        destination: Destination,
+        key: &str,
+        arguments: BTreeMap<ShortString, AMQPValue>,
+    ) {
+        let exists = self
+            .bindings
+            .iter()
+            .any(|b| b.source == source && b.destination == destination && b.key == key);
+        if !exists {
+            self.bindings.push(Binding {
+                source: source.to_owned(),
+                destination,
+                key: key.to_owned(),
+                arguments,
+            });
+        }
+    }
+
+    /// Routes a message, returning whether any queue got it. Replies to a
+    /// direct reply-to are handed to the `call` waiting for them.
+    fn publish(&mut self, exchange: &str, message: Message) -> Result<bool, AmqpError> {
+        if exchange.is_empty() && message.routing_key.starts_with(DIRECT_REPLY_TO) {
+            let id = message
+                .properties
+                .correlation_id()
+                .as_ref()
+                .map(|id| id.to_string())
+                .unwrap_or_default();
+            return match self.replies.remove(&id) {
+                Some(tx) => Ok(tx.send(message.delivery(0)).is_ok()),
+                _ => {
+                    debug!("discarding late reply: {}", id);
+                    Ok(false)
+                }
+            };
+        }
+
+        let delayed = match self.exchanges.get(exchange) {
+            Some(exch) => exch.delayed,
+            _ if exchange.is_empty() => false,
+            _ => return Err(AmqpError::PublishingError {}),
+        };
+
+        let delay = match delayed {
+            true => x_delay(&message.properties),
+            _ => None,
+        };
+        if let Some(delay) = delay {
+            self.scheduled.push(Scheduled {
+                due: self.clock + delay,
+                exchange: exchange.to_owned(),
+                message,
+            });
+            return Ok(true);
+        }
+
+        Ok(self.enqueue(exchange, message))
+    }
+
+    fn enqueue(&mut self, exchange: &str, message: Message) -> bool {
+        let queues = self.route(exchange, &message);
+        if queues.is_empty() {
+            warn!(
+                "unroutable message - exchange: {} - key: {}",
+                exchange, message.routing_key
+            );
+            return false;
+        }
+
+        for name in queues {
+            let now = self.clock;
+            let queue = self.queue(&name);
+            let ttl = [
+                message_ttl(&message.properties),
+                queue_ttl(&queue.arguments),
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+            queue.ready.push_back(Message {
+                expires_at: ttl.map(|ttl| now + ttl),
+                ..message.clone()
+            });
+            self.dispatch(&name);
+        }
+
+        true
+    }
+
+    /// Publishes through the default exchange as retry and DLQ hops do.
+    /// Copies a settled message to `queue`, returning whether it was routed.
+    fn republish(
+        &mut self,
+        queue: &str,
+        properties: AMQPProperties,
+        data: Vec<u8>,
+    ) -> Result<bool, AmqpError> {
+        let message = Message {
+            exchange: "".to_owned(),
+            routing_key: queue.to_owned(),
+            properties,
+            data,
+            redelivered: false,
+            expires_at: None,
+        };
+        self.publish("", message)
+    }
+
+    /// Puts an unacked message back at the head of `queue`, as redelivered.
+    fn requeue(&mut self, queue: &str, message: Message) {
+        let message = Message {
+            redelivered: true,
+            ..message
+        };
+        self.queue(queue).ready.push_front(message);
+        self.dispatch(queue);
+    }
+
+    fn route(&self, exchange: &str, message: &Message) -> BTreeSet<String> {
+        let mut queues = BTreeSet::new();
+        let mut visited = BTreeSet::new();
+        self.route_into(exchange, message, &mut queues, &mut visited);
+        queues
+    }
+
+    fn route_into(
+        &self,
+        exchange: &str,
+        message: &Message,
+        queues: &mut BTreeSet<String>,
+        visited: &mut BTreeSet<String>,
+    ) {
+        if !visited.insert(exchange.to_owned()) {
+            return;
+        }
+
+        if exchange.is_empty() {
+            if self.queues.contains_key(&message.routing_key) {
+                queues.insert(message.routing_key.clone());
+            }
+            return;
+        }
+
+        let exch = match self.exchanges.get(exchange) {
+            Some(exch) => exch,
+            _ => return,
+        };
+
+        let before = queues.len();
+        let headers = message.properties.headers().clone().unwrap_or_default();
+        for bind in self.bindings.iter().filter(|b| b.source == exchange) {
+            if !matches(&exch.kind, bind, &message.routing_key, &headers) {
+                continue;
+            }
+
+            match &bind.destination {
+                Destination::Queue(queue) => {
+                    queues.insert(queue.clone());
+                }
+                Destination::Exchange(destination) => {
+                    self.route_into(destination, message, queues, visited)
+                }
+            }
+        }
+
+        if queues.len() == before {
+            if let Some(alternate) = &exch.alternate {
+                self.route_into(alternate, message, queues, visited);
+            }
+        }
+    }
+
+    /// Pushes ready messages to the consumers of `queue` with prefetch room
+    /// left, the highest priority consumers first.
+    fn dispatch(&mut self, queue: &str) {
+        loop {
+            let consumer = {
+                let unacked = match self.queues.get(queue) {
+                    Some(q) if !q.ready.is_empty() => &q.unacked,
+                    _ => return,
+                };
+                self.consumers
+                    .iter()
+                    .filter(|(_, sub)| sub.queue == queue)
+                    .filter(|(name, sub)| {
+                        let held = unacked
+                            .values()
+                            .filter(|(owner, _)| owner.as_deref() == Some(name.as_str()))
+                            .count();
+                        held < sub.prefetch
+                    })
+                    .max_by_key(|(_, sub)| sub.priority)
+                    .map(|(name, _)| name.clone())
+            };
+
+            let consumer = match consumer {
+                Some(consumer) => consumer,
+                _ => return,
+            };
+
+            let message = self.queue(queue).ready.pop_front().unwrap();
+            let delivery = self.deliver(queue, Some(consumer.clone()), message);
+            let tag = delivery.delivery_tag;
+            if self.consumers[&consumer].tx.send(delivery).is_err() {
+                debug!("consumer: {} is gone, requeuing its message", consumer);
+                self.consumers.remove(&consumer);
+                if let Some((_, message)) = self.take_unacked(tag) {
+                    self.queue(queue).ready.push_front(message);
+                }
+            }
+        }
+    }
+
+    fn deliver(&mut self, queue: &str, consumer: Option<String>, message: Message) -> Delivery {
+        self.next_tag += 1;
+        let tag = self.next_tag;
+        let delivery = message.delivery(tag);
+        self.queue(queue).unacked.insert(tag, (consumer, message));
+        delivery
+    }
+
+    fn take_unacked(&mut self, tag: u64) -> Option<(String, Message)> {
+        self.queues.iter_mut().find_map(|(name, queue)| {
+            queue
+                .unacked
+                .remove(&tag)
+                .map(|(_, message)| (name.clone(), message))
+        })
+    }
+
+    /// Republishes to the dead letter exchange of `queue`, recording the death
+    /// in `x-death`. Messages are dropped when the queue has none.
+    fn dead_letter(&mut self, queue: &str, message: Message, reason: &str) {
+        let arguments = &self.queue(queue).arguments;
+        let exchange = match string_argument(arguments, "x-dead-letter-exchange") {
+            Some(exchange) => exchange,
+            _ => {
+                debug!("dropping {} message of queue: {}", reason, queue);
+                return;
+            }
+        };
+        let routing_key = string_argument(arguments, "x-dead-letter-routing-key")
+            .unwrap_or_else(|| message.routing_key.clone());
+
+        let properties = with_death(&message, queue, reason, self.clock / 1000);
+        let message = Message {
+            exchange: exchange.clone(),
+            routing_key,
+            properties,
+            redelivered: false,
+            expires_at: None,
+            ..message
+        };
+
+        if let Err(err) = self.publish(&exchange, message) {
+            warn!("failure to dead-letter from: {} - {:?}", queue, err);
+        }
+    }
+
+    /// Releases the due delayed messages and dead-letters the expired ones
+    /// until nothing else is due at the current time. Like RabbitMQ, messages
+    /// only expire once they reach the head of their queue.
+    fn tick(&mut self) {
+        loop {
+            let now = self.clock;
+            let (due, pending) = std::mem::take(&mut self.scheduled)
+                .into_iter()
+                .partition::<Vec<_>, _>(|s| s.due <= now);
+            self.scheduled = pending;
+
+            let mut expired = vec![];
+            for (name, queue) in self.queues.iter_mut() {
+                while let Some(message) = queue.ready.pop_front() {
+                    match message.expires_at {
+                        Some(at) if at <= now => expired.push((name.clone(), message)),
+                        _ => {
+                            queue.ready.push_front(message);
+                            break;
+                        }
+                    }
+                }
+            }
+
+            if due.is_empty() && expired.is_empty() {
+                return;
+            }
+
+            for scheduled in due {
+                self.enqueue(&scheduled.exchange, scheduled.message);
+            }
+            for (queue, message) in expired {
+                self.dead_letter(&queue, message, "expired");
+            }
+        }
+    }
+}
+
+impl Message {
+    fn delivery(&self, tag: u64) -> Delivery {
+        Delivery {
+            delivery_tag: tag,
+            exchange: ShortString::from(self.exchange.as_str()),
+            routing_key: ShortString::from(self.routing_key.as_str()),
+            redelivered: self.redelivered,
+            properties: self.properties.clone(),
+            data: self.data.clone(),
+            acker: Acker::default(),
+        }
+    }
+}
+
+fn matches(kind: &ExchangeKind, bind: &Binding, key: &str, headers: &FieldTable) -> bool {
+    match kind {
+        ExchangeKind::Direct => bind.key == key,
+        ExchangeKind::Fanout => true,
+        ExchangeKind::Topic => topic_matches(
+            &bind.key.split('.').collect::<Vec<_>>(),
+            &key.split('.').collect::<Vec<_>>(),
+        ),
+        ExchangeKind::Headers => {
+            let any = string_argument(&bind.arguments, "x-match").as_deref()
+                == Some(HeadersMatch::Any.as_str());
+            let mut expected = bind
+                .arguments
+                .iter()
+                .filter(|(key, _)| !key.as_str().starts_with("x-"));
+            let found = |(key, value): (&ShortString, &AMQPValue)| {
+                headers.inner().get(key.as_str()) == Some(value)
+            };
+
+            match any {
+                true => expected.any(found),
+                _ => expected.all(found),
+            }
+        }
+    }
+}
+
+/// `*` matches exactly one word and `#` zero or more.
+fn topic_matches(pattern: &[&str], key: &[&str]) -> bool {
+    match (pattern.first(), key.first()) {
+        (None, None) => true,
+        (Some(&"#"), _) => {
+            topic_matches(&pattern[1..], key)
+                || (!key.is_empty() && topic_matches(pattern, &key[1..]))
+        }
+        (Some(&"*"), Some(_)) => topic_matches(&pattern[1..], &key[1..]),
+        (Some(word), Some(other)) if word == other => topic_matches(&pattern[1..], &key[1..]),
+        _ => false,
+    }
+}
+
+fn string_argument(arguments: &BTreeMap<ShortString, AMQPValue>, key: &str) -> Option<String> {
+    arguments
+        .get(key)
+        .and_then(|value| value.as_long_string())
+        .map(|value| value.to_string())
+}
+
+fn queue_ttl(arguments: &BTreeMap<ShortString, AMQPValue>) -> Option<u64> {
+    match arguments.get("x-message-ttl")? {
+        AMQPValue::LongLongInt(ttl) => Some((*ttl).max(0) as u64),
+        AMQPValue::LongInt(ttl) => Some((*ttl).max(0) as u64),
+        _ => None,
+    }
+}
+
+fn message_ttl(properties: &AMQPProperties) -> Option<u64> {
+    properties
+        .expiration()
+        .as_ref()
+        .and_then(|expiration| expiration.as_str().parse().ok())
+}
+
+fn x_delay(properties: &AMQPProperties) -> Option<u64> {
+    let headers = properties.headers().as_ref()?;
+    match headers.inner().get("x-delay")? {
+        AMQPValue::LongLongInt(delay) => Some((*delay).max(0) as u64),
+        AMQPValue::LongInt(delay) => Some((*delay).max(0) as u64),
+        _ => None,
+    }
+}
+
+/// Copies the properties the way the broker dead-letters: the per-message
+/// expiration is removed and the death is counted in `x-death`, newest first.
+fn with_death(message: &Message, queue: &str, reason: &str, time: u64) -> AMQPProperties {
+    let mut headers = match message.properties.headers() {
+        Some(headers) => headers.inner().clone(),
+        None => BTreeMap::new(),
+    };
+
+    let mut deaths = match headers.get("x-death").and_then(|v| v.as_array()) {
+        Some(arr) => arr
+            .as_slice()
+            .iter()
+            .filter_map(|v| v.as_field_table().cloned())
+            .collect::<Vec<_>>(),
+        _ => vec![],
+    };
+
+    let same = |death: &FieldTable| {
+        let get = |key: &str| death.inner().get(key).and_then(|v| v.as_long_string());
+        get("queue").map(|q| q.to_string()).as_deref() == Some(queue)
+            && get("reason").map(|r| r.to_string()).as_deref() == Some(reason)
+    };
+    let count = match deaths.iter().position(same) {
+        Some(idx) => {
+            let death = deaths.remove(idx);
+            death
+                .inner()
+                .get("count")
+                .and_then(|v| v.as_long_long_int())
+                .unwrap_or(0)
+        }
+        _ => 0,
+    };
+
+    let mut death = BTreeMap::new();
+    death.insert(
+        ShortString::from("queue"),
+        AMQPValue::LongString(LongString::from(queue)),
+    );
+    death.insert(
+        ShortString::from("reason"),
+        AMQPValue::LongString(LongString::from(reason)),
+    );
+    death.insert(
+        ShortString::from("count"),
+        AMQPValue::LongLongInt(LongLongInt::from(count + 1)),
+    );
+    death.insert(
+        ShortString::from("exchange"),
+        AMQPValue::LongString(LongString::from(message.exchange.as_str())),
+    );
+    death.insert(
+        ShortString::from("routing-keys"),
+        AMQPValue::FieldArray(FieldArray::from(vec![AMQPValue::LongString(
+            LongString::from(message.routing_key.as_str()),
+        )])),
+    );
+    death.insert(ShortString::from("time"), AMQPValue::Timestamp(time));
+    deaths.insert(0, FieldTable::from(death));
+
+    headers.insert(
+        ShortString::from("x-death"),
+        AMQPValue::FieldArray(FieldArray::from(
+            deaths
+                .into_iter()
+                .map(AMQPValue::FieldTable)
+                .collect::<Vec<_>>(),
+        )),
+    );
+
+    without_expiration(&message.properties).with_headers(FieldTable::from(headers))
+}
+
+fn without_expiration(properties: &AMQPProperties) -> AMQPProperties {
+    let mut copy = AMQPProperties::default();
+    if let Some(value) = properties.content_type() {
+        copy = copy.with_content_type(value.clone());
+    }
+    if let Some(value) = properties.content_encoding() {
+        copy = copy.with_content_encoding(value.clone());
+    }
+    if let Some(value) = properties.headers() {
+        copy = copy.with_headers(value.clone());
+    }
+    if let Some(value) = properties.delivery_mode() {
+        copy = copy.with_delivery_mode(*value);
+    }
+    if let Some(value) = properties.priority() {
+        copy = copy.with_priority(*value);
+    }
+    if let Some(value) = properties.correlation_id() {
+        copy = copy.with_correlation_id(value.clone());
+    }
+    if let Some(value) = properties.reply_to() {
+        copy = copy.with_reply_to(value.clone());
+    }
+    if let Some(value) = properties.message_id() {
+        copy = copy.with_message_id(value.clone());
+    }
+    if let Some(value) = properties.timestamp() {
+        copy = copy.with_timestamp(*value);
+    }
+    if let Some(value) = properties.kind() {
+        copy = copy.with_kind(value.clone());
+    }
+    if let Some(value) = properties.user_id() {
+        copy = copy.with_user_id(value.clone());
+    }
+    if let Some(value) = properties.app_id() {
+        copy = copy.with_app_id(value.clone());
+    }
+    if let Some(value) = properties.cluster_id() {
+        copy = copy.with_cluster_id(value.clone());
+    }
+    copy
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        amqp::{
+            client::FAILURE_REASON_HEADER,
+            router::{TypeRouter, UnknownTypePolicy},
+            topology::{DelayedDefinition, ExchangeBindingDefinition, QueueBindingDefinition},
+            types::{AmqpMessageType, Metadata},
+        },
+        errors::HandlerError,
+    };
+
+    struct Failing {
+        err: fn() -> HandlerError,
+    }
+
+    #[async_trait]
+    impl ConsumerHandler for Failing {
+        async fn exec(&self, _ctx: &Context, _data: &[u8]) -> Result<(), HandlerError> {
+            Err((self.err)())
+        }
+    }
+
+    fn temp() -> PublishData {
+        PublishData::raw("Temp", b"{}".to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_routing() {
+        let amqp = InMemoryAmqp::new(&Config::mock());
+        let topology = AmqpTopology::new()
+            .exchange(ExchangeDefinition::name("direct").direct())
+            .exchange(
+                ExchangeDefinition::name("fanout")
+                    .fanout()
+                    .binding(ExchangeBindingDefinition::new("fanout", "topic", "")),
+            )
+            .exchange(
+                ExchangeDefinition::name("topic")
+                    .topic()
+                    .alternate_exchange("direct"),
+            )
+            .exchange(ExchangeDefinition::name("headers").header())
+            .queue(
+                QueueDefinition::name("direct-q")
+                    .binding(QueueBindingDefinition::new("direct", "direct-q", "key")),
+            )
+            .queue(
+                QueueDefinition::name("fanout-q")
+                    .binding(QueueBindingDefinition::new("fanout", "fanout-q", "")),
+            )
+            .queue(
+                QueueDefinition::name("topic-q").binding(QueueBindingDefinition::new(
+                    "topic",
+                    "topic-q",
+                    "iot.*.temp.#",
+                )),
+            )
+            .queue(
+                QueueDefinition::name("headers-q").binding(
+                    QueueBindingDefinition::new("headers", "headers-q", "")
+                        .match_all(&[("type", "Temp"), ("device-id", "device")]),
+                ),
+            );
+        amqp.install_topology(&topology).await.unwrap();
+
+        let ctx = Context::new();
+        amqp.publish(&ctx, "direct", "key", &temp()).await.unwrap();
+        amqp.publish(&ctx, "direct", "other", &temp())
+            .await
+            .unwrap();
+        assert_eq!(amqp.messages("direct-q").len(), 1);
+
+        // fanout copies to its queues and through the binding to the topic,
+        // which still routes on the original key
+        amqp.publish(&ctx, "fanout", "iot.a.temp", &temp())
+            .await
+            .unwrap();
+        assert_eq!(amqp.messages("fanout-q").len(), 1);
+        assert_eq!(amqp.messages("topic-q").len(), 1);
+
+        amqp.publish(&ctx, "topic", "iot.b.temp", &temp())
+            .await
+            .unwrap();
+        assert_eq!(amqp.messages("topic-q").len(), 2);
+
+        // unroutable on the topic exchange, the alternate gets it
+        amqp.publish(&ctx, "topic", "key", &temp()).await.unwrap();
+        assert_eq!(amqp.messages("direct-q").len(), 2);
+
+        amqp.publish(&ctx, "headers", "", &temp()).await.unwrap();
+        amqp.publish(&ctx, "headers", "", &temp().device_id("device"))
+            .await
+            .unwrap();
+        assert_eq!(amqp.messages("headers-q").len(), 1);
+
+        let err = amqp
+            .publish(
+                &ctx,
+                "direct",
+                "none",
+                &temp().mandatory().with_confirm(Duration::from_secs(1)),
+            )
+            .await;
+        assert_eq!(
+            err,
+            Err(AmqpError::ReturnedMessageError(312, "NO_ROUTE".to_owned()))
+        );
+        assert_eq!(
+            amqp.publish(&ctx, "missing", "", &temp()).await,
+            Err(AmqpError::PublishingError {})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retry_until_dlq() {
+        let amqp = InMemoryAmqp::new(&Config::mock());
+        let topology = AmqpTopology::new().queue(
+            QueueDefinition::name("queue")
+                .msg_type(AmqpMessageType::Temp)
+                .with_dlq()
+                .with_retry(1000, 2),
+        );
+        amqp.install_topology(&topology).await.unwrap();
+        let def = topology.get_consumers_def("queue").unwrap();
+        let handler = Arc::new(Failing {
+            err: || HandlerError::Retry("unavailable".to_owned()),
+        });
+
+        amqp.publish(&Context::new(), "", "queue", &temp())
+            .await
+            .unwrap();
+
+        for attempt in 0..2 {
+            let delivery = amqp.get("queue").unwrap();
+            let metadata = Metadata::from_properties(&delivery.properties);
+            assert_eq!(metadata.attempts("queue"), attempt);
+
+            amqp.consume(&def, handler.clone(), &delivery)
+                .await
+                .unwrap();
+            assert_eq!(amqp.unacked("queue"), 0);
+            assert_eq!(amqp.messages("queue-retry").len(), 1);
+
+            amqp.advance(Duration::from_millis(999));
+            assert!(amqp.messages("queue").is_empty());
+            amqp.advance(Duration::from_millis(1));
+            assert!(amqp.messages("queue-retry").is_empty());
+        }
+
+        let delivery = amqp.get("queue").unwrap();
+        amqp.consume(&def, handler, &delivery).await.unwrap();
+
+        let dead = amqp.messages("queue-dlq");
+        assert_eq!(dead.len(), 1);
+        let headers = dead[0].properties.headers().clone().unwrap();
+        assert_eq!(
+            headers.inner().get(FAILURE_REASON_HEADER),
+            Some(&AMQPValue::LongString(LongString::from("unavailable")))
+        );
+        let deaths = Metadata::from_properties(&dead[0].properties).deaths;
+        assert_eq!(deaths.len(), 1);
+        assert_eq!(deaths[0].queue, "queue-retry");
+        assert_eq!(deaths[0].count, 2);
+    }
+
+    #[tokio::test]
+    async fn test_retry_after() {
+        let amqp = InMemoryAmqp::new(&Config::mock());
+        let topology = AmqpTopology::new()
+            .queue(
+                QueueDefinition::name("queue")
+                    .msg_type(AmqpMessageType::Temp)
+                    .with_backoff(&[1000, 10000], 3),
+            )
+            .delayed(DelayedDefinition::new("", "queue").delay(Duration::from_secs(5)));
+        amqp.install_topology(&topology).await.unwrap();
+        let def = topology.get_consumers_def("queue").unwrap();
+
+        amqp.publish(&Context::new(), "", "queue", &temp())
+            .await
+            .unwrap();
+        let delivery = amqp.get("queue").unwrap();
+        let handler = Arc::new(Failing {
+            err: || HandlerError::RetryAfter(Duration::from_secs(5), "busy".to_owned()),
+        });
+        amqp.consume(&def, handler, &delivery).await.unwrap();
+        assert_eq!(amqp.messages(".queue.delay-5000").len(), 1);
+
+        amqp.advance(Duration::from_millis(4999));
+        assert!(amqp.messages("queue").is_empty());
+        amqp.advance(Duration::from_millis(1));
+        let delivery = amqp.get("queue").unwrap();
+        assert_eq!(
+            Metadata::from_properties(&delivery.properties).attempts("queue"),
+            1
+        );
+
+        // no holding queue for the delay, cut to the last tier
+        let handler = Arc::new(Failing {
+            err: || HandlerError::RetryAfter(Duration::from_secs(120), "busy".to_owned()),
+        });
+        amqp.consume(&def, handler, &delivery).await.unwrap();
+        assert_eq!(amqp.messages("queue-retry-10000").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reject_with_retry_without_dlq() {
+        let amqp = InMemoryAmqp::new(&Config::mock());
+        let topology = AmqpTopology::new().queue(
+            QueueDefinition::name("queue")
+                .msg_type(AmqpMessageType::Temp)
+                .with_retry(1000, 2),
+        );
+        amqp.install_topology(&topology).await.unwrap();
+        let def = topology.get_consumers_def("queue").unwrap();
+        let handler = Arc::new(Failing {
+            err: || HandlerError::Reject("invalid".to_owned()),
+        });
+
+        amqp.publish(&Context::new(), "", "queue", &temp())
+            .await
+            .unwrap();
+        let delivery = amqp.get("queue").unwrap();
+        amqp.consume(&def, handler, &delivery).await.unwrap();
+
+        // the retry queues dead-letter to the DLQ declared along with them
+        let dead = amqp.messages("queue-dlq");
+        assert_eq!(dead.len(), 1);
+        let headers = dead[0].properties.headers().clone().unwrap();
+        assert_eq!(
+            headers.inner().get(FAILURE_REASON_HEADER),
+            Some(&AMQPValue::LongString(LongString::from("invalid")))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_type_with_retry() {
+        let amqp = InMemoryAmqp::new(&Config::mock());
+        let topology = AmqpTopology::new().queue(
+            QueueDefinition::name("queue")
+                .msg_type(AmqpMessageType::Temp)
+                .with_retry(1000, 2),
+        );
+        amqp.install_topology(&topology).await.unwrap();
+        let router = TypeRouter::new().route_version(
+            "Temp",
+            2,
+            Arc::new(Failing {
+                err: || HandlerError::Retry("unavailable".to_owned()),
+            }),
+        );
+        let def = topology
+            .get_consumers_def("queue")
+            .unwrap()
+            .accept_only(router.types());
+        let router = router.boxed();
+
+        // neither the type nor the version have a handler
+        let ctx = Context::new();
+        amqp.publish(&ctx, "", "queue", &PublishData::raw("GPS", b"{}".to_vec()))
+            .await
+            .unwrap();
+        amqp.publish(&ctx, "", "queue", &temp()).await.unwrap();
+        for _ in 0..2 {
+            let delivery = amqp.get("queue").unwrap();
+            amqp.consume(&def, router.clone(), &delivery).await.unwrap();
+        }
+
+        assert!(amqp.messages("queue").is_empty());
+        assert!(amqp.messages("queue-retry").is_empty());
+        let reasons = amqp
+            .messages("queue-dlq")
+            .iter()
+            .map(|dead| {
+                dead.properties.headers().clone().unwrap().inner()[FAILURE_REASON_HEADER].clone()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reasons,
+            vec![
+                AMQPValue::LongString(LongString::from("unknown message type `GPS`")),
+                AMQPValue::LongString(LongString::from("unknown message type `Temp`")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delayed() {
+        let amqp = InMemoryAmqp::new(&Config::mock());
+        let topology = AmqpTopology::new()
+            .queue(QueueDefinition::name("queue"))
+            .delayed(DelayedDefinition::new("", "queue").delay(Duration::from_secs(5)));
+        amqp.install_topology(&topology).await.unwrap();
+
+        let data = temp().delay(Duration::from_secs(5));
+        amqp.publish(&Context::new(), "", "queue", &data)
+            .await
+            .unwrap();
+        assert_eq!(amqp.messages(".queue.delay-5000").len(), 1);
+
+        amqp.advance(Duration::from_millis(4999));
+        assert!(amqp.messages("queue").is_empty());
+        amqp.advance(Duration::from_millis(1));
+        assert_eq!(amqp.messages("queue").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_expire_from_head() {
+        let amqp = InMemoryAmqp::new(&Config::mock());
+        let topology = AmqpTopology::new().queue(QueueDefinition::name("queue").with_dlq());
+        amqp.install_topology(&topology).await.unwrap();
+
+        {
+            let mut broker = amqp.broker.lock().unwrap();
+            for expiration in ["10000", "1000"] {
+                let properties =
+                    AMQPProperties::default().with_expiration(ShortString::from(expiration));
+                broker
+                    .republish("queue", properties, b"{}".to_vec())
+                    .unwrap();
+            }
+        }
+
+        // the shorter expiration waits behind the head
+        amqp.advance(Duration::from_millis(1000));
+        assert_eq!(amqp.messages("queue").len(), 2);
+        amqp.advance(Duration::from_millis(9000));
+        assert!(amqp.messages("queue").is_empty());
+        assert_eq!(amqp.messages("queue-dlq").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_nack() {
+        let amqp = InMemoryAmqp::new(&Config::mock());
+        let topology = AmqpTopology::new().queue(
+            QueueDefinition::name("queue")
+                .msg_type(AmqpMessageType::GPS)
+                .with_dlq(),
+        );
+        amqp.install_topology(&topology).await.unwrap();
+        let handler = Arc::new(Failing {
+            err: || HandlerError::Retry("unavailable".to_owned()),
+        });
+        amqp.publish(&Context::new(), "", "queue", &temp())
+            .await
+            .unwrap();
+
+        // unknown types go back to the queue
+        let mut def = topology.get_consumers_def("queue").unwrap();
+        def.unknown_type = UnknownTypePolicy::Requeue;
+        let delivery = amqp.get("queue").unwrap();
+        assert!(!delivery.redelivered);
+        amqp.consume(&def, handler.clone(), &delivery)
+            .await
+            .unwrap();
+        assert!(amqp.messages("queue")[0].redelivered);
+
+        // retries without retry queues go to the DLQ along with the reason
+        def.msg_types = vec!["Temp".to_owned()];
+        let delivery = amqp.get("queue").unwrap();
+        amqp.consume(&def, handler.clone(), &delivery)
+            .await
+            .unwrap();
+        let headers = amqp.messages("queue-dlq")[0]
+            .properties
+            .headers()
+            .clone()
+            .unwrap();
+        assert_eq!(
+            headers.inner().get(FAILURE_REASON_HEADER),
+            Some(&AMQPValue::LongString(LongString::from("unavailable")))
+        );
+
+        // consumers unaware of the DLQ leave it to the broker dead-lettering
+        def.with_dlq = false;
+        amqp.publish(&Context::new(), "", "queue", &temp())
+            .await
+            .unwrap();
+        let delivery = amqp.get("queue").unwrap();
+        amqp.consume(&def, handler.clone(), &delivery)
+            .await
+            .unwrap();
+        let deaths = Metadata::from_properties(&amqp.messages("queue-dlq")[1].properties).deaths;
+        assert_eq!(deaths[0].reason, "rejected");
+        assert_eq!(deaths[0].queue, "queue");
+
+        assert_eq!(
+            amqp.consume(&def, handler, &delivery).await,
+            Err(AmqpError::NackMessageError {})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unroutable_dlq() {
+        let amqp = InMemoryAmqp::new(&Config::mock());
+        let topology = AmqpTopology::new()
+            .queue(QueueDefinition::name("queue").msg_type(AmqpMessageType::Temp));
+        amqp.install_topology(&topology).await.unwrap();
+        let handler = Arc::new(Failing {
+            err: || HandlerError::Reject("invalid".to_owned()),
+        });
+        amqp.publish(&Context::new(), "", "queue", &temp())
+            .await
+            .unwrap();
+
+        // the copy has nowhere to go, the original is kept instead of acked
+        let mut def = topology.get_consumers_def("queue").unwrap();
+        def.with_dlq = true;
+        let delivery = amqp.get("queue").unwrap();
+        assert_eq!(
+            amqp.consume(&def, handler, &delivery).await,
+            Err(AmqpError::PublishingToDQLError {})
+        );
+        assert_eq!(amqp.unacked("queue"), 0);
+        assert!(amqp.messages("queue")[0].redelivered);
+    }
+
+    #[tokio::test]
+    async fn test_consumer() {
+        let amqp = InMemoryAmqp::new(&Config::mock());
+        let topology = AmqpTopology::new().queue(QueueDefinition::name("queue").prefetch(1));
+        amqp.install_topology(&topology).await.unwrap();
+        let def = topology.get_consumers_def("queue").unwrap();
+
+        let ctx = Context::new();
+        amqp.publish(&ctx, "", "queue", &temp()).await.unwrap();
+        amqp.publish(&ctx, "", "queue", &temp()).await.unwrap();
+
+        let mut deliveries = amqp.consumer(&def).await.unwrap();
+        assert_eq!(
+            amqp.consumer(&def).await.err(),
+            Some(AmqpError::DuplicateConsumerError("queue".to_owned()))
+        );
+        let first = deliveries.next().await.unwrap().unwrap();
+        assert_eq!(amqp.unacked("queue"), 1);
+        assert_eq!(amqp.messages("queue").len(), 1);
+
+        // the redelivery after cancel is what another consumer gets
+        amqp.cancel_consumer(&def).await.unwrap();
+        assert_eq!(amqp.unacked("queue"), 0);
+        assert_eq!(amqp.messages("queue").len(), 2);
+        let mut deliveries = amqp.consumer(&def).await.unwrap();
+        deliveries.next().await.unwrap().unwrap();
+
+        // the redelivery after close is what a restarted consumer gets
+        amqp.close().await.unwrap();
+        assert!(deliveries.next().await.is_none());
+        assert!(amqp.messages("queue")[0].redelivered);
+        assert_eq!(amqp.messages("queue")[0].delivery_tag, 0);
+        assert_eq!(first.routing_key.as_str(), "queue");
+    }
+
+    #[test]
+    fn test_topic_matches() {
+        let matches = |pattern: &str, key: &str| {
+            topic_matches(
+                &pattern.split('.').collect::<Vec<_>>(),
+                &key.split('.').collect::<Vec<_>>(),
+            )
+        };
+
+        assert!(matches("iot.*.temp", "iot.a.temp"));
+        assert!(!matches("iot.*.temp", "iot.a.b.temp"));
+        assert!(matches("iot.#", "iot"));
+        assert!(matches("iot.#.temp", "iot.a.b.temp"));
+        assert!(matches("#", "anything.at.all"));
+        assert!(!matches("iot.*", "iot"));
+    }
+}
@@ -0,0 +1,463 @@
+use super::{
+    client::IAmqp,
+    topology::{AmqpTopology, ExchangeDefinition, ExchangeKind, QueueDeclaration},
+};
+use crate::errors::AmqpError;
+use lapin::{
+    options::{ExchangeDeclareOptions, QueueDeclareOptions, QueueDeleteOptions},
+    protocol::{AMQPErrorKind, AMQPSoftError},
+    types::FieldTable,
+    Channel, Error,
+};
+use log::{debug, error, info};
+use std::{fmt::Display, sync::Arc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceKind {
+    Exchange,
+    Queue,
+    Binding,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeKind {
+    Unchanged,
+    Create,
+    /// Declared with different flags or arguments, carries the broker reply.
+    Conflict(String),
+    /// Not compared with the broker, bindings can't be listed over AMQP.
+    /// Binding is idempotent so `migrate` always declares them.
+    Unchecked,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopologyChange {
+    pub resource: ResourceKind,
+    pub name: String,
+    pub change: ChangeKind,
+    /// Messages in the queue when it already exists.
+    pub messages: u32,
+    pub consumers: u32,
+}
+
+impl TopologyChange {
+    fn new(resource: ResourceKind, name: &str, change: ChangeKind) -> Self {
+        TopologyChange {
+            resource,
+            name: name.to_owned(),
+            change,
+            messages: 0,
+            consumers: 0,
+        }
+    }
+
+    /// Only empty queues nobody consumes from can be recreated without
+    /// losing messages, exchanges are never recreated since that would
+    /// drop bindings declared outside the topology.
+    pub fn is_safe(&self) -> bool {
+        match self.change {
+            ChangeKind::Conflict(_) => {
+                self.resource == ResourceKind::Queue && self.messages == 0 && self.consumers == 0
+            }
+            _ => true,
+        }
+    }
+}
+
+impl Display for TopologyChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let resource = match self.resource {
+            ResourceKind::Exchange => "exchange",
+            ResourceKind::Queue => "queue",
+            ResourceKind::Binding => "binding",
+        };
+
+        match &self.change {
+            ChangeKind::Unchanged => write!(f, "  {} {}", resource, self.name),
+            ChangeKind::Create => write!(f, "+ {} {}", resource, self.name),
+            ChangeKind::Unchecked => write!(
+                f,
+                "? {} {} (not checked, declared on migrate)",
+                resource, self.name
+            ),
+            ChangeKind::Conflict(reason) => write!(
+                f,
+                "~ {} {} ({}, messages: {}, consumers: {})",
+                resource, self.name, reason, self.messages, self.consumers
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TopologyPlan {
+    pub changes: Vec<TopologyChange>,
+}
+
+impl TopologyPlan {
+    pub fn has_changes(&self) -> bool {
+        self.changes
+            .iter()
+            .any(|c| matches!(c.change, ChangeKind::Create | ChangeKind::Conflict(_)))
+    }
+
+    pub fn conflicts(&self) -> Vec<&TopologyChange> {
+        self.changes
+            .iter()
+            .filter(|c| matches!(c.change, ChangeKind::Conflict(_)))
+            .collect()
+    }
+
+    pub fn is_safe(&self) -> bool {
+        self.changes.iter().all(|c| c.is_safe())
+    }
+}
+
+impl Display for TopologyPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for change in self.changes.iter() {
+            writeln!(f, "{}", change)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares a desired topology against the live broker.
+///
+/// Every resource is first declared passively to learn whether it exists and
+/// then declared with the desired flags and arguments, which the broker only
+/// accepts when they match. Failed declarations close the channel, so each
+/// check runs on its own short-lived channel.
+pub struct TopologyPlanner {
+    amqp: Arc<dyn IAmqp + Send + Sync>,
+}
+
+impl TopologyPlanner {
+    pub fn new(amqp: Arc<dyn IAmqp + Send + Sync>) -> Self {
+        TopologyPlanner { amqp }
+    }
+
+    pub async fn plan(&self, topology: &AmqpTopology) -> Result<TopologyPlan, AmqpError> {
+        topology.validate()?;
+
+        let mut plan = TopologyPlan::default();
+
+        for exch in topology.exchanges.iter() {
+            plan.changes.push(self.plan_exchange(exch).await?);
+        }
+
+        for queue in topology.queues.iter() {
+            for declaration in queue.declarations() {
+                plan.changes.push(self.plan_queue(&declaration).await?);
+            }
+        }
+
+        for delayed in topology.delayed.iter() {
+            for declaration in delayed.declarations() {
+                plan.changes.push(self.plan_queue(&declaration).await?);
+            }
+        }
+
+        for exch in topology.exchanges.iter() {
+            for bind in exch.bindings.iter() {
+                plan.changes.push(TopologyChange::new(
+                    ResourceKind::Binding,
+                    &format!(
+                        "{} -> {} ({})",
+                        bind.source, bind.destination, bind.routing_key
+                    ),
+                    ChangeKind::Unchecked,
+                ));
+            }
+        }
+
+        for queue in topology.queues.iter() {
+            for bind in queue.bindings.iter() {
+                plan.changes.push(TopologyChange::new(
+                    ResourceKind::Binding,
+                    &format!("{} -> {} ({})", bind.exchange, bind.queue, bind.routing_key),
+                    ChangeKind::Unchecked,
+                ));
+            }
+        }
+
+        Ok(plan)
+    }
+
+    /// Applies the plan when every change is safe: missing resources are
+    /// created, conflicting empty and idle queues are recreated and bindings
+    /// are declared. Nothing is touched when an unsafe change is found.
+    pub async fn migrate(&self, topology: &AmqpTopology) -> Result<TopologyPlan, AmqpError> {
+        let plan = self.plan(topology).await?;
+
+        if !plan.is_safe() {
+            let unsafe_changes = plan
+                .changes
+                .iter()
+                .filter(|c| !c.is_safe())
+                .map(|c| c.name.clone())
+                .collect::<Vec<_>>()
+                .join(", ");
+            error!("unsafe topology changes: {}", unsafe_changes);
+            return Err(AmqpError::UnsafeTopologyChangeError(unsafe_changes));
+        }
+
+        for change in plan.conflicts() {
+            info!("recreating queue: {}", change.name);
+            self.channel()
+                .await?
+                .queue_delete(
+                    &change.name,
+                    QueueDeleteOptions {
+                        if_unused: true,
+                        if_empty: true,
+                        nowait: false,
+                    },
+                )
+                .await
+                .map_err(|_| AmqpError::DeleteQueueError(change.name.clone()))?;
+        }
+
+        self.amqp.install_topology(topology).await?;
+
+        Ok(plan)
+    }
+
+    async fn plan_exchange(&self, exch: &ExchangeDefinition) -> Result<TopologyChange, AmqpError> {
+        debug!("planning exchange: {}", exch.name);
+
+        let kind = match exch.delayed {
+            true => lapin::ExchangeKind::Custom("x-delayed-message".to_owned()),
+            _ => ExchangeKind::map(exch.kind.clone()),
+        };
+
+        let passive = self
+            .channel()
+            .await?
+            .exchange_declare(
+                exch.name,
+                kind.clone(),
+                ExchangeDeclareOptions {
+                    passive: true,
+                    ..ExchangeDeclareOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .await;
+
+        if let Err(err) = passive {
+            return missing_or_err(ResourceKind::Exchange, exch.name, err);
+        }
+
+        let declared = self
+            .channel()
+            .await?
+            .exchange_declare(
+                exch.name,
+                kind,
+                ExchangeDeclareOptions {
+                    passive: false,
+                    durable: exch.durable,
+                    auto_delete: exch.auto_delete,
+                    internal: exch.internal,
+                    nowait: false,
+                },
+                FieldTable::from(exch.arguments()),
+            )
+            .await;
+
+        match declared {
+            Ok(_) => Ok(TopologyChange::new(
+                ResourceKind::Exchange,
+                exch.name,
+                ChangeKind::Unchanged,
+            )),
+            Err(err) => conflict_or_err(ResourceKind::Exchange, exch.name, err),
+        }
+    }
+
+    async fn plan_queue(
+        &self,
+        declaration: &QueueDeclaration,
+    ) -> Result<TopologyChange, AmqpError> {
+        debug!("planning queue: {}", declaration.name);
+
+        let existing = match self
+            .channel()
+            .await?
+            .queue_declare(
+                &declaration.name,
+                QueueDeclareOptions {
+                    passive: true,
+                    ..QueueDeclareOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .await
+        {
+            Ok(queue) => queue,
+            Err(err) => return missing_or_err(ResourceKind::Queue, &declaration.name, err),
+        };
+
+        let declared = self
+            .channel()
+            .await?
+            .queue_declare(
+                &declaration.name,
+                QueueDeclareOptions {
+                    passive: false,
+                    durable: declaration.durable,
+                    exclusive: declaration.exclusive,
+                    auto_delete: declaration.auto_delete,
+                    nowait: false,
+                },
+                FieldTable::from(declaration.arguments.clone()),
+            )
+            .await;
+
+        let mut change = match declared {
+            Ok(_) => TopologyChange::new(
+                ResourceKind::Queue,
+                &declaration.name,
+                ChangeKind::Unchanged,
+            ),
+            Err(err) => conflict_or_err(ResourceKind::Queue, &declaration.name, err)?,
+        };
+        change.messages = existing.message_count();
+        change.consumers = existing.consumer_count();
+
+        Ok(change)
+    }
+
+    async fn channel(&self) -> Result<Channel, AmqpError> {
+        self.amqp
+            .connection()?
+            .create_channel()
+            .await
+            .map_err(|_| AmqpError::ChannelError {})
+    }
+}
+
+fn missing_or_err(
+    resource: ResourceKind,
+    name: &str,
+    err: Error,
+) -> Result<TopologyChange, AmqpError> {
+    match soft_error(&err) {
+        Some(AMQPSoftError::NOTFOUND) => {
+            Ok(TopologyChange::new(resource, name, ChangeKind::Create))
+        }
+        _ => {
+            error!("failure to inspect: {} - {}", name, err);
+            Err(AmqpError::InspectTopologyError(name.to_owned()))
+        }
+    }
+}
+
+fn conflict_or_err(
+    resource: ResourceKind,
+    name: &str,
+    err: Error,
+) -> Result<TopologyChange, AmqpError> {
+    match (soft_error(&err), &err) {
+        (Some(AMQPSoftError::PRECONDITIONFAILED), Error::ProtocolError(amqp)) => {
+            Ok(TopologyChange::new(
+                resource,
+                name,
+                ChangeKind::Conflict(amqp.get_message().to_string()),
+            ))
+        }
+        _ => {
+            error!("failure to inspect: {} - {}", name, err);
+            Err(AmqpError::InspectTopologyError(name.to_owned()))
+        }
+    }
+}
+
+fn soft_error(err: &Error) -> Option<AMQPSoftError> {
+    match err {
+        Error::ProtocolError(amqp) => match amqp.kind() {
+            AMQPErrorKind::Soft(soft) => Some(soft.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lapin::protocol::AMQPError;
+
+    fn conflict(resource: ResourceKind, messages: u32, consumers: u32) -> TopologyChange {
+        TopologyChange {
+            resource,
+            name: "name".to_owned(),
+            change: ChangeKind::Conflict("PRECONDITION_FAILED".to_owned()),
+            messages,
+            consumers,
+        }
+    }
+
+    #[test]
+    fn test_change_is_safe() {
+        assert!(TopologyChange::new(ResourceKind::Queue, "queue", ChangeKind::Create).is_safe());
+        assert!(conflict(ResourceKind::Queue, 0, 0).is_safe());
+        assert!(!conflict(ResourceKind::Queue, 1, 0).is_safe());
+        assert!(!conflict(ResourceKind::Queue, 0, 1).is_safe());
+        assert!(!conflict(ResourceKind::Exchange, 0, 0).is_safe());
+    }
+
+    #[test]
+    fn test_plan() {
+        let mut plan = TopologyPlan::default();
+        plan.changes.push(TopologyChange::new(
+            ResourceKind::Exchange,
+            "exchange",
+            ChangeKind::Unchanged,
+        ));
+        assert!(!plan.has_changes());
+
+        plan.changes.push(conflict(ResourceKind::Queue, 10, 0));
+        plan.changes.push(TopologyChange::new(
+            ResourceKind::Queue,
+            "queue",
+            ChangeKind::Create,
+        ));
+        plan.changes.push(TopologyChange::new(
+            ResourceKind::Binding,
+            "exchange -> queue (key)",
+            ChangeKind::Unchecked,
+        ));
+        assert!(plan.has_changes());
+        assert!(!plan.is_safe());
+        assert_eq!(plan.conflicts().len(), 1);
+        assert_eq!(
+            plan.to_string(),
+            "  exchange exchange\n~ queue name (PRECONDITION_FAILED, messages: 10, consumers: 0)\n+ queue queue\n? binding exchange -> queue (key) (not checked, declared on migrate)\n"
+        );
+    }
+
+    #[test]
+    fn test_classify_errors() {
+        let not_found = Error::ProtocolError(AMQPError::from_id(404, "NOT_FOUND".into()).unwrap());
+        assert_eq!(
+            missing_or_err(ResourceKind::Queue, "queue", not_found)
+                .unwrap()
+                .change,
+            ChangeKind::Create
+        );
+
+        let failed = Error::ProtocolError(
+            AMQPError::from_id(406, "PRECONDITION_FAILED - inequivalent arg".into()).unwrap(),
+        );
+        assert_eq!(
+            conflict_or_err(ResourceKind::Queue, "queue", failed)
+                .unwrap()
+                .change,
+            ChangeKind::Conflict("PRECONDITION_FAILED - inequivalent arg".to_owned())
+        );
+
+        assert!(missing_or_err(ResourceKind::Queue, "queue", Error::ChannelsLimitReached).is_err());
+    }
+}
@@ -1,3 +1,17 @@
 pub mod client;
+pub mod compression;
+pub mod dispatcher;
+pub mod dlq;
+pub mod envelope;
+pub mod export;
+pub mod idempotency;
+pub mod memory;
+pub mod migration;
+pub mod outbox;
+pub mod pool;
+pub mod router;
+pub mod rpc;
+pub mod spec;
+pub mod stream;
 pub mod topology;
 pub mod types;
@@ -0,0 +1,507 @@
+use super::{
+    client::IAmqp, compression::Compression, envelope::TRACEPARENT_HEADER, types::PublishData,
+};
+use crate::{
+    errors::AmqpError,
+    otel::propagation::{self, MapExtractor, MapInjector},
+};
+use deadpool_postgres::Pool;
+use log::{debug, error, warn};
+use opentelemetry::{
+    global::{self, BoxedTracer},
+    trace::SpanKind,
+    Context,
+};
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+use tokio_postgres::{GenericClient, Row};
+use uuid::Uuid;
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS outbox (
+    id BIGSERIAL PRIMARY KEY,
+    message_id TEXT NOT NULL UNIQUE,
+    exchange TEXT NOT NULL,
+    routing_key TEXT NOT NULL,
+    msg_type TEXT NOT NULL,
+    schema_version INT NOT NULL,
+    device_id TEXT,
+    headers TEXT NOT NULL,
+    traceparent TEXT,
+    priority SMALLINT,
+    content_type TEXT NOT NULL,
+    compression TEXT,
+    correlation_id TEXT,
+    delay_ms BIGINT,
+    payload BYTEA NOT NULL,
+    attempts INT NOT NULL DEFAULT 0,
+    last_error TEXT,
+    claimed_until TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    sent_at TIMESTAMPTZ,
+    dead_at TIMESTAMPTZ
+)";
+
+const INSERT_MESSAGE: &str = "INSERT INTO outbox
+    (message_id, exchange, routing_key, msg_type, schema_version, device_id, headers, traceparent,
+    priority, content_type, compression, correlation_id, delay_ms, payload)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)";
+
+// claims are committed right away so no lock is held while publishing, the
+// lease hands them to another relay if this one dies before settling them
+const CLAIM_PENDING: &str = "UPDATE outbox SET claimed_until = now() + make_interval(secs => $2)
+    WHERE id IN (
+        SELECT id FROM outbox
+        WHERE sent_at IS NULL AND dead_at IS NULL
+        AND (claimed_until IS NULL OR claimed_until < now())
+        ORDER BY id LIMIT $1 FOR UPDATE SKIP LOCKED
+    )
+    RETURNING id, message_id, exchange, routing_key, msg_type, schema_version, device_id,
+    headers, traceparent, priority, content_type, compression, correlation_id, delay_ms, payload,
+    attempts";
+
+const MARK_SENT: &str = "UPDATE outbox SET sent_at = now(), claimed_until = NULL WHERE id = $1";
+
+const MARK_FAILED: &str = "UPDATE outbox SET attempts = attempts + 1, last_error = $2,
+    claimed_until = NULL, dead_at = CASE WHEN $3 THEN now() END
+    WHERE id = $1";
+
+const RELEASE: &str = "UPDATE outbox SET claimed_until = NULL WHERE id = ANY($1)";
+
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const DELETE_SENT: &str = "DELETE FROM outbox WHERE sent_at < now() - make_interval(secs => $1)";
+
+/// Creates the `outbox` table when missing.
+pub async fn migrate(pool: &Pool) -> Result<(), AmqpError> {
+    let client = pool.get().await.map_err(|err| {
+        error!("failure to get a db connection - {:?}", err);
+        AmqpError::OutboxError("connection".to_owned())
+    })?;
+
+    client.batch_execute(CREATE_TABLE).await.map_err(|err| {
+        error!("failure to create outbox table - {:?}", err);
+        AmqpError::OutboxError("outbox".to_owned())
+    })
+}
+
+/// Writes the message to the outbox with `client`, usually the transaction of
+/// the repository changes, so it is only published if they are committed.
+pub async fn enqueue<C>(
+    client: &C,
+    ctx: &Context,
+    exchange: &str,
+    key: &str,
+    data: &PublishData,
+) -> Result<(), AmqpError>
+where
+    C: GenericClient + Sync,
+{
+    let message_id = data
+        .message_id
+        .clone()
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let mut headers = data.headers.clone();
+    propagation::inject(ctx, &mut MapInjector(&mut headers));
+    let traceparent = headers.get(TRACEPARENT_HEADER).cloned();
+    let headers = serde_json::to_string(&headers).map_err(|_| AmqpError::ParsePayloadError)?;
+    let delay = data.delay.map(|delay| delay.as_millis() as i64);
+
+    client
+        .execute(
+            INSERT_MESSAGE,
+            &[
+                &message_id,
+                &exchange,
+                &key,
+                &data.msg_type,
+                &(data.schema_version as i32),
+                &data.device_id,
+                &headers,
+                &traceparent,
+                &data.priority.map(i16::from),
+                &data.content_type,
+                &data.encoding(),
+                &data.correlation_id,
+                &delay,
+                &data.payload.as_ref(),
+            ],
+        )
+        .await
+        .map_err(|err| {
+            error!("failure to write outbox message - {:?}", err);
+            AmqpError::OutboxError(message_id.clone())
+        })?;
+
+    debug!("message: {} written to the outbox", message_id);
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct OutboxConfig {
+    pub batch_size: i64,
+    /// Wait between polls once the outbox is empty
+    pub poll_interval: Duration,
+    pub confirm_timeout: Duration,
+    /// Claimed messages not settled within it are relayed again, keep it above
+    /// `batch_size` confirm timeouts
+    pub lease: Duration,
+    /// Failed publishes before the message is parked with `dead_at`
+    pub max_attempts: i32,
+    /// Sent messages are deleted once older than it
+    pub retention: Duration,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        OutboxConfig {
+            batch_size: 100,
+            poll_interval: Duration::from_secs(1),
+            confirm_timeout: Duration::from_secs(5),
+            lease: Duration::from_secs(10 * 60),
+            max_attempts: 10,
+            retention: Duration::from_secs(7 * 24 * 60 * 60),
+        }
+    }
+}
+
+struct OutboxRow {
+    exchange: String,
+    routing_key: String,
+    traceparent: Option<String>,
+    data: PublishData,
+}
+
+impl OutboxRow {
+    fn from_row(row: &Row) -> Result<OutboxRow, AmqpError> {
+        let message_id: String = row.get("message_id");
+        let headers: String = row.get("headers");
+        let headers = serde_json::from_str::<BTreeMap<String, String>>(&headers)
+            .map_err(|_| AmqpError::OutboxError(message_id.clone()))?;
+        let msg_type: String = row.get("msg_type");
+        let schema_version: i32 = row.get("schema_version");
+        let device_id: Option<String> = row.get("device_id");
+        let priority: Option<i16> = row.get("priority");
+        let content_type: String = row.get("content_type");
+        let compression: Option<String> = row.get("compression");
+        let correlation_id: Option<String> = row.get("correlation_id");
+        let delay: Option<i64> = row.get("delay_ms");
+
+        let mut data = PublishData::raw(&msg_type, row.get("payload"))
+            .message_id(&message_id)
+            .schema_version(schema_version as u16)
+            .content_type(&content_type);
+        data.headers = headers;
+        data.device_id = device_id;
+        data.priority = priority.map(|priority| priority as u8);
+        data.correlation_id = correlation_id;
+        data.delay = delay.map(|ms| Duration::from_millis(ms as u64));
+        if let Some(encoding) = compression {
+            let compression = Compression::from_encoding(&encoding)
+                .ok_or_else(|| AmqpError::CompressionError(encoding.clone()))?;
+            data = data.compress(compression, 0);
+        }
+
+        Ok(OutboxRow {
+            exchange: row.get("exchange"),
+            routing_key: row.get("routing_key"),
+            traceparent: row.get("traceparent"),
+            data,
+        })
+    }
+}
+
+/// A claimed row, `row` holds the error of an unreadable one.
+struct Claimed {
+    id: i64,
+    attempts: i32,
+    row: Result<OutboxRow, AmqpError>,
+}
+
+impl Claimed {
+    fn from_row(row: &Row) -> Claimed {
+        Claimed {
+            id: row.get("id"),
+            attempts: row.get("attempts"),
+            row: OutboxRow::from_row(row),
+        }
+    }
+}
+
+/// What becomes of a claimed row, decided apart from the SQL applying it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Settled {
+    /// Confirmed by the broker
+    Sent,
+    /// Failed below `max_attempts`, the later rows wait for it
+    Failed(String),
+    /// Unreadable or failed `max_attempts` times, the later rows go on
+    Parked(String),
+}
+
+/// Claimed rows in the order they are relayed, the order they were written in.
+struct RelayBatch {
+    rows: std::vec::IntoIter<Claimed>,
+    max_attempts: i32,
+}
+
+impl RelayBatch {
+    fn new(mut rows: Vec<Claimed>, max_attempts: i32) -> RelayBatch {
+        rows.sort_by_key(|claimed| claimed.id);
+        RelayBatch {
+            rows: rows.into_iter(),
+            max_attempts,
+        }
+    }
+
+    fn next(&mut self) -> Option<Claimed> {
+        self.rows.next()
+    }
+
+    fn settle(&self, attempts: i32, result: Result<(), AmqpError>) -> Settled {
+        match result {
+            Ok(_) => Settled::Sent,
+            Err(err) if attempts + 1 >= self.max_attempts => Settled::Parked(err.to_string()),
+            Err(err) => Settled::Failed(err.to_string()),
+        }
+    }
+
+    /// Rows left once one failed, released for the next batch.
+    fn release(self) -> Vec<i64> {
+        self.rows.map(|claimed| claimed.id).collect()
+    }
+}
+
+/// Publishes the outbox messages in order with publisher confirms.
+///
+/// Rows are claimed with `SKIP LOCKED`, many relays can run side by side. A
+/// message is marked sent only once confirmed, a crash in between publishes
+/// it again with the same message id for idempotent consumers to skip it.
+/// Messages failing `max_attempts` times, or unreadable ones, are parked with
+/// `dead_at` so they stop holding back the later ones.
+pub struct OutboxRelay {
+    amqp: Arc<dyn IAmqp + Send + Sync>,
+    pool: Arc<Pool>,
+    cfg: OutboxConfig,
+    tracer: BoxedTracer,
+}
+
+impl OutboxRelay {
+    pub fn new(amqp: Arc<dyn IAmqp + Send + Sync>, pool: Arc<Pool>, cfg: OutboxConfig) -> Self {
+        OutboxRelay {
+            amqp,
+            pool,
+            cfg,
+            tracer: global::tracer("outbox"),
+        }
+    }
+
+    /// Publishes a single batch, returning how many messages were sent.
+    pub async fn relay_once(&self) -> Result<usize, AmqpError> {
+        let db_err = |err: tokio_postgres::Error| {
+            error!("outbox db failure - {:?}", err);
+            AmqpError::OutboxError("relay".to_owned())
+        };
+
+        let client = self.pool.get().await.map_err(|err| {
+            error!("failure to get a db connection - {:?}", err);
+            AmqpError::OutboxError("connection".to_owned())
+        })?;
+
+        let rows = client
+            .query(
+                CLAIM_PENDING,
+                &[&self.cfg.batch_size, &self.cfg.lease.as_secs_f64()],
+            )
+            .await
+            .map_err(db_err)?;
+        let claimed = rows.iter().map(Claimed::from_row).collect();
+        let mut batch = RelayBatch::new(claimed, self.cfg.max_attempts);
+
+        let mut sent = 0;
+        while let Some(claimed) = batch.next() {
+            let settled = match claimed.row {
+                Ok(row) => batch.settle(claimed.attempts, self.publish(row).await),
+                Err(err) => {
+                    error!("unreadable outbox message: {} - {}", claimed.id, err);
+                    Settled::Parked(err.to_string())
+                }
+            };
+
+            // settled one by one, a later db failure never leaves a sent row
+            // claimed for another relay to publish again
+            let marked = match &settled {
+                Settled::Sent => client.execute(MARK_SENT, &[&claimed.id]).await,
+                Settled::Failed(err) => {
+                    client
+                        .execute(MARK_FAILED, &[&claimed.id, err, &false])
+                        .await
+                }
+                Settled::Parked(err) => {
+                    client
+                        .execute(MARK_FAILED, &[&claimed.id, err, &true])
+                        .await
+                }
+            };
+            marked.map_err(db_err)?;
+
+            match settled {
+                Settled::Sent => sent += 1,
+                Settled::Parked(err) => {
+                    error!("outbox message: {} parked - {}", claimed.id, err);
+                }
+                Settled::Failed(err) => {
+                    // later rows wait so messages keep their order
+                    warn!("failure to relay outbox message: {} - {}", claimed.id, err);
+                    break;
+                }
+            }
+        }
+
+        let released = batch.release();
+        if !released.is_empty() {
+            client
+                .execute(RELEASE, &[&released])
+                .await
+                .map_err(db_err)?;
+        }
+
+        Ok(sent)
+    }
+
+    async fn publish(&self, mut row: OutboxRow) -> Result<(), AmqpError> {
+        // rows written before the trace context went into the headers
+        if let Some(traceparent) = row.traceparent.take() {
+            row.data
+                .headers
+                .entry(TRACEPARENT_HEADER.to_owned())
+                .or_insert(traceparent);
+        }
+        let span = propagation::remote_span(
+            &self.tracer,
+            &MapExtractor(&row.data.headers),
+            "outbox relay",
+            SpanKind::Producer,
+        );
+
+        let data = row.data.with_confirm(self.cfg.confirm_timeout);
+        let result = self
+            .amqp
+            .publish(span.context(), &row.exchange, &row.routing_key, &data)
+            .await;
+        span.finish(result)
+    }
+
+    pub async fn purge(&self) -> Result<u64, AmqpError> {
+        let client = self.pool.get().await.map_err(|err| {
+            error!("failure to get a db connection - {:?}", err);
+            AmqpError::OutboxError("connection".to_owned())
+        })?;
+
+        client
+            .execute(DELETE_SENT, &[&self.cfg.retention.as_secs_f64()])
+            .await
+            .map_err(|err| {
+                error!("failure to purge the outbox - {:?}", err);
+                AmqpError::OutboxError("purge".to_owned())
+            })
+    }
+
+    pub async fn run_until<F>(self, signal: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let (tx, mut shutdown) = watch::channel(false);
+        tokio::spawn(async move {
+            signal.await;
+            let _ = tx.send(true);
+        });
+
+        let mut last_purge: Option<Instant> = None;
+        loop {
+            let wait = match self.relay_once().await {
+                Ok(sent) if sent as i64 >= self.cfg.batch_size => Duration::ZERO,
+                Ok(_) => {
+                    if !matches!(last_purge, Some(at) if at.elapsed() < PURGE_INTERVAL) {
+                        if let Err(err) = self.purge().await {
+                            error!("{:?}", err);
+                        }
+                        last_purge = Some(Instant::now());
+                    }
+                    self.cfg.poll_interval
+                }
+                Err(err) => {
+                    error!("{:?}", err);
+                    self.cfg.poll_interval
+                }
+            };
+
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = tokio::time::sleep(wait) => {},
+            }
+        }
+
+        debug!("outbox relay stopped");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claimed(id: i64, attempts: i32) -> Claimed {
+        Claimed {
+            id,
+            attempts,
+            row: Ok(OutboxRow {
+                exchange: "exchange".to_owned(),
+                routing_key: "key".to_owned(),
+                traceparent: None,
+                data: PublishData::raw("Temp", b"{}".to_vec()),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_relay_batch_order() {
+        let unreadable = Claimed {
+            id: 2,
+            attempts: 0,
+            row: Err(AmqpError::OutboxError("id".to_owned())),
+        };
+        let mut batch = RelayBatch::new(vec![claimed(3, 0), unreadable, claimed(1, 0)], 3);
+
+        let ids: Vec<i64> = std::iter::from_fn(|| batch.next())
+            .map(|claimed| claimed.id)
+            .collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_relay_batch_settle() {
+        let batch = RelayBatch::new(vec![], 3);
+        let err = || Err(AmqpError::PublishingError {});
+
+        assert_eq!(batch.settle(0, Ok(())), Settled::Sent);
+        assert_eq!(batch.settle(2, Ok(())), Settled::Sent);
+        assert!(matches!(batch.settle(1, err()), Settled::Failed(_)));
+        assert!(matches!(batch.settle(2, err()), Settled::Parked(_)));
+    }
+
+    #[test]
+    fn test_relay_batch_release() {
+        let mut batch = RelayBatch::new(vec![claimed(2, 0), claimed(1, 0), claimed(3, 0)], 3);
+        assert_eq!(batch.next().unwrap().id, 1);
+        assert_eq!(batch.release(), vec![2, 3]);
+
+        let mut batch = RelayBatch::new(vec![claimed(1, 0)], 3);
+        assert!(batch.next().is_some());
+        assert!(batch.next().is_none());
+        assert!(batch.release().is_empty());
+    }
+}
@@ -0,0 +1,71 @@
+use crate::errors::AmqpError;
+use lapin::{options::ConfirmSelectOptions, Channel, Connection};
+use log::{debug, warn};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+};
+
+/// Round-robin pool of publisher channels.
+///
+/// A channel-level error only takes down the channel it happened on, so a
+/// closed channel is replaced the next time its slot is handed out.
+#[derive(Debug)]
+pub struct ChannelPool {
+    channels: Mutex<Vec<Channel>>,
+    next: AtomicUsize,
+    confirm: bool,
+}
+
+impl ChannelPool {
+    pub async fn new(conn: &Connection, size: usize, confirm: bool) -> Result<Self, AmqpError> {
+        debug!("creating channel pool with {} channels...", size);
+
+        let mut channels = vec![];
+        for _ in 0..size.max(1) {
+            channels.push(Self::create(conn, confirm).await?);
+        }
+
+        debug!("channel pool created");
+
+        Ok(ChannelPool {
+            channels: Mutex::new(channels),
+            next: AtomicUsize::new(0),
+            confirm,
+        })
+    }
+
+    pub async fn get(&self, conn: &Connection) -> Result<Channel, AmqpError> {
+        let (idx, channel) = {
+            let channels = self.channels.lock().unwrap();
+            let idx = self.next.fetch_add(1, Ordering::Relaxed) % channels.len();
+            (idx, channels[idx].clone())
+        };
+
+        if channel.status().connected() {
+            return Ok(channel);
+        }
+
+        warn!("pool channel {} is closed, recreating", idx);
+        let channel = Self::create(conn, self.confirm).await?;
+        self.channels.lock().unwrap()[idx] = channel.clone();
+
+        Ok(channel)
+    }
+
+    async fn create(conn: &Connection, confirm: bool) -> Result<Channel, AmqpError> {
+        let channel = conn
+            .create_channel()
+            .await
+            .map_err(|_| AmqpError::ChannelError {})?;
+
+        if confirm {
+            channel
+                .confirm_select(ConfirmSelectOptions { nowait: false })
+                .await
+                .map_err(|_| AmqpError::ChannelError {})?;
+        }
+
+        Ok(channel)
+    }
+}
@@ -0,0 +1,304 @@
+use super::{
+    envelope::{Envelope, DEFAULT_SCHEMA_VERSION},
+    topology::{ConsumerHandler, TypedConsumer, TypedConsumerHandler},
+    types::AmqpMessageType,
+};
+use crate::errors::HandlerError;
+use async_trait::async_trait;
+use lapin::{message::Delivery, types::AMQPValue};
+use log::debug;
+use opentelemetry::Context;
+use serde::de::DeserializeOwned;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+/// Type of the delivery being handled, carried in the handler `Context` by
+/// `IAmqp::consume`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryType(pub String);
+
+impl DeliveryType {
+    /// The `type` header wins over the `type` property, the latter is what
+    /// other AMQP clients usually set.
+    pub fn from_delivery(delivery: &Delivery) -> DeliveryType {
+        DeliveryType(Envelope::from_properties(&delivery.properties).msg_type)
+    }
+}
+
+/// String headers of the delivery being handled, carried in the handler
+/// `Context` by `IAmqp::consume` along with `DeliveryType`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeliveryHeaders(pub BTreeMap<String, String>);
+
+impl DeliveryHeaders {
+    pub fn from_delivery(delivery: &Delivery) -> DeliveryHeaders {
+        let headers = delivery
+            .properties
+            .headers()
+            .iter()
+            .flat_map(|headers| headers.inner().iter())
+            .filter_map(|(key, value)| {
+                let value = match value {
+                    AMQPValue::LongString(value) => value.to_string(),
+                    AMQPValue::ShortString(value) => value.to_string(),
+                    _ => return None,
+                };
+                Some((key.to_string(), value))
+            })
+            .collect();
+
+        DeliveryHeaders(headers)
+    }
+}
+
+/// What happens to messages whose type no handler of the consumer accepts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownTypePolicy {
+    /// Nack with requeue so another consumer of the queue can take it, the
+    /// message comes straight back when no other consumer accepts it
+    Requeue,
+    /// Ack and forget the message
+    Drop,
+    /// Send to the consumer DLQ, or nack without requeue when there is none
+    #[default]
+    Dlq,
+}
+
+/// Dispatches the messages of a single queue to a handler per message type,
+/// or per message type and schema version so v1 and v2 payloads can be
+/// consumed side by side. A versioned route wins over the unversioned one.
+///
+/// ```ignore
+/// let router = TypeRouter::new()
+///     .route(AmqpMessageType::Temp, temp_handler)
+///     .route_typed(AmqpMessageType::GPS, gps_handler);
+/// dispatcher.register_router(def.on_unknown_type(UnknownTypePolicy::Dlq), router)
+/// ```
+#[derive(Default)]
+pub struct TypeRouter {
+    handlers: HashMap<String, Arc<dyn ConsumerHandler + Send + Sync>>,
+}
+
+impl TypeRouter {
+    pub fn new() -> Self {
+        TypeRouter::default()
+    }
+
+    pub fn route(
+        self,
+        msg_type: AmqpMessageType,
+        handler: Arc<dyn ConsumerHandler + Send + Sync>,
+    ) -> Self {
+        self.route_type(&msg_type.to_string(), handler)
+    }
+
+    /// Routes a type outside `AmqpMessageType`, e.g. published by other services.
+    pub fn route_type(
+        mut self,
+        msg_type: &str,
+        handler: Arc<dyn ConsumerHandler + Send + Sync>,
+    ) -> Self {
+        self.handlers.insert(msg_type.to_owned(), handler);
+        self
+    }
+
+    pub fn route_version(
+        mut self,
+        msg_type: &str,
+        version: u16,
+        handler: Arc<dyn ConsumerHandler + Send + Sync>,
+    ) -> Self {
+        self.handlers.insert(versioned(msg_type, version), handler);
+        self
+    }
+
+    pub fn route_typed<T>(
+        self,
+        msg_type: AmqpMessageType,
+        handler: Arc<dyn TypedConsumerHandler<T> + Send + Sync>,
+    ) -> Self
+    where
+        T: DeserializeOwned + Send + Sync + 'static,
+    {
+        self.route(msg_type, TypedConsumer::new(handler))
+    }
+
+    pub fn types(&self) -> Vec<String> {
+        let mut types = self
+            .handlers
+            .keys()
+            .map(|key| match key.split_once('@') {
+                Some((msg_type, _)) => msg_type.to_owned(),
+                _ => key.clone(),
+            })
+            .collect::<Vec<_>>();
+        types.sort();
+        types.dedup();
+        types
+    }
+
+    pub fn boxed(self) -> Arc<dyn ConsumerHandler + Send + Sync> {
+        Arc::new(self)
+    }
+}
+
+impl TypeRouter {
+    fn handler(&self, ctx: &Context) -> Option<&Arc<dyn ConsumerHandler + Send + Sync>> {
+        let msg_type = match ctx.get::<DeliveryType>() {
+            Some(DeliveryType(msg_type)) => msg_type.as_str(),
+            _ => "",
+        };
+
+        let version = ctx
+            .get::<Envelope>()
+            .map(|envelope| envelope.schema_version)
+            .unwrap_or(DEFAULT_SCHEMA_VERSION);
+
+        self.handlers
+            .get(&versioned(msg_type, version))
+            .or_else(|| self.handlers.get(msg_type))
+    }
+}
+
+#[async_trait]
+impl ConsumerHandler for TypeRouter {
+    async fn exec(&self, ctx: &Context, data: &[u8]) -> Result<(), HandlerError> {
+        let msg_type = match ctx.get::<DeliveryType>() {
+            Some(DeliveryType(msg_type)) => msg_type.as_str(),
+            _ => "",
+        };
+
+        match self.handler(ctx) {
+            Some(handler) => {
+                debug!("routing msg type: {}", msg_type);
+                handler.exec(ctx, data).await
+            }
+            _ => Err(HandlerError::Reject(format!(
+                "no handler for message type `{}`",
+                msg_type
+            ))),
+        }
+    }
+
+    fn handles(&self, ctx: &Context) -> bool {
+        self.handler(ctx).is_some()
+    }
+}
+
+fn versioned(msg_type: &str, version: u16) -> String {
+    format!("{}@{}", msg_type, version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amqp::client::delivery;
+    use lapin::{
+        protocol::basic::AMQPProperties,
+        types::{AMQPValue, FieldTable, LongString, ShortString},
+    };
+    use std::sync::Mutex;
+
+    struct Recorder {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    #[async_trait]
+    impl ConsumerHandler for Recorder {
+        async fn exec(&self, _ctx: &Context, _data: &[u8]) -> Result<(), HandlerError> {
+            self.calls.lock().unwrap().push(self.name);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_delivery_type() {
+        let properties = AMQPProperties::default().with_kind(ShortString::from("GPS"));
+        assert_eq!(
+            DeliveryType::from_delivery(&delivery(properties.clone(), b"")),
+            DeliveryType("GPS".to_owned())
+        );
+
+        let mut header = FieldTable::default();
+        header.insert(
+            ShortString::from("type"),
+            AMQPValue::LongString(LongString::from("Temp")),
+        );
+        assert_eq!(
+            DeliveryType::from_delivery(&delivery(properties.with_headers(header), b"")),
+            DeliveryType("Temp".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_type_router() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let router = TypeRouter::new()
+            .route(
+                AmqpMessageType::Temp,
+                Arc::new(Recorder {
+                    name: "temp",
+                    calls: calls.clone(),
+                }),
+            )
+            .route_type(
+                "Shadow",
+                Arc::new(Recorder {
+                    name: "shadow",
+                    calls: calls.clone(),
+                }),
+            );
+        assert_eq!(router.types(), vec!["Shadow", "Temp"]);
+
+        let ctx = Context::new().with_value(DeliveryType("Temp".to_owned()));
+        assert!(router.exec(&ctx, b"{}").await.is_ok());
+        let ctx = Context::new().with_value(DeliveryType("Shadow".to_owned()));
+        assert!(router.exec(&ctx, b"{}").await.is_ok());
+        assert_eq!(*calls.lock().unwrap(), vec!["temp", "shadow"]);
+
+        let ctx = Context::new().with_value(DeliveryType("GPS".to_owned()));
+        assert!(!router.handles(&ctx));
+        assert!(matches!(
+            router.exec(&ctx, b"{}").await,
+            Err(HandlerError::Reject(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_type_router_versions() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let router = TypeRouter::new()
+            .route(
+                AmqpMessageType::Temp,
+                Arc::new(Recorder {
+                    name: "v1",
+                    calls: calls.clone(),
+                }),
+            )
+            .route_version(
+                "Temp",
+                2,
+                Arc::new(Recorder {
+                    name: "v2",
+                    calls: calls.clone(),
+                }),
+            );
+        assert_eq!(router.types(), vec!["Temp"]);
+
+        let ctx = |version: u16| {
+            Context::new()
+                .with_value(DeliveryType("Temp".to_owned()))
+                .with_value(Envelope {
+                    schema_version: version,
+                    ..Envelope::default()
+                })
+        };
+        assert!(router.exec(&ctx(2), b"{}").await.is_ok());
+        assert!(router.exec(&ctx(1), b"{}").await.is_ok());
+        assert!(router.exec(&ctx(3), b"{}").await.is_ok());
+        assert_eq!(*calls.lock().unwrap(), vec!["v2", "v1", "v1"]);
+    }
+}
@@ -0,0 +1,293 @@
+use super::{client::IAmqp, compression, topology::ConsumerHandler, types::PublishData};
+use crate::errors::{AmqpError, HandlerError};
+use async_trait::async_trait;
+use lapin::{message::Delivery, types::AMQPValue};
+use log::{debug, error, warn};
+use opentelemetry::Context;
+use std::sync::Arc;
+
+/// Pseudo queue of the RabbitMQ direct reply-to, replies are consumed without
+/// declaring a queue per caller.
+pub const DIRECT_REPLY_TO: &str = "amq.rabbitmq.reply-to";
+
+/// Set on replies to requests the server failed to handle.
+pub const RPC_ERROR_HEADER: &str = "x-rpc-error";
+
+/// Where the reply of the delivery being handled goes, carried in the handler
+/// `Context` by `IAmqp::consume`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RpcRequest {
+    pub reply_to: String,
+    pub correlation_id: String,
+}
+
+impl RpcRequest {
+    pub fn from_delivery(delivery: &Delivery) -> Option<RpcRequest> {
+        match (
+            delivery.properties.reply_to(),
+            delivery.properties.correlation_id(),
+        ) {
+            (Some(reply_to), Some(correlation_id)) => Some(RpcRequest {
+                reply_to: reply_to.to_string(),
+                correlation_id: correlation_id.to_string(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Handler of requests made with `IAmqp::call`, the returned payload is sent
+/// back to the caller.
+#[async_trait]
+pub trait RpcHandler {
+    async fn exec(&self, ctx: &Context, data: &[u8]) -> Result<Vec<u8>, HandlerError>;
+}
+
+/// Adapts a `RpcHandler` into a `ConsumerHandler` replying automatically.
+///
+/// Rejected and dropped requests are answered with the `x-rpc-error` header so
+/// the caller fails fast, retried requests are only answered once handled. A
+/// reply that can't be published is only logged, retrying would run the
+/// handler again for a caller that most likely timed out already.
+pub struct RpcServer {
+    amqp: Arc<dyn IAmqp + Send + Sync>,
+    handler: Arc<dyn RpcHandler + Send + Sync>,
+}
+
+impl RpcServer {
+    pub fn new(
+        amqp: Arc<dyn IAmqp + Send + Sync>,
+        handler: Arc<dyn RpcHandler + Send + Sync>,
+    ) -> Arc<dyn ConsumerHandler + Send + Sync> {
+        Arc::new(RpcServer { amqp, handler })
+    }
+
+    async fn reply(&self, ctx: &Context, req: &RpcRequest, data: PublishData) {
+        debug!("replying to: {}", req.correlation_id);
+        let data = data.correlation_id(&req.correlation_id);
+        if let Err(err) = self.amqp.publish(ctx, "", &req.reply_to, &data).await {
+            error!("failure to reply to: {} - {:?}", req.correlation_id, err);
+        }
+    }
+}
+
+#[async_trait]
+impl ConsumerHandler for RpcServer {
+    async fn exec(&self, ctx: &Context, data: &[u8]) -> Result<(), HandlerError> {
+        let result = self.handler.exec(ctx, data).await;
+
+        let req = match ctx.get::<RpcRequest>() {
+            Some(req) => req,
+            _ => {
+                warn!("request without reply_to or correlation_id, reply discarded");
+                return result.map(|_| ());
+            }
+        };
+
+        match result {
+            Ok(reply) => {
+                self.reply(ctx, req, PublishData::raw("", reply)).await;
+                Ok(())
+            }
+            Err(HandlerError::Reject(reason)) => {
+                let data = PublishData::raw("", vec![]).header(RPC_ERROR_HEADER, &reason);
+                self.reply(ctx, req, data).await;
+                Err(HandlerError::Reject(reason))
+            }
+            Err(HandlerError::Drop(reason)) => {
+                let data = PublishData::raw("", vec![]).header(RPC_ERROR_HEADER, &reason);
+                self.reply(ctx, req, data).await;
+                Err(HandlerError::Drop(reason))
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Payload of a reply, or the failure reported by the server.
+pub(crate) fn reply_result(
+    delivery: Delivery,
+    max_message_size: usize,
+) -> Result<Vec<u8>, AmqpError> {
+    let error = delivery.properties.headers().as_ref().and_then(|headers| {
+        match headers.inner().get(RPC_ERROR_HEADER) {
+            Some(AMQPValue::LongString(reason)) => Some(reason.to_string()),
+            _ => None,
+        }
+    });
+
+    match error {
+        Some(reason) => {
+            error!("rpc request failed - {}", reason);
+            Err(AmqpError::RpcError(reason))
+        }
+        _ => {
+            let encoding = delivery.properties.content_encoding().as_ref();
+            compression::decode(
+                encoding.map(|e| e.as_str()),
+                &delivery.data,
+                max_message_size,
+            )
+            .map(|data| data.into_owned())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        amqp::{
+            client::delivery,
+            memory::InMemoryAmqp,
+            topology::{AmqpTopology, QueueDefinition},
+        },
+        env::Config,
+    };
+    use futures_util::StreamExt;
+    use lapin::{
+        protocol::basic::AMQPProperties,
+        types::{FieldTable, LongString, ShortString},
+    };
+    use std::{collections::BTreeMap, time::Duration};
+
+    struct Echo;
+
+    #[async_trait]
+    impl RpcHandler for Echo {
+        async fn exec(&self, _ctx: &Context, data: &[u8]) -> Result<Vec<u8>, HandlerError> {
+            match data {
+                b"invalid" => Err(HandlerError::Reject("invalid request".to_owned())),
+                b"large" => Ok(vec![0; 64]),
+                _ => Ok(data.to_vec()),
+            }
+        }
+    }
+
+    /// Serves `Echo` on the `rpc` queue, `silent` has no server at all.
+    async fn serve() -> Arc<InMemoryAmqp> {
+        let mut cfg = Config::mock();
+        cfg.amqp_max_message_size = 32;
+        let amqp = InMemoryAmqp::new(&cfg);
+        let topology = AmqpTopology::new()
+            .queue(QueueDefinition::name("rpc"))
+            .queue(QueueDefinition::name("silent"));
+        amqp.install_topology(&topology).await.unwrap();
+
+        let def = topology.get_consumers_def("rpc").unwrap();
+        let server = RpcServer::new(amqp.clone(), Arc::new(Echo));
+        let mut deliveries = amqp.consumer(&def).await.unwrap();
+        let consumer = amqp.clone();
+        tokio::spawn(async move {
+            while let Some(Ok(delivery)) = deliveries.next().await {
+                let _ = consumer.consume(&def, server.clone(), &delivery).await;
+            }
+        });
+
+        amqp
+    }
+
+    #[tokio::test]
+    async fn test_call() {
+        let amqp = serve().await;
+        let timeout = Duration::from_secs(1);
+
+        let data = PublishData::raw("", b"ping".to_vec());
+        assert_eq!(
+            amqp.call(&Context::new(), "", "rpc", &data, timeout).await,
+            Ok(b"ping".to_vec())
+        );
+
+        let data = PublishData::raw("", b"invalid".to_vec());
+        assert_eq!(
+            amqp.call(&Context::new(), "", "rpc", &data, timeout).await,
+            Err(AmqpError::RpcError("invalid request".to_owned()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_call_timeout() {
+        let amqp = serve().await;
+        let timeout = Duration::from_millis(50);
+
+        let data = PublishData::raw("", b"ping".to_vec());
+        assert_eq!(
+            amqp.call(&Context::new(), "", "silent", &data, timeout)
+                .await,
+            Err(AmqpError::RpcTimeoutError {})
+        );
+
+        // the reply over the max size is never published, nor handled again
+        let server = RpcServer::new(amqp, Arc::new(Echo));
+        let ctx = Context::new().with_value(RpcRequest {
+            reply_to: DIRECT_REPLY_TO.to_owned(),
+            correlation_id: "id".to_owned(),
+        });
+        assert_eq!(server.exec(&ctx, b"large").await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_call_closed() {
+        let amqp = serve().await;
+
+        let caller = amqp.clone();
+        let call = tokio::spawn(async move {
+            let data = PublishData::raw("", b"ping".to_vec());
+            let timeout = Duration::from_secs(10);
+            caller
+                .call(&Context::new(), "", "silent", &data, timeout)
+                .await
+        });
+        while amqp.messages("silent").is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        amqp.close().await.unwrap();
+        assert_eq!(
+            call.await.unwrap(),
+            Err(AmqpError::RpcError("reply consumer closed".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_rpc_request() {
+        let req = delivery(AMQPProperties::default(), b"{}");
+        assert_eq!(RpcRequest::from_delivery(&req), None);
+
+        let req = delivery(
+            AMQPProperties::default()
+                .with_reply_to(ShortString::from(DIRECT_REPLY_TO))
+                .with_correlation_id(ShortString::from("id")),
+            b"{}",
+        );
+        let req = RpcRequest::from_delivery(&req).unwrap();
+        assert_eq!(req.reply_to, DIRECT_REPLY_TO);
+        assert_eq!(req.correlation_id, "id");
+
+        let ctx = Context::new().with_value(req.clone());
+        assert_eq!(ctx.get::<RpcRequest>(), Some(&req));
+    }
+
+    #[test]
+    fn test_reply_result() {
+        let reply = delivery(AMQPProperties::default(), b"{\"state\":\"on\"}");
+        assert_eq!(
+            reply_result(reply, 1024),
+            Ok(b"{\"state\":\"on\"}".to_vec())
+        );
+
+        let mut headers = BTreeMap::new();
+        headers.insert(
+            ShortString::from(RPC_ERROR_HEADER),
+            AMQPValue::LongString(LongString::from("unknown device")),
+        );
+        let reply = delivery(
+            AMQPProperties::default().with_headers(FieldTable::from(headers)),
+            b"",
+        );
+        assert_eq!(
+            reply_result(reply, 1024),
+            Err(AmqpError::RpcError("unknown device".to_owned()))
+        );
+    }
+}
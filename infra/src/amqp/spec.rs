@@ -0,0 +1,341 @@
+use super::{
+    topology::{
+        AmqpTopology, DelayedDefinition, ExchangeBindingDefinition, ExchangeDefinition,
+        ExchangeKind, HeadersMatch, QueueBindingDefinition, QueueDefinition, QueueOverflow,
+        QueueType,
+    },
+    types::AmqpMessageType,
+};
+use crate::errors::AmqpError;
+use log::error;
+use serde::Deserialize;
+use std::{collections::BTreeMap, path::Path, time::Duration};
+
+/// File representation of an `AmqpTopology`, e.g. in YAML:
+///
+/// ```yaml
+/// exchanges:
+///   - name: exchange_top_test1
+///     kind: direct
+/// queues:
+///   - name: queue_top_test1
+///     msg_type: Temp
+///     dlq: true
+///     retry: { tiers: [1000, 10000], retries: 3 }
+///     bindings:
+///       - exchange: exchange_top_test1
+///         routing_key: exchange_top_test1_queue_top_test1
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TopologySpec {
+    pub exchanges: Vec<ExchangeSpec>,
+    pub queues: Vec<QueueSpec>,
+    pub delayed: Vec<DelayedSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExchangeSpec {
+    pub name: String,
+    #[serde(default)]
+    pub kind: ExchangeKind,
+    #[serde(default)]
+    pub delayed: bool,
+    #[serde(default = "default_true")]
+    pub durable: bool,
+    #[serde(default)]
+    pub auto_delete: bool,
+    #[serde(default)]
+    pub internal: bool,
+    pub alternate_exchange: Option<String>,
+    #[serde(default)]
+    pub bindings: Vec<ExchangeBindingSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExchangeBindingSpec {
+    pub destination: String,
+    #[serde(default)]
+    pub routing_key: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueueSpec {
+    pub name: String,
+    #[serde(default)]
+    pub msg_type: AmqpMessageType,
+    #[serde(default)]
+    pub dlq: bool,
+    pub retry: Option<RetrySpec>,
+    #[serde(default = "default_true")]
+    pub durable: bool,
+    #[serde(default)]
+    pub exclusive: bool,
+    #[serde(default)]
+    pub auto_delete: bool,
+    #[serde(default)]
+    pub queue_type: QueueType,
+    pub message_ttl: Option<i32>,
+    pub max_length: Option<i64>,
+    pub max_length_bytes: Option<i64>,
+    pub overflow: Option<QueueOverflow>,
+    #[serde(default)]
+    pub lazy: bool,
+    #[serde(default)]
+    pub single_active_consumer: bool,
+    pub max_priority: Option<u8>,
+    pub prefetch: Option<u16>,
+    pub consumer_priority: Option<i32>,
+    #[serde(default)]
+    pub bindings: Vec<QueueBindingSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RetrySpec {
+    pub tiers: Vec<i32>,
+    pub retries: i64,
+    #[serde(default)]
+    pub jitter: f32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueueBindingSpec {
+    pub exchange: String,
+    #[serde(default)]
+    pub routing_key: String,
+    pub x_match: Option<HeadersMatch>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DelayedSpec {
+    pub exchange: String,
+    #[serde(default)]
+    pub routing_key: String,
+    /// Delays in milliseconds.
+    pub delays: Vec<u64>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Topology definitions hold `&'static str`, the file is loaded once at
+/// startup so its strings are leaked to live as long as the process.
+fn leak(value: String) -> &'static str {
+    Box::leak(value.into_boxed_str())
+}
+
+impl TopologySpec {
+    pub fn into_topology(self) -> AmqpTopology {
+        let mut topology = AmqpTopology::new();
+
+        for exch in self.exchanges {
+            let name = leak(exch.name);
+            let mut def = ExchangeDefinition::name(name);
+            def.kind = exch.kind;
+            def.delayed = exch.delayed;
+            def.durable = exch.durable;
+            def.auto_delete = exch.auto_delete;
+            def.internal = exch.internal;
+            def.alternate_exchange = exch.alternate_exchange.map(leak);
+            for bind in exch.bindings {
+                def = def.binding(ExchangeBindingDefinition::new(
+                    name,
+                    leak(bind.destination),
+                    leak(bind.routing_key),
+                ));
+            }
+            topology = topology.exchange(def);
+        }
+
+        for queue in self.queues {
+            let name = leak(queue.name);
+            let mut def = QueueDefinition::name(name).msg_type(queue.msg_type);
+            if queue.dlq {
+                def = def.with_dlq();
+            }
+            if let Some(retry) = queue.retry {
+                def = def
+                    .with_backoff(&retry.tiers, retry.retries)
+                    .with_jitter(retry.jitter);
+            }
+            def.durable = queue.durable;
+            def.exclusive = queue.exclusive;
+            def.auto_delete = queue.auto_delete;
+            def.queue_type = queue.queue_type;
+            def.message_ttl = queue.message_ttl;
+            def.max_length = queue.max_length;
+            def.max_length_bytes = queue.max_length_bytes;
+            def.overflow = queue.overflow;
+            def.lazy = queue.lazy;
+            def.single_active_consumer = queue.single_active_consumer;
+            def.max_priority = queue.max_priority;
+            def.prefetch = queue.prefetch;
+            def.consumer_priority = queue.consumer_priority;
+
+            for bind in queue.bindings {
+                let mut binding =
+                    QueueBindingDefinition::new(leak(bind.exchange), name, leak(bind.routing_key));
+                if let Some(x_match) = bind.x_match {
+                    let headers = bind
+                        .headers
+                        .into_iter()
+                        .map(|(key, value)| (leak(key), leak(value)))
+                        .collect::<Vec<_>>();
+                    binding.x_match = Some(x_match);
+                    binding.headers = Box::leak(headers.into_boxed_slice());
+                }
+                def = def.binding(binding);
+            }
+            topology = topology.queue(def);
+        }
+
+        for delayed in self.delayed {
+            let mut def = DelayedDefinition::new(leak(delayed.exchange), leak(delayed.routing_key));
+            for delay in delayed.delays {
+                def = def.delay(Duration::from_millis(delay));
+            }
+            topology = topology.delayed(def);
+        }
+
+        topology
+    }
+}
+
+impl AmqpTopology {
+    pub fn from_yaml(content: &str) -> Result<Self, AmqpError> {
+        let spec = serde_yaml::from_str::<TopologySpec>(content).map_err(|err| {
+            error!("failure to parse yaml topology - {:?}", err);
+            AmqpError::TopologyFileError(err.to_string())
+        })?;
+
+        Ok(spec.into_topology())
+    }
+
+    pub fn from_toml(content: &str) -> Result<Self, AmqpError> {
+        let spec = toml::from_str::<TopologySpec>(content).map_err(|err| {
+            error!("failure to parse toml topology - {:?}", err);
+            AmqpError::TopologyFileError(err.to_string())
+        })?;
+
+        Ok(spec.into_topology())
+    }
+
+    /// Loads a `.yaml`, `.yml` or `.toml` topology file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, AmqpError> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| AmqpError::TopologyFileError(err.to_string()))?;
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml") | Some("yml") => Self::from_yaml(&content),
+            Some("toml") => Self::from_toml(&content),
+            _ => Err(AmqpError::TopologyFileError(format!(
+                "unsupported topology file `{}`",
+                path.display()
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const YAML: &str = r#"
+exchanges:
+  - name: exchange
+    kind: topic
+    alternate_exchange: unrouted
+    bindings:
+      - destination: other
+        routing_key: "iot.#"
+  - name: headers
+    kind: headers
+queues:
+  - name: queue
+    msg_type: Temp
+    dlq: true
+    retry: { tiers: [1000, 10000], retries: 3, jitter: 0.5 }
+    queue_type: quorum
+    overflow: reject-publish
+    prefetch: 1
+    consumer_priority: 10
+    bindings:
+      - exchange: exchange
+        routing_key: "iot.*.temp"
+      - exchange: headers
+        x_match: any
+        headers: { type: Temp }
+delayed:
+  - exchange: exchange
+    routing_key: iot.commands
+    delays: [30000]
+"#;
+
+    #[test]
+    fn test_from_yaml() {
+        let topology = AmqpTopology::from_yaml(YAML).unwrap();
+        assert!(topology.validate().is_ok());
+
+        assert_eq!(topology.exchanges.len(), 2);
+        assert_eq!(topology.exchanges[0].kind, ExchangeKind::Topic);
+        assert!(topology.exchanges[0].durable);
+        assert_eq!(topology.exchanges[0].alternate_exchange, Some("unrouted"));
+        assert_eq!(topology.exchanges[0].bindings[0].destination, "other");
+
+        let queue = &topology.queues[0];
+        assert_eq!(queue.name, "queue");
+        assert_eq!(queue.msg_type, AmqpMessageType::Temp);
+        assert!(queue.with_dlq && queue.with_retry);
+        assert_eq!(queue.retry_tiers, vec![1000, 10000]);
+        assert_eq!(queue.retries, Some(3));
+        assert_eq!(queue.queue_type, QueueType::Quorum);
+        assert_eq!(queue.overflow, Some(QueueOverflow::RejectPublish));
+        let consumer = topology.get_consumers_def("queue").unwrap();
+        assert_eq!(consumer.prefetch, 1);
+        assert_eq!(consumer.priority, Some(10));
+        assert_eq!(queue.bindings[0].routing_key, "iot.*.temp");
+        assert_eq!(queue.bindings[1].x_match, Some(HeadersMatch::Any));
+        assert_eq!(queue.bindings[1].headers, &[("type", "Temp")]);
+
+        assert_eq!(
+            topology.delayed[0].delays,
+            vec![Duration::from_millis(30000)]
+        );
+    }
+
+    #[test]
+    fn test_from_toml() {
+        let topology = AmqpTopology::from_toml(
+            r#"
+[[exchanges]]
+name = "exchange"
+kind = "fanout"
+
+[[queues]]
+name = "queue"
+durable = false
+max_length = 100
+
+[[queues.bindings]]
+exchange = "exchange"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(topology.exchanges[0].kind, ExchangeKind::Fanout);
+        assert!(!topology.queues[0].durable);
+        assert_eq!(topology.queues[0].max_length, Some(100));
+        assert_eq!(topology.queues[0].bindings[0].exchange, "exchange");
+        assert_eq!(topology.queues[0].bindings[0].routing_key, "");
+    }
+
+    #[test]
+    fn test_invalid_file() {
+        assert!(AmqpTopology::from_yaml("queues: [{ kind: nope }]").is_err());
+        assert!(AmqpTopology::from_file("topology.json").is_err());
+    }
+}
@@ -0,0 +1,331 @@
+use crate::errors::AmqpError;
+use async_trait::async_trait;
+use lapin::types::{AMQPValue, FieldTable, LongString};
+use log::error;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::Mutex as AsyncMutex;
+
+/// Consumer argument selecting where a stream is read from, the broker also
+/// sets it on each delivery with the offset of the message.
+pub const STREAM_OFFSET_HEADER: &str = "x-stream-offset";
+
+/// Where a stream consumer starts reading when there is no stored offset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StreamOffset {
+    /// Oldest message still in the stream
+    First,
+    /// Last chunk written to the stream
+    Last,
+    /// Only messages published after the consumer is attached
+    #[default]
+    Next,
+    /// Messages published at or after the unix timestamp, in seconds
+    Timestamp(u64),
+    Offset(u64),
+}
+
+impl StreamOffset {
+    pub fn argument(&self) -> AMQPValue {
+        match self {
+            StreamOffset::First => AMQPValue::LongString(LongString::from("first")),
+            StreamOffset::Last => AMQPValue::LongString(LongString::from("last")),
+            StreamOffset::Next => AMQPValue::LongString(LongString::from("next")),
+            StreamOffset::Timestamp(secs) => AMQPValue::Timestamp(*secs),
+            StreamOffset::Offset(offset) => AMQPValue::LongLongInt(*offset as i64),
+        }
+    }
+
+    /// A stored offset was already handled, the consumer resumes right after it.
+    pub fn resume(stored: Option<u64>, default: StreamOffset) -> StreamOffset {
+        match stored {
+            Some(offset) => StreamOffset::Offset(offset + 1),
+            _ => default,
+        }
+    }
+}
+
+/// Offset of a message delivered from a stream.
+pub fn delivery_offset(header: &FieldTable) -> Option<u64> {
+    match header.inner().get(STREAM_OFFSET_HEADER) {
+        Some(AMQPValue::LongLongInt(offset)) => Some(*offset as u64),
+        Some(AMQPValue::LongUInt(offset)) => Some(*offset as u64),
+        Some(AMQPValue::LongInt(offset)) => Some(*offset as u64),
+        _ => None,
+    }
+}
+
+/// Offsets delivered to a stream consumer and not saved yet, so deliveries
+/// settled out of order by a concurrent consumer never save an offset past
+/// one still being handled, which a restart would skip.
+#[derive(Debug, Default)]
+pub struct OffsetTracker {
+    pending: Mutex<BTreeMap<u64, bool>>,
+}
+
+impl OffsetTracker {
+    /// Registers an offset in delivery order, before it is handled.
+    pub fn delivered(&self, offset: u64) {
+        self.pending.lock().unwrap().entry(offset).or_insert(false);
+    }
+
+    /// Marks `offset` settled, whether handled, retried or dead-lettered, and
+    /// returns the offset to save once every offset delivered before it was
+    /// settled as well.
+    pub fn settled(&self, offset: u64) -> Option<u64> {
+        let mut pending = self.pending.lock().unwrap();
+        pending.insert(offset, true);
+
+        let mut contiguous = None;
+        loop {
+            let first = match pending.iter().next() {
+                Some((&first, &true)) => first,
+                _ => return contiguous,
+            };
+            pending.remove(&first);
+            contiguous = Some(first);
+        }
+    }
+}
+
+/// Last settled offset of each stream consumer, saved values never go back so
+/// concurrent deliveries finishing out of order don't rewind the consumer.
+#[async_trait]
+pub trait OffsetStore: Debug {
+    async fn load(&self, consumer: &str) -> Result<Option<u64>, AmqpError>;
+    async fn save(&self, consumer: &str, offset: u64) -> Result<(), AmqpError>;
+    /// Forgets the offset of `consumer`, the only way a saved one goes back.
+    async fn reset(&self, consumer: &str) -> Result<(), AmqpError>;
+}
+
+#[derive(Debug, Default)]
+pub struct InMemoryOffsetStore {
+    offsets: Mutex<HashMap<String, u64>>,
+}
+
+impl InMemoryOffsetStore {
+    pub fn new() -> Arc<dyn OffsetStore + Send + Sync> {
+        Arc::new(InMemoryOffsetStore::default())
+    }
+}
+
+#[async_trait]
+impl OffsetStore for InMemoryOffsetStore {
+    async fn load(&self, consumer: &str) -> Result<Option<u64>, AmqpError> {
+        Ok(self.offsets.lock().unwrap().get(consumer).copied())
+    }
+
+    async fn save(&self, consumer: &str, offset: u64) -> Result<(), AmqpError> {
+        let mut offsets = self.offsets.lock().unwrap();
+        let current = offsets.entry(consumer.to_owned()).or_insert(offset);
+        *current = offset.max(*current);
+        Ok(())
+    }
+
+    async fn reset(&self, consumer: &str) -> Result<(), AmqpError> {
+        self.offsets.lock().unwrap().remove(consumer);
+        Ok(())
+    }
+}
+
+/// Keeps one `<consumer>.offset` file per consumer inside `dir`.
+#[derive(Debug)]
+pub struct FileOffsetStore {
+    dir: PathBuf,
+    offsets: InMemoryOffsetStore,
+    writes: Mutex<HashMap<String, Arc<AsyncMutex<()>>>>,
+}
+
+impl FileOffsetStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Arc<dyn OffsetStore + Send + Sync>, AmqpError> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).map_err(|err| {
+            error!("failure to create offsets dir - {:?}", err);
+            AmqpError::OffsetStoreError(dir.display().to_string())
+        })?;
+
+        Ok(Arc::new(FileOffsetStore {
+            dir,
+            offsets: InMemoryOffsetStore::default(),
+            writes: Mutex::new(HashMap::default()),
+        }))
+    }
+
+    fn path(&self, consumer: &str) -> PathBuf {
+        self.dir.join(format!("{}.offset", consumer))
+    }
+
+    /// Serializes the writes of a consumer, they share its tmp file.
+    fn write_lock(&self, consumer: &str) -> Arc<AsyncMutex<()>> {
+        self.writes
+            .lock()
+            .unwrap()
+            .entry(consumer.to_owned())
+            .or_default()
+            .clone()
+    }
+}
+
+#[async_trait]
+impl OffsetStore for FileOffsetStore {
+    async fn load(&self, consumer: &str) -> Result<Option<u64>, AmqpError> {
+        if let Some(offset) = self.offsets.load(consumer).await? {
+            return Ok(Some(offset));
+        }
+
+        let content = match tokio::fs::read_to_string(self.path(consumer)).await {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                error!("failure to read offset - {:?}", err);
+                return Err(AmqpError::OffsetStoreError(consumer.to_owned()));
+            }
+        };
+
+        let offset = content.trim().parse::<u64>().map_err(|err| {
+            error!("invalid stored offset - {:?}", err);
+            AmqpError::OffsetStoreError(consumer.to_owned())
+        })?;
+        self.offsets.save(consumer, offset).await?;
+
+        Ok(Some(offset))
+    }
+
+    async fn save(&self, consumer: &str, offset: u64) -> Result<(), AmqpError> {
+        let lock = self.write_lock(consumer);
+        let _write = lock.lock().await;
+
+        self.offsets.save(consumer, offset).await?;
+        let offset = self.offsets.load(consumer).await?.unwrap_or(offset);
+
+        // written aside and renamed so a crash never leaves a truncated file
+        let path = self.path(consumer);
+        let tmp = path.with_extension("offset.tmp");
+        let written = match tokio::fs::write(&tmp, offset.to_string()).await {
+            Ok(_) => tokio::fs::rename(&tmp, &path).await,
+            err => err,
+        };
+
+        written.map_err(|err| {
+            error!("failure to save offset - {:?}", err);
+            AmqpError::OffsetStoreError(consumer.to_owned())
+        })
+    }
+
+    async fn reset(&self, consumer: &str) -> Result<(), AmqpError> {
+        let lock = self.write_lock(consumer);
+        let _write = lock.lock().await;
+
+        self.offsets.reset(consumer).await?;
+        match tokio::fs::remove_file(self.path(consumer)).await {
+            Ok(_) => Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => {
+                error!("failure to reset offset - {:?}", err);
+                Err(AmqpError::OffsetStoreError(consumer.to_owned()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lapin::types::ShortString;
+
+    #[test]
+    fn test_stream_offset() {
+        assert_eq!(
+            StreamOffset::First.argument(),
+            AMQPValue::LongString(LongString::from("first"))
+        );
+        assert_eq!(
+            StreamOffset::Timestamp(1660000000).argument(),
+            AMQPValue::Timestamp(1660000000)
+        );
+        assert_eq!(
+            StreamOffset::Offset(42).argument(),
+            AMQPValue::LongLongInt(42)
+        );
+
+        assert_eq!(
+            StreamOffset::resume(None, StreamOffset::First),
+            StreamOffset::First
+        );
+        assert_eq!(
+            StreamOffset::resume(Some(41), StreamOffset::First),
+            StreamOffset::Offset(42)
+        );
+    }
+
+    #[test]
+    fn test_delivery_offset() {
+        let mut header = FieldTable::default();
+        assert_eq!(delivery_offset(&header), None);
+
+        header.insert(
+            ShortString::from(STREAM_OFFSET_HEADER),
+            AMQPValue::LongLongInt(7),
+        );
+        assert_eq!(delivery_offset(&header), Some(7));
+    }
+
+    #[test]
+    fn test_offset_tracker() {
+        let tracker = OffsetTracker::default();
+        for offset in 10..14 {
+            tracker.delivered(offset);
+        }
+
+        assert_eq!(tracker.settled(11), None);
+        assert_eq!(tracker.settled(13), None);
+        assert_eq!(tracker.settled(10), Some(11));
+        assert_eq!(tracker.settled(12), Some(13));
+
+        // offsets consumed without the dispatcher are saved right away
+        assert_eq!(tracker.settled(20), Some(20));
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_offset_store() {
+        let store = InMemoryOffsetStore::new();
+        assert_eq!(store.load("consumer").await.unwrap(), None);
+
+        store.save("consumer", 10).await.unwrap();
+        store.save("consumer", 5).await.unwrap();
+        assert_eq!(store.load("consumer").await.unwrap(), Some(10));
+        assert_eq!(store.load("other").await.unwrap(), None);
+
+        store.reset("consumer").await.unwrap();
+        store.save("consumer", 5).await.unwrap();
+        assert_eq!(store.load("consumer").await.unwrap(), Some(5));
+    }
+
+    #[tokio::test]
+    async fn test_file_offset_store() {
+        let dir = std::env::temp_dir().join(format!("offsets-{}", uuid::Uuid::new_v4()));
+
+        let store = FileOffsetStore::new(&dir).unwrap();
+        assert_eq!(store.load("consumer").await.unwrap(), None);
+        store.save("consumer", 10).await.unwrap();
+
+        let saves = (0..20).map(|offset| store.save("consumer", offset));
+        for saved in futures_util::future::join_all(saves).await {
+            assert!(saved.is_ok());
+        }
+
+        let store = FileOffsetStore::new(&dir).unwrap();
+        assert_eq!(store.load("consumer").await.unwrap(), Some(19));
+
+        store.reset("consumer").await.unwrap();
+        let store = FileOffsetStore::new(&dir).unwrap();
+        assert_eq!(store.load("consumer").await.unwrap(), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,14 +1,44 @@
-use crate::errors::AmqpError;
+use crate::errors::{AmqpError, HandlerError};
 use async_trait::async_trait;
+use lapin::types::{AMQPValue, LongInt, LongLongInt, LongString, ShortShortUInt, ShortString};
+use log::error;
 use opentelemetry::Context;
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{collections::BTreeMap, marker::PhantomData, sync::Arc, time::Duration};
 
-use super::types::AmqpMessageType;
+use super::{
+    idempotency::IdempotencyStore,
+    router::UnknownTypePolicy,
+    stream::{OffsetStore, OffsetTracker, StreamOffset},
+    types::AmqpMessageType,
+};
+
+pub const DEFAULT_PREFETCH: u16 = 10;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HeadersMatch {
+    #[default]
+    All,
+    Any,
+}
+
+impl HeadersMatch {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HeadersMatch::All => "all",
+            HeadersMatch::Any => "any",
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy, Default)]
 pub struct QueueBindingDefinition {
     pub exchange: &'static str,
     pub queue: &'static str,
     pub routing_key: &'static str,
+    pub x_match: Option<HeadersMatch>,
+    pub headers: &'static [(&'static str, &'static str)],
 }
 
 impl QueueBindingDefinition {
@@ -17,6 +47,128 @@ impl QueueBindingDefinition {
             exchange,
             queue,
             routing_key,
+            x_match: None,
+            headers: &[],
+        }
+    }
+
+    /// Headers exchange binding matching messages carrying every header,
+    /// e.g. `&[("type", "Temp"), ("device-id", "device")]`.
+    pub fn match_all(mut self, headers: &'static [(&'static str, &'static str)]) -> Self {
+        self.x_match = Some(HeadersMatch::All);
+        self.headers = headers;
+        self
+    }
+
+    /// Headers exchange binding matching messages carrying at least one header.
+    pub fn match_any(mut self, headers: &'static [(&'static str, &'static str)]) -> Self {
+        self.x_match = Some(HeadersMatch::Any);
+        self.headers = headers;
+        self
+    }
+
+    pub fn arguments(&self) -> BTreeMap<ShortString, AMQPValue> {
+        let mut args = BTreeMap::new();
+
+        if let Some(x_match) = self.x_match {
+            args.insert(
+                ShortString::from("x-match"),
+                AMQPValue::LongString(LongString::from(x_match.as_str())),
+            );
+
+            for (key, value) in self.headers.iter() {
+                args.insert(
+                    ShortString::from(*key),
+                    AMQPValue::LongString(LongString::from(*value)),
+                );
+            }
+        }
+
+        args
+    }
+}
+
+/// Topic binding keys are dot separated words where a word is either `*`,
+/// `#` or a literal without wildcards, e.g. `iot.*.temp.#`.
+pub fn is_valid_topic_pattern(pattern: &str) -> bool {
+    if pattern.is_empty() {
+        return true;
+    }
+
+    pattern.split('.').all(|word| match word {
+        "*" | "#" => true,
+        _ => !word.is_empty() && !word.contains(['*', '#']),
+    })
+}
+
+/// A queue exactly as it is declared on the broker.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueDeclaration {
+    pub name: String,
+    pub durable: bool,
+    pub exclusive: bool,
+    pub auto_delete: bool,
+    pub arguments: BTreeMap<ShortString, AMQPValue>,
+}
+
+impl QueueDeclaration {
+    fn durable(name: String, arguments: BTreeMap<ShortString, AMQPValue>) -> Self {
+        QueueDeclaration {
+            name,
+            durable: true,
+            exclusive: false,
+            auto_delete: false,
+            arguments,
+        }
+    }
+}
+
+fn dead_letter_arguments(exchange: &str, routing_key: &str) -> BTreeMap<ShortString, AMQPValue> {
+    let mut args = BTreeMap::new();
+    args.insert(
+        ShortString::from("x-dead-letter-exchange"),
+        AMQPValue::LongString(LongString::from(exchange)),
+    );
+    args.insert(
+        ShortString::from("x-dead-letter-routing-key"),
+        AMQPValue::LongString(LongString::from(routing_key)),
+    );
+    args
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueType {
+    #[default]
+    Classic,
+    Quorum,
+    Stream,
+}
+
+impl QueueType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QueueType::Classic => "classic",
+            QueueType::Quorum => "quorum",
+            QueueType::Stream => "stream",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueueOverflow {
+    DropHead,
+    RejectPublish,
+    RejectPublishDlx,
+}
+
+impl QueueOverflow {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QueueOverflow::DropHead => "drop-head",
+            QueueOverflow::RejectPublish => "reject-publish",
+            QueueOverflow::RejectPublishDlx => "reject-publish-dlx",
         }
     }
 }
@@ -31,16 +183,259 @@ pub struct QueueDefinition {
     pub with_retry: bool,
     pub retry_ttl: Option<i32>,
     pub retries: Option<i64>,
+    pub retry_tiers: Vec<i32>,
+    pub retry_jitter: f32,
+    pub durable: bool,
+    pub exclusive: bool,
+    pub auto_delete: bool,
+    pub queue_type: QueueType,
+    pub message_ttl: Option<i32>,
+    pub max_length: Option<i64>,
+    pub max_length_bytes: Option<i64>,
+    pub overflow: Option<QueueOverflow>,
+    pub lazy: bool,
+    pub single_active_consumer: bool,
+    pub max_priority: Option<u8>,
+    /// Prefetch of the consumers built by `get_consumers_def`
+    pub prefetch: Option<u16>,
+    /// Priority of the consumers built by `get_consumers_def`
+    pub consumer_priority: Option<i32>,
 }
 
 impl QueueDefinition {
     pub fn name(name: &'static str) -> QueueDefinition {
         QueueDefinition {
             name,
+            durable: true,
             ..Default::default()
         }
     }
 
+    /// The queue doesn't survive a broker restart.
+    pub fn transient(mut self) -> Self {
+        self.durable = false;
+        self
+    }
+
+    pub fn exclusive(mut self) -> Self {
+        self.exclusive = true;
+        self
+    }
+
+    pub fn auto_delete(mut self) -> Self {
+        self.auto_delete = true;
+        self
+    }
+
+    pub fn quorum(mut self) -> Self {
+        self.queue_type = QueueType::Quorum;
+        self
+    }
+
+    pub fn stream(mut self) -> Self {
+        self.queue_type = QueueType::Stream;
+        self
+    }
+
+    pub fn message_ttl(mut self, milliseconds: i32) -> Self {
+        self.message_ttl = Some(milliseconds);
+        self
+    }
+
+    pub fn max_length(mut self, max: i64) -> Self {
+        self.max_length = Some(max);
+        self
+    }
+
+    pub fn max_length_bytes(mut self, max: i64) -> Self {
+        self.max_length_bytes = Some(max);
+        self
+    }
+
+    /// What the broker does once `max_length` or `max_length_bytes` is reached.
+    pub fn overflow(mut self, overflow: QueueOverflow) -> Self {
+        self.overflow = Some(overflow);
+        self
+    }
+
+    pub fn lazy(mut self) -> Self {
+        self.lazy = true;
+        self
+    }
+
+    pub fn single_active_consumer(mut self) -> Self {
+        self.single_active_consumer = true;
+        self
+    }
+
+    pub fn max_priority(mut self, priority: u8) -> Self {
+        self.max_priority = Some(priority);
+        self
+    }
+
+    pub fn prefetch(mut self, prefetch: u16) -> Self {
+        self.prefetch = Some(prefetch);
+        self
+    }
+
+    pub fn consumer_priority(mut self, priority: i32) -> Self {
+        self.consumer_priority = Some(priority);
+        self
+    }
+
+    /// Declaration arguments derived from the definition, retry and DLQ
+    /// arguments are added when the queue is installed.
+    pub fn arguments(&self) -> BTreeMap<ShortString, AMQPValue> {
+        let mut args = BTreeMap::new();
+
+        if self.queue_type != QueueType::Classic {
+            args.insert(
+                ShortString::from("x-queue-type"),
+                AMQPValue::LongString(LongString::from(self.queue_type.as_str())),
+            );
+        }
+
+        if let Some(ttl) = self.message_ttl {
+            args.insert(
+                ShortString::from("x-message-ttl"),
+                AMQPValue::LongInt(LongInt::from(ttl)),
+            );
+        }
+
+        if let Some(max) = self.max_length {
+            args.insert(
+                ShortString::from("x-max-length"),
+                AMQPValue::LongLongInt(LongLongInt::from(max)),
+            );
+        }
+
+        if let Some(max) = self.max_length_bytes {
+            args.insert(
+                ShortString::from("x-max-length-bytes"),
+                AMQPValue::LongLongInt(LongLongInt::from(max)),
+            );
+        }
+
+        if let Some(overflow) = self.overflow {
+            args.insert(
+                ShortString::from("x-overflow"),
+                AMQPValue::LongString(LongString::from(overflow.as_str())),
+            );
+        }
+
+        if self.lazy {
+            args.insert(
+                ShortString::from("x-queue-mode"),
+                AMQPValue::LongString(LongString::from("lazy")),
+            );
+        }
+
+        if self.single_active_consumer {
+            args.insert(
+                ShortString::from("x-single-active-consumer"),
+                AMQPValue::Boolean(true),
+            );
+        }
+
+        if let Some(priority) = self.max_priority {
+            args.insert(
+                ShortString::from("x-max-priority"),
+                AMQPValue::ShortShortUInt(ShortShortUInt::from(priority)),
+            );
+        }
+
+        args
+    }
+
+    /// Every queue needed by this definition in declaration order: the retry
+    /// tiers dead-lettering back to the queue, the DLQ and the queue itself,
+    /// which dead-letters to the first retry tier or to the DLQ.
+    pub fn declarations(&self) -> Vec<QueueDeclaration> {
+        let mut declarations = vec![];
+        let mut args = BTreeMap::new();
+
+        if self.with_retry {
+            for (name, ttl) in self.retry_queues() {
+                let mut retry_args = dead_letter_arguments("", self.name);
+                retry_args.insert(
+                    ShortString::from("x-message-ttl"),
+                    AMQPValue::LongInt(LongInt::from(ttl)),
+                );
+                declarations.push(QueueDeclaration::durable(name, retry_args));
+            }
+
+            let first = retry_queue_names(self.name, &self.retry_tiers).remove(0);
+            args = dead_letter_arguments("", &first);
+        }
+
+        if self.with_dlq || self.with_retry {
+            let dlq = self.dlq_name();
+            if !self.with_retry {
+                args = dead_letter_arguments("", &dlq);
+            }
+            declarations.push(QueueDeclaration::durable(dlq, BTreeMap::new()));
+        }
+
+        args.extend(self.arguments());
+        declarations.push(QueueDeclaration {
+            name: self.name.to_owned(),
+            durable: self.durable,
+            exclusive: self.exclusive,
+            auto_delete: self.auto_delete,
+            arguments: args,
+        });
+
+        declarations
+    }
+
+    /// Rejects combinations the broker would refuse when declaring the queue.
+    pub fn validate(&self) -> Result<(), AmqpError> {
+        let invalid = |reason: &str| {
+            error!("invalid queue: {} - {}", self.name, reason);
+            Err(AmqpError::InvalidTopologyError(
+                self.name.to_owned(),
+                reason.to_owned(),
+            ))
+        };
+
+        if self.queue_type != QueueType::Classic {
+            if !self.durable || self.exclusive || self.auto_delete {
+                return invalid("quorum and stream queues must be durable and shared");
+            }
+
+            if self.lazy {
+                return invalid("only classic queues can be lazy");
+            }
+        }
+
+        if self.queue_type == QueueType::Stream {
+            if self.with_retry || self.with_dlq {
+                return invalid("stream queues don't support dead lettering");
+            }
+
+            if self.max_priority.is_some() || self.single_active_consumer {
+                return invalid("stream queues don't support priorities");
+            }
+        }
+
+        if self.overflow == Some(QueueOverflow::RejectPublishDlx) && !self.with_dlq {
+            return invalid("reject-publish-dlx requires a dlq");
+        }
+
+        if self.with_retry
+            && (self.retry_tiers.is_empty() || self.retry_tiers.iter().any(|ttl| *ttl <= 0))
+        {
+            return invalid("retry requires positive tiers");
+        }
+
+        // a jittered delay of 0 would hand the message straight back
+        if self.retry_queues().iter().any(|(_, ttl)| *ttl <= 0) {
+            return invalid("retry jitter must leave every delay positive");
+        }
+
+        Ok(())
+    }
+
     pub fn msg_type(mut self, msg_type: AmqpMessageType) -> Self {
         self.msg_type = msg_type;
         self
@@ -48,17 +443,48 @@ impl QueueDefinition {
 
     pub fn with_dlq(mut self) -> Self {
         self.with_dlq = true;
-        self.dlq_name = Box::leak(Box::new(self.dlq_name()));
+        self.leak_dlq_name();
         self
     }
 
-    pub fn with_retry(mut self, milliseconds: i32, retries: i64) -> Self {
+    pub fn with_retry(self, milliseconds: i32, retries: i64) -> Self {
+        self.with_backoff(&[milliseconds], retries)
+    }
+
+    /// Declares one retry queue per tier, e.g. `&[1000, 10000, 60000, 600000]`.
+    /// The n-th retry waits for the n-th tier and every retry past the last
+    /// tier waits for the last one.
+    pub fn with_backoff(mut self, tiers: &[i32], retries: i64) -> Self {
+        // exhausted retries go to the DLQ, declared even without `with_dlq`
+        self.leak_dlq_name();
         self.with_retry = true;
         self.retries = Some(retries);
-        self.retry_ttl = Some(milliseconds);
+        self.retry_ttl = tiers.first().copied();
+        self.retry_tiers = tiers.to_vec();
         self
     }
 
+    /// Randomly shortens each retry delay by up to `ratio` of the tier TTL so
+    /// failed messages don't come back all at once, `ratio` stays below 1.
+    pub fn with_jitter(mut self, ratio: f32) -> Self {
+        self.retry_jitter = ratio.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Every retry queue with its TTL, jittered tiers included.
+    pub fn retry_queues(&self) -> Vec<(String, i32)> {
+        let mut queues = vec![];
+        for queue in retry_tiers(self.name, &self.retry_tiers, self.retry_jitter)
+            .into_iter()
+            .flatten()
+        {
+            if !queues.contains(&queue) {
+                queues.push(queue);
+            }
+        }
+        queues
+    }
+
     pub fn binding(mut self, bind: QueueBindingDefinition) -> Self {
         self.bindings.push(bind);
         self
@@ -67,9 +493,54 @@ impl QueueDefinition {
     fn dlq_name(&self) -> String {
         format!("{}-dlq", self.name)
     }
+
+    fn leak_dlq_name(&mut self) {
+        if self.dlq_name.is_empty() {
+            self.dlq_name = Box::leak(Box::new(self.dlq_name()));
+        }
+    }
+}
+
+/// A single tier keeps the historical `<queue>-retry` name, multiple tiers are
+/// named after their TTL so changing a tier declares a new queue instead of
+/// conflicting with the existing one.
+pub fn retry_queue_names(queue: &str, tiers: &[i32]) -> Vec<String> {
+    match tiers.len() {
+        0 | 1 => vec![format!("{}-retry", queue)],
+        _ => tiers
+            .iter()
+            .map(|ttl| format!("{}-retry-{}", queue, ttl))
+            .collect(),
+    }
+}
+
+/// Retry queues a jittered tier is split into. RabbitMQ only expires messages
+/// from the head of a queue, so a shorter per-message expiration would wait
+/// behind the messages queued before it, each queue keeps a single TTL instead.
+const JITTER_STEPS: usize = 4;
+
+/// Retry queues of each tier with their TTL, a jittered tier is spread over
+/// `JITTER_STEPS` queues from the tier TTL down to `1 - jitter` of it.
+pub fn retry_tiers(queue: &str, tiers: &[i32], jitter: f32) -> Vec<Vec<(String, i32)>> {
+    retry_queue_names(queue, tiers)
+        .into_iter()
+        .zip(tiers.iter().copied())
+        .map(|(name, ttl)| {
+            let mut queues = vec![(name, ttl)];
+            if jitter > 0.0 {
+                for step in 1..JITTER_STEPS {
+                    let cut = ttl as f32 * jitter * step as f32 / (JITTER_STEPS - 1) as f32;
+                    let jittered = ttl - cut.round() as i32;
+                    queues.push((format!("{}-retry-{}", queue, jittered), jittered));
+                }
+            }
+            queues
+        })
+        .collect()
 }
 
-#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ExchangeKind {
     #[default]
     Direct,
@@ -87,12 +558,44 @@ impl ExchangeKind {
             ExchangeKind::Topic => lapin::ExchangeKind::Topic,
         }
     }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExchangeKind::Direct => "direct",
+            ExchangeKind::Fanout => "fanout",
+            ExchangeKind::Headers => "headers",
+            ExchangeKind::Topic => "topic",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExchangeBindingDefinition {
+    pub source: &'static str,
+    pub destination: &'static str,
+    pub routing_key: &'static str,
+}
+
+impl ExchangeBindingDefinition {
+    pub fn new(source: &'static str, destination: &'static str, routing_key: &'static str) -> Self {
+        ExchangeBindingDefinition {
+            source,
+            destination,
+            routing_key,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct ExchangeDefinition {
     pub name: &'static str,
     pub kind: ExchangeKind,
+    pub delayed: bool,
+    pub durable: bool,
+    pub auto_delete: bool,
+    pub internal: bool,
+    pub alternate_exchange: Option<&'static str>,
+    pub bindings: Vec<ExchangeBindingDefinition>,
 }
 
 impl ExchangeDefinition {
@@ -100,9 +603,72 @@ impl ExchangeDefinition {
         ExchangeDefinition {
             name,
             kind: ExchangeKind::default(),
+            delayed: false,
+            durable: true,
+            auto_delete: false,
+            internal: false,
+            alternate_exchange: None,
+            bindings: vec![],
         }
     }
 
+    /// The exchange doesn't survive a broker restart.
+    pub fn transient(mut self) -> Self {
+        self.durable = false;
+        self
+    }
+
+    pub fn auto_delete(mut self) -> Self {
+        self.auto_delete = true;
+        self
+    }
+
+    /// Only reachable through exchange-to-exchange bindings.
+    pub fn internal(mut self) -> Self {
+        self.internal = true;
+        self
+    }
+
+    /// Messages this exchange can't route are sent to `exchange` instead.
+    pub fn alternate_exchange(mut self, exchange: &'static str) -> Self {
+        self.alternate_exchange = Some(exchange);
+        self
+    }
+
+    /// Binds this exchange to another one, installed after every exchange of
+    /// the topology was declared.
+    pub fn binding(mut self, bind: ExchangeBindingDefinition) -> Self {
+        self.bindings.push(bind);
+        self
+    }
+
+    pub fn arguments(&self) -> BTreeMap<ShortString, AMQPValue> {
+        let mut args = BTreeMap::new();
+
+        if self.delayed {
+            args.insert(
+                ShortString::from("x-delayed-type"),
+                AMQPValue::LongString(LongString::from(self.kind.as_str())),
+            );
+        }
+
+        if let Some(exchange) = self.alternate_exchange {
+            args.insert(
+                ShortString::from("alternate-exchange"),
+                AMQPValue::LongString(LongString::from(exchange)),
+            );
+        }
+
+        args
+    }
+
+    /// Declares the exchange as `x-delayed-message`, routing as `kind` once the
+    /// `x-delay` header elapses. Requires the delayed message exchange plugin.
+    pub fn delayed(mut self) -> Self {
+        self.delayed = true;
+        self
+    }
+
     pub fn direct(mut self) -> Self {
         self.kind = ExchangeKind::Direct;
         self
@@ -126,10 +692,62 @@ impl ExchangeDefinition {
 
 #[async_trait]
 pub trait ConsumerHandler {
-    async fn exec(&self, ctx: &Context, data: &[u8]) -> Result<(), AmqpError>;
+    async fn exec(&self, ctx: &Context, data: &[u8]) -> Result<(), HandlerError>;
+
+    /// Whether the delivery typed in `ctx` has a handler, the others go
+    /// through the `UnknownTypePolicy` of the consumer.
+    fn handles(&self, _ctx: &Context) -> bool {
+        true
+    }
+}
+
+#[async_trait]
+pub trait TypedConsumerHandler<T>
+where
+    T: DeserializeOwned + Send + Sync,
+{
+    async fn exec(&self, ctx: &Context, msg: &T) -> Result<(), HandlerError>;
+}
+
+/// Adapts a `TypedConsumerHandler` into a raw `ConsumerHandler`.
+///
+/// Payloads that can't be deserialized fail with `HandlerError::Reject`, which
+/// `IAmqp::consume` sends straight to the DLQ instead of retrying.
+pub struct TypedConsumer<T> {
+    handler: Arc<dyn TypedConsumerHandler<T> + Send + Sync>,
+    _msg: PhantomData<fn() -> T>,
+}
+
+impl<T> TypedConsumer<T>
+where
+    T: DeserializeOwned + Send + Sync + 'static,
+{
+    pub fn new(
+        handler: Arc<dyn TypedConsumerHandler<T> + Send + Sync>,
+    ) -> Arc<dyn ConsumerHandler + Send + Sync> {
+        Arc::new(TypedConsumer {
+            handler,
+            _msg: PhantomData,
+        })
+    }
+}
+
+#[async_trait]
+impl<T> ConsumerHandler for TypedConsumer<T>
+where
+    T: DeserializeOwned + Send + Sync,
+{
+    async fn exec(&self, ctx: &Context, data: &[u8]) -> Result<(), HandlerError> {
+        let msg = serde_json::from_slice::<T>(data).map_err(|err| {
+            error!("failure to deserialize the payload - {:?}", err);
+            HandlerError::Reject(format!("failure to deserialize the payload - {}", err))
+        })?;
+
+        self.handler.exec(ctx, &msg).await
+    }
 }
 
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct ConsumerDefinition {
     pub name: &'static str,
     pub queue: &'static str,
@@ -138,6 +756,50 @@ pub struct ConsumerDefinition {
     pub retries: i64,
     pub with_dlq: bool,
     pub dlq_name: &'static str,
+    pub prefetch: u16,
+    pub priority: Option<i32>,
+    pub concurrency: usize,
+    pub retry_tiers: Vec<i32>,
+    pub retry_jitter: f32,
+    pub stream_offset: Option<StreamOffset>,
+    /// `stream_offset` wins over the stored offset, see `stream_offset_force`
+    pub stream_offset_force: bool,
+    pub offset_store: Option<Arc<dyn OffsetStore + Send + Sync>>,
+    pub offset_tracker: Arc<OffsetTracker>,
+    pub msg_types: Vec<String>,
+    /// Only `msg_types` are accepted, untyped messages included
+    pub accept_only: bool,
+    pub unknown_type: UnknownTypePolicy,
+    pub idempotency: Option<Arc<dyn IdempotencyStore + Send + Sync>>,
+}
+
+/// A consumer handles one message at a time unless told otherwise, the
+/// dispatcher could never take a delivery with no concurrency at all.
+impl Default for ConsumerDefinition {
+    fn default() -> Self {
+        ConsumerDefinition {
+            name: "",
+            queue: "",
+            msg_type: AmqpMessageType::default(),
+            with_retry: false,
+            retries: 0,
+            with_dlq: false,
+            dlq_name: "",
+            prefetch: 0,
+            priority: None,
+            concurrency: 1,
+            retry_tiers: vec![],
+            retry_jitter: 0.0,
+            stream_offset: None,
+            stream_offset_force: false,
+            offset_store: None,
+            offset_tracker: Arc::default(),
+            msg_types: vec![],
+            accept_only: false,
+            unknown_type: UnknownTypePolicy::default(),
+            idempotency: None,
+        }
+    }
 }
 
 impl ConsumerDefinition {
@@ -145,6 +807,7 @@ impl ConsumerDefinition {
         ConsumerDefinition {
             name,
             retries: 1,
+            prefetch: DEFAULT_PREFETCH,
             ..Default::default()
         }
     }
@@ -169,12 +832,184 @@ impl ConsumerDefinition {
         self.retries = retries;
         self
     }
+
+    /// The DLQ messages are dead-lettered to, `<queue>-dlq` when the
+    /// definition wasn't built from the topology.
+    pub fn dlq(&self) -> String {
+        match self.dlq_name {
+            "" => format!("{}-dlq", self.queue),
+            name => name.to_owned(),
+        }
+    }
+
+    /// Unacked messages held by the consumer. Priority queues only reorder the
+    /// messages not delivered yet, keep it low for urgent ones to overtake.
+    pub fn prefetch(mut self, prefetch: u16) -> Self {
+        self.prefetch = prefetch;
+        self
+    }
+
+    /// The broker delivers to the highest priority consumers of a queue while
+    /// they have prefetch room left, the others only get the overflow.
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// `basic_consume` arguments.
+    pub fn arguments(&self) -> BTreeMap<ShortString, AMQPValue> {
+        let mut args = BTreeMap::new();
+
+        if let Some(priority) = self.priority {
+            args.insert(
+                ShortString::from("x-priority"),
+                AMQPValue::LongInt(LongInt::from(priority)),
+            );
+        }
+
+        args
+    }
+
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Consumes a stream queue starting at `offset` unless the store holds the
+    /// offset last handled by this consumer.
+    pub fn stream(mut self, offset: StreamOffset) -> Self {
+        self.stream_offset = Some(offset);
+        self
+    }
+
+    /// Consumes a stream queue from `offset` even when the store holds one,
+    /// e.g. to backfill from `First` or a `Timestamp`. The stored offset is
+    /// reset on attach, drop the override once the backfill is done or every
+    /// restart reads from `offset` again.
+    pub fn stream_offset_force(mut self, offset: StreamOffset) -> Self {
+        self.stream_offset = Some(offset);
+        self.stream_offset_force = true;
+        self
+    }
+
+    pub fn offset_store(mut self, store: Arc<dyn OffsetStore + Send + Sync>) -> Self {
+        self.offset_store = Some(store);
+        self
+    }
+
+    /// Accepts `msg_type` besides `msg_type`, e.g. for a `TypeRouter`.
+    pub fn accept(mut self, msg_type: &str) -> Self {
+        self.msg_types.push(msg_type.to_owned());
+        self
+    }
+
+    /// Accepts exactly `msg_types`, neither `msg_type` nor untyped messages.
+    pub fn accept_only(mut self, msg_types: Vec<String>) -> Self {
+        self.msg_types = msg_types;
+        self.accept_only = true;
+        self
+    }
+
+    pub fn on_unknown_type(mut self, policy: UnknownTypePolicy) -> Self {
+        self.unknown_type = policy;
+        self
+    }
+
+    /// Skips and acks messages whose id the store already holds.
+    pub fn idempotent(mut self, store: Arc<dyn IdempotencyStore + Send + Sync>) -> Self {
+        self.idempotency = Some(store);
+        self
+    }
+
+    /// Untyped messages are accepted unless `accept_only` is set.
+    pub fn accepts(&self, msg_type: &str) -> bool {
+        if self.msg_types.iter().any(|accepted| accepted == msg_type) {
+            return true;
+        }
+
+        !self.accept_only && (msg_type.is_empty() || msg_type == self.msg_type.to_string())
+    }
+
+    /// Retry queues of each tier, a queue without tiers has the single
+    /// `<queue>-retry` one.
+    pub fn retry_queues(&self) -> Vec<Vec<String>> {
+        if self.retry_tiers.is_empty() {
+            return vec![retry_queue_names(self.queue, &[])];
+        }
+
+        retry_tiers(self.queue, &self.retry_tiers, self.retry_jitter)
+            .into_iter()
+            .map(|tier| tier.into_iter().map(|(name, _)| name).collect())
+            .collect()
+    }
+}
+
+/// TTL holding queues used to publish with a delay without the plugin.
+///
+/// Each delay gets its own `<exchange>.<routing_key>.delay-<ms>` queue, messages
+/// wait there until the TTL expires and are then dead-lettered to `exchange`
+/// with `routing_key`. A queue per delay avoids messages with a long expiration
+/// blocking shorter ones behind them.
+///
+/// Holding queues of the default exchange routed to a queue also serve the
+/// `RetryAfter` delays of its consumers, and count as retries of the queue.
+#[derive(Debug, Clone, Default)]
+pub struct DelayedDefinition {
+    pub exchange: &'static str,
+    pub routing_key: &'static str,
+    pub delays: Vec<Duration>,
+}
+
+impl DelayedDefinition {
+    pub fn new(exchange: &'static str, routing_key: &'static str) -> Self {
+        DelayedDefinition {
+            exchange,
+            routing_key,
+            delays: vec![],
+        }
+    }
+
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delays.push(delay);
+        self
+    }
+
+    pub fn declarations(&self) -> Vec<QueueDeclaration> {
+        self.holding_queues()
+            .into_iter()
+            .map(|(name, ttl)| {
+                let mut args = dead_letter_arguments(self.exchange, self.routing_key);
+                args.insert(
+                    ShortString::from("x-message-ttl"),
+                    AMQPValue::LongLongInt(LongLongInt::from(ttl as i64)),
+                );
+                QueueDeclaration::durable(name, args)
+            })
+            .collect()
+    }
+
+    pub fn holding_queues(&self) -> Vec<(String, u64)> {
+        self.delays
+            .iter()
+            .map(|delay| {
+                (
+                    delay_queue_name(self.exchange, self.routing_key, *delay),
+                    delay.as_millis() as u64,
+                )
+            })
+            .collect()
+    }
+}
+
+pub fn delay_queue_name(exchange: &str, routing_key: &str, delay: Duration) -> String {
+    format!("{}.{}.delay-{}", exchange, routing_key, delay.as_millis())
 }
 
 pub struct AmqpTopology {
     pub exchanges: Vec<ExchangeDefinition>,
     pub queues: Vec<QueueDefinition>,
     pub consumers: Vec<ConsumerDefinition>,
+    pub delayed: Vec<DelayedDefinition>,
 }
 
 impl AmqpTopology {
@@ -183,6 +1018,7 @@ impl AmqpTopology {
             exchanges: vec![],
             queues: vec![],
             consumers: vec![],
+            delayed: vec![],
         }
     }
 
@@ -196,6 +1032,44 @@ impl AmqpTopology {
         self
     }
 
+    pub fn delayed(mut self, delayed: DelayedDefinition) -> Self {
+        self.delayed.push(delayed);
+        self
+    }
+
+    /// Checks the queues and that every binding fits the kind of its exchange
+    /// when the exchange is part of this topology.
+    pub fn validate(&self) -> Result<(), AmqpError> {
+        for queue in self.queues.iter() {
+            queue.validate()?;
+
+            for bind in queue.bindings.iter() {
+                let kind = match self.exchanges.iter().find(|e| e.name == bind.exchange) {
+                    Some(exch) => exch.kind.clone(),
+                    _ => continue,
+                };
+
+                if kind == ExchangeKind::Topic && !is_valid_topic_pattern(bind.routing_key) {
+                    error!("invalid topic pattern: {}", bind.routing_key);
+                    return Err(AmqpError::InvalidTopologyError(
+                        bind.queue.to_owned(),
+                        format!("invalid topic pattern `{}`", bind.routing_key),
+                    ));
+                }
+
+                if kind != ExchangeKind::Headers && bind.x_match.is_some() {
+                    error!("header arguments binding to: {}", bind.exchange);
+                    return Err(AmqpError::InvalidTopologyError(
+                        bind.queue.to_owned(),
+                        format!("`{}` is not a headers exchange", bind.exchange),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn boxed(self) -> Box<Self> {
         Box::new(self)
     }
@@ -215,6 +1089,22 @@ impl AmqpTopology {
                     with_dlq: queue.with_dlq,
                     dlq_name: queue.dlq_name,
                     with_retry: queue.with_retry,
+                    prefetch: queue.prefetch.unwrap_or(DEFAULT_PREFETCH),
+                    priority: queue.consumer_priority,
+                    concurrency: 1,
+                    retry_tiers: queue.retry_tiers,
+                    retry_jitter: queue.retry_jitter,
+                    stream_offset: match queue.queue_type {
+                        QueueType::Stream => Some(StreamOffset::default()),
+                        _ => None,
+                    },
+                    stream_offset_force: false,
+                    offset_store: None,
+                    offset_tracker: Arc::default(),
+                    msg_types: vec![],
+                    accept_only: false,
+                    unknown_type: UnknownTypePolicy::default(),
+                    idempotency: None,
                 });
             }
         }
@@ -225,6 +1115,7 @@ impl AmqpTopology {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use serde::Deserialize;
 
     #[test]
     fn test_queue_binding_definition() {
@@ -235,6 +1126,83 @@ mod tests {
         assert_eq!(def.routing_key, "routingKey");
     }
 
+    #[test]
+    fn test_queue_binding_arguments() {
+        let def = QueueBindingDefinition::new("exchange", "queue", "");
+        assert!(def.arguments().is_empty());
+
+        let def = def.match_all(&[("type", "Temp"), ("device-id", "device")]);
+        let args = def.arguments();
+        assert_eq!(
+            args.get("x-match"),
+            Some(&AMQPValue::LongString(LongString::from("all")))
+        );
+        assert_eq!(
+            args.get("type"),
+            Some(&AMQPValue::LongString(LongString::from("Temp")))
+        );
+        assert_eq!(
+            args.get("device-id"),
+            Some(&AMQPValue::LongString(LongString::from("device")))
+        );
+
+        let def = def.match_any(&[("type", "GPS")]);
+        let args = def.arguments();
+        assert_eq!(args.len(), 2);
+        assert_eq!(
+            args.get("x-match"),
+            Some(&AMQPValue::LongString(LongString::from("any")))
+        );
+    }
+
+    #[test]
+    fn test_topic_pattern() {
+        assert!(is_valid_topic_pattern(""));
+        assert!(is_valid_topic_pattern("#"));
+        assert!(is_valid_topic_pattern("iot.*.temp.#"));
+        assert!(is_valid_topic_pattern("iot.device_1.temp"));
+        assert!(!is_valid_topic_pattern("iot..temp"));
+        assert!(!is_valid_topic_pattern("iot.temp*"));
+        assert!(!is_valid_topic_pattern("iot.#temp"));
+    }
+
+    #[test]
+    fn test_topology_validate() {
+        let topology = AmqpTopology::new()
+            .exchange(ExchangeDefinition::name("topic").topic())
+            .exchange(ExchangeDefinition::name("headers").header())
+            .queue(
+                QueueDefinition::name("queue")
+                    .binding(QueueBindingDefinition::new("topic", "queue", "iot.*.temp"))
+                    .binding(
+                        QueueBindingDefinition::new("headers", "queue", "")
+                            .match_any(&[("type", "Temp")]),
+                    ),
+            );
+        assert!(topology.validate().is_ok());
+
+        let topology = AmqpTopology::new()
+            .exchange(ExchangeDefinition::name("topic").topic())
+            .queue(
+                QueueDefinition::name("queue").binding(QueueBindingDefinition::new(
+                    "topic",
+                    "queue",
+                    "iot.temp*",
+                )),
+            );
+        assert!(topology.validate().is_err());
+
+        let topology = AmqpTopology::new()
+            .exchange(ExchangeDefinition::name("direct").direct())
+            .queue(
+                QueueDefinition::name("queue").binding(
+                    QueueBindingDefinition::new("direct", "queue", "key")
+                        .match_all(&[("type", "Temp")]),
+                ),
+            );
+        assert!(topology.validate().is_err());
+    }
+
     #[test]
     fn test_queue_definition() {
         let def = QueueDefinition::name("name");
@@ -249,11 +1217,185 @@ mod tests {
         assert_eq!(def.retries, Some(3));
         assert_eq!(def.retry_ttl, Some(1000));
 
+        assert_eq!(def.retry_queues(), vec![("name-retry".to_owned(), 1000)]);
+
+        let def = def.with_backoff(&[1000, 10000, 60000], 5);
+        assert_eq!(def.retries, Some(5));
+        assert_eq!(def.retry_ttl, Some(1000));
+        assert_eq!(
+            def.retry_queues(),
+            vec![
+                ("name-retry-1000".to_owned(), 1000),
+                ("name-retry-10000".to_owned(), 10000),
+                ("name-retry-60000".to_owned(), 60000),
+            ]
+        );
+
+        let def = def.with_backoff(&[1500, 3000], 5).with_jitter(0.5);
+        assert_eq!(
+            def.retry_queues(),
+            vec![
+                ("name-retry-1500".to_owned(), 1500),
+                ("name-retry-1250".to_owned(), 1250),
+                ("name-retry-1000".to_owned(), 1000),
+                ("name-retry-750".to_owned(), 750),
+                ("name-retry-3000".to_owned(), 3000),
+                ("name-retry-2500".to_owned(), 2500),
+                ("name-retry-2000".to_owned(), 2000),
+            ]
+        );
+        assert!(def.validate().is_ok());
+
+        let def = def.with_jitter(2.0);
+        assert_eq!(def.retry_jitter, 1.0);
+        assert!(def.retry_queues().contains(&("name-retry-0".to_owned(), 0)));
+        assert!(def.validate().is_err());
+        assert!(def
+            .clone()
+            .with_backoff(&[1], 5)
+            .with_jitter(0.9)
+            .validate()
+            .is_err());
+
         let binding = QueueBindingDefinition::new("exchange", "queue", "routing_key");
         let def = def.binding(binding);
         assert_eq!(def.bindings[0].exchange, binding.exchange);
     }
 
+    #[test]
+    fn test_queue_arguments() {
+        let def = QueueDefinition::name("queue");
+        assert!(def.durable);
+        assert!(def.arguments().is_empty());
+
+        let def = def
+            .quorum()
+            .message_ttl(1000)
+            .max_length(10)
+            .max_length_bytes(1024)
+            .overflow(QueueOverflow::RejectPublish)
+            .single_active_consumer();
+        let args = def.arguments();
+        assert_eq!(
+            args.get("x-queue-type"),
+            Some(&AMQPValue::LongString(LongString::from("quorum")))
+        );
+        assert_eq!(args.get("x-message-ttl"), Some(&AMQPValue::LongInt(1000)));
+        assert_eq!(args.get("x-max-length"), Some(&AMQPValue::LongLongInt(10)));
+        assert_eq!(
+            args.get("x-max-length-bytes"),
+            Some(&AMQPValue::LongLongInt(1024))
+        );
+        assert_eq!(
+            args.get("x-overflow"),
+            Some(&AMQPValue::LongString(LongString::from("reject-publish")))
+        );
+        assert_eq!(
+            args.get("x-single-active-consumer"),
+            Some(&AMQPValue::Boolean(true))
+        );
+        assert!(def.validate().is_ok());
+
+        let def = QueueDefinition::name("queue").lazy().max_priority(5);
+        let args = def.arguments();
+        assert_eq!(
+            args.get("x-queue-mode"),
+            Some(&AMQPValue::LongString(LongString::from("lazy")))
+        );
+        assert_eq!(
+            args.get("x-max-priority"),
+            Some(&AMQPValue::ShortShortUInt(5))
+        );
+        assert!(def.validate().is_ok());
+    }
+
+    #[test]
+    fn test_queue_declarations() {
+        let declarations = QueueDefinition::name("queue").declarations();
+        assert_eq!(declarations.len(), 1);
+        assert_eq!(declarations[0].name, "queue");
+        assert!(declarations[0].arguments.is_empty());
+
+        let declarations = QueueDefinition::name("queue").with_dlq().declarations();
+        assert_eq!(declarations.len(), 2);
+        assert_eq!(declarations[0].name, "queue-dlq");
+        assert_eq!(
+            declarations[1].arguments.get("x-dead-letter-routing-key"),
+            Some(&AMQPValue::LongString(LongString::from("queue-dlq")))
+        );
+
+        let declarations = QueueDefinition::name("queue")
+            .with_dlq()
+            .with_backoff(&[1000, 10000], 3)
+            .max_length(10)
+            .declarations();
+        let names = declarations
+            .iter()
+            .map(|d| d.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "queue-retry-1000",
+                "queue-retry-10000",
+                "queue-dlq",
+                "queue"
+            ]
+        );
+        assert_eq!(
+            declarations[1].arguments.get("x-message-ttl"),
+            Some(&AMQPValue::LongInt(10000))
+        );
+        assert_eq!(
+            declarations[1].arguments.get("x-dead-letter-routing-key"),
+            Some(&AMQPValue::LongString(LongString::from("queue")))
+        );
+        assert_eq!(
+            declarations[3].arguments.get("x-dead-letter-routing-key"),
+            Some(&AMQPValue::LongString(LongString::from("queue-retry-1000")))
+        );
+        assert_eq!(
+            declarations[3].arguments.get("x-max-length"),
+            Some(&AMQPValue::LongLongInt(10))
+        );
+    }
+
+    #[test]
+    fn test_queue_validate() {
+        let def = QueueDefinition::name("queue")
+            .transient()
+            .exclusive()
+            .auto_delete();
+        assert!(!def.durable && def.exclusive && def.auto_delete);
+        assert!(def.validate().is_ok());
+
+        assert!(def.quorum().validate().is_err());
+        assert!(QueueDefinition::name("queue")
+            .quorum()
+            .lazy()
+            .validate()
+            .is_err());
+        assert!(QueueDefinition::name("queue")
+            .stream()
+            .with_dlq()
+            .validate()
+            .is_err());
+        assert!(QueueDefinition::name("queue")
+            .stream()
+            .max_priority(5)
+            .validate()
+            .is_err());
+        assert!(QueueDefinition::name("queue").stream().validate().is_ok());
+        assert!(QueueDefinition::name("queue")
+            .overflow(QueueOverflow::RejectPublishDlx)
+            .validate()
+            .is_err());
+        assert!(QueueDefinition::name("queue")
+            .with_backoff(&[], 3)
+            .validate()
+            .is_err());
+    }
+
     #[test]
     fn test_exchange_kind() {
         let kind = ExchangeKind::Direct;
@@ -276,6 +1418,49 @@ mod tests {
 
         let def = def.header();
         assert_eq!(def.kind, ExchangeKind::Headers);
+        assert_eq!(def.kind.as_str(), "headers");
+
+        assert!(!def.delayed);
+        let def = def.delayed();
+        assert!(def.delayed);
+        assert_eq!(
+            def.arguments().get("x-delayed-type"),
+            Some(&AMQPValue::LongString(LongString::from("headers")))
+        );
+
+        assert!(def.durable && !def.auto_delete && !def.internal);
+        let def = ExchangeDefinition::name("exchange")
+            .transient()
+            .auto_delete()
+            .internal()
+            .alternate_exchange("unrouted")
+            .binding(ExchangeBindingDefinition::new("exchange", "other", "key"));
+        assert!(!def.durable && def.auto_delete && def.internal);
+        assert_eq!(
+            def.arguments().get("alternate-exchange"),
+            Some(&AMQPValue::LongString(LongString::from("unrouted")))
+        );
+        assert_eq!(def.bindings[0].source, "exchange");
+        assert_eq!(def.bindings[0].destination, "other");
+        assert_eq!(def.bindings[0].routing_key, "key");
+    }
+
+    #[test]
+    fn test_delayed_definition() {
+        let def = DelayedDefinition::new("exchange", "key")
+            .delay(Duration::from_secs(30))
+            .delay(Duration::from_millis(500));
+
+        assert_eq!(
+            def.holding_queues(),
+            vec![
+                ("exchange.key.delay-30000".to_owned(), 30000),
+                ("exchange.key.delay-500".to_owned(), 500),
+            ]
+        );
+
+        let topology = AmqpTopology::new().delayed(def);
+        assert_eq!(topology.delayed[0].exchange, "exchange");
     }
 
     #[test]
@@ -292,6 +1477,52 @@ mod tests {
         let def = def.with_retry(3);
         assert!(def.with_retry);
         assert_eq!(def.retries, 3);
+
+        assert_eq!(def.prefetch, DEFAULT_PREFETCH);
+        let def = def.prefetch(50);
+        assert_eq!(def.prefetch, 50);
+
+        assert!(def.arguments().is_empty());
+        let def = def.priority(5);
+        assert_eq!(
+            def.arguments().get("x-priority"),
+            Some(&AMQPValue::LongInt(5))
+        );
+
+        assert_eq!(ConsumerDefinition::default().concurrency, 1);
+        assert_eq!(def.concurrency, 1);
+        let def = def.concurrency(0);
+        assert_eq!(def.concurrency, 1);
+        let def = def.concurrency(5);
+        assert_eq!(def.concurrency, 5);
+
+        assert!(def.stream_offset.is_none());
+        let def = def
+            .stream(StreamOffset::First)
+            .offset_store(crate::amqp::stream::InMemoryOffsetStore::new());
+        assert_eq!(def.stream_offset, Some(StreamOffset::First));
+        assert!(def.offset_store.is_some());
+        assert!(!def.stream_offset_force);
+        let def = def.stream_offset_force(StreamOffset::Timestamp(1660000000));
+        assert_eq!(def.stream_offset, Some(StreamOffset::Timestamp(1660000000)));
+        assert!(def.stream_offset_force);
+
+        let topology = AmqpTopology::new().queue(QueueDefinition::name("telemetry").stream());
+        let def = topology.get_consumers_def("telemetry").unwrap();
+        assert_eq!(def.stream_offset, Some(StreamOffset::Next));
+
+        let def = def.msg_type(AmqpMessageType::Temp);
+        assert!(def.accepts("") && def.accepts("Temp"));
+        assert!(!def.accepts("GPS"));
+        assert_eq!(def.unknown_type, UnknownTypePolicy::Dlq);
+
+        let def = def.accept("GPS").on_unknown_type(UnknownTypePolicy::Drop);
+        assert!(def.accepts("GPS"));
+        assert_eq!(def.unknown_type, UnknownTypePolicy::Drop);
+
+        let def = def.accept_only(vec!["Shadow".to_owned()]);
+        assert!(def.accepts("Shadow"));
+        assert!(!def.accepts("") && !def.accepts("Temp") && !def.accepts("GPS"));
     }
 
     #[test]
@@ -312,5 +1543,48 @@ mod tests {
         let queue_def = QueueDefinition::name("queue").with_retry(1000, 3);
         let _topology = topology.queue(queue_def.clone());
         assert!(consumer_def.is_some());
+
+        let topology = AmqpTopology::new()
+            .queue(QueueDefinition::name("queue").with_backoff(&[1000, 10000], 3));
+        let consumer_def = topology.get_consumers_def("queue").unwrap();
+        assert_eq!(
+            consumer_def.retry_queues(),
+            vec![
+                vec!["queue-retry-1000".to_owned()],
+                vec!["queue-retry-10000".to_owned()]
+            ]
+        );
+    }
+
+    #[derive(Deserialize)]
+    struct Msg {
+        value: i32,
+    }
+
+    struct MsgHandler;
+
+    #[async_trait]
+    impl TypedConsumerHandler<Msg> for MsgHandler {
+        async fn exec(&self, _ctx: &Context, msg: &Msg) -> Result<(), HandlerError> {
+            match msg.value {
+                1 => Ok(()),
+                _ => Err(HandlerError::Retry("retry".to_owned())),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_typed_consumer() {
+        let handler = TypedConsumer::new(Arc::new(MsgHandler {}));
+        let ctx = Context::new();
+
+        let res = handler.exec(&ctx, b"{\"value\": 1}").await;
+        assert!(res.is_ok());
+
+        let res = handler.exec(&ctx, b"{\"value\": 2}").await;
+        assert_eq!(res, Err(HandlerError::Retry("retry".to_owned())));
+
+        let res = handler.exec(&ctx, b"{\"wrong\": true}").await;
+        assert!(matches!(res, Err(HandlerError::Reject(_))));
     }
 }
@@ -1,16 +1,86 @@
+use super::{
+    compression::Compression,
+    envelope::{Envelope, DEFAULT_CONTENT_TYPE, DEFAULT_SCHEMA_VERSION},
+};
 use crate::errors::AmqpError;
-use lapin::types::FieldTable;
-use serde::Serialize;
-use std::fmt::Display;
+use lapin::{
+    protocol::basic::AMQPProperties,
+    types::{AMQPValue, FieldTable},
+};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, collections::BTreeMap, fmt::Display, time::Duration};
+
+pub const DEFAULT_CONFIRM_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct XDeath {
+    pub queue: String,
+    pub reason: String,
+    pub count: i64,
+    pub exchange: String,
+    pub routing_keys: Vec<String>,
+    pub time: Option<u64>,
+}
+
+impl XDeath {
+    fn extract(table: &FieldTable) -> XDeath {
+        let string = |key: &str| match table.inner().get(key) {
+            Some(value) => match value.as_long_string() {
+                Some(st) => st.to_string(),
+                _ => "".to_owned(),
+            },
+            _ => "".to_owned(),
+        };
+
+        let routing_keys = match table.inner().get("routing-keys") {
+            Some(value) => match value.as_array() {
+                Some(arr) => arr
+                    .as_slice()
+                    .iter()
+                    .filter_map(|key| key.as_long_string().map(|st| st.to_string()))
+                    .collect(),
+                _ => vec![],
+            },
+            _ => vec![],
+        };
+
+        XDeath {
+            queue: string("queue"),
+            reason: string("reason"),
+            count: match table.inner().get("count") {
+                Some(value) => value.as_long_long_int().unwrap_or(0),
+                _ => 0,
+            },
+            exchange: string("exchange"),
+            routing_keys,
+            time: table.inner().get("time").and_then(AMQPValue::as_timestamp),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Metadata {
     pub msg_type: String,
     pub count: i64,
     pub traceparent: String,
+    pub deaths: Vec<XDeath>,
+    pub envelope: Envelope,
 }
 
 impl Metadata {
+    /// Reads the envelope from the properties as well, falling back to the
+    /// `type` property when the `type` header is missing.
+    pub fn from_properties(properties: &AMQPProperties) -> Metadata {
+        let header = properties.headers().clone().unwrap_or_default();
+        let envelope = Envelope::from_properties(properties);
+
+        Metadata {
+            msg_type: envelope.msg_type.clone(),
+            envelope,
+            ..Metadata::extract(&header)
+        }
+    }
+
     pub fn extract(header: &FieldTable) -> Metadata {
         let count = match header.inner().get("x-death") {
             Some(value) => match value.as_array() {
@@ -48,15 +118,46 @@ impl Metadata {
             _ => "".to_owned(),
         };
 
+        let deaths = match header.inner().get("x-death") {
+            Some(value) => match value.as_array() {
+                Some(arr) => arr
+                    .as_slice()
+                    .iter()
+                    .filter_map(|value| value.as_field_table().map(XDeath::extract))
+                    .collect(),
+                _ => vec![],
+            },
+            _ => vec![],
+        };
+
         Metadata {
             msg_type,
             count,
             traceparent,
+            deaths,
+            envelope: Envelope::from_headers(header),
         }
     }
+
+    /// How many times the message already went through the retry queues of
+    /// `queue`, or the delay holding queues routing to it from the default
+    /// exchange, named as `delay_queue_name("", queue, ..)`.
+    pub fn attempts(&self, queue: &str) -> i64 {
+        let retry = format!("{}-retry", queue);
+        let delayed = format!(".{}.delay-", queue);
+
+        self.deaths
+            .iter()
+            .filter(|death| {
+                death.reason == "expired"
+                    && (death.queue.starts_with(&retry) || death.queue.starts_with(&delayed))
+            })
+            .map(|death| death.count)
+            .sum()
+    }
 }
 
-#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
 pub enum AmqpMessageType {
     #[default]
     MQTTMsg,
@@ -78,6 +179,24 @@ pub trait PublishPayload {
 pub struct PublishData {
     pub payload: Box<[u8]>,
     pub msg_type: String,
+    pub mandatory: bool,
+    pub confirm: Option<Duration>,
+    pub delay: Option<Duration>,
+    pub headers: BTreeMap<String, String>,
+    pub correlation_id: Option<String>,
+    pub reply_to: Option<String>,
+    /// Generated on publish when not set
+    pub message_id: Option<String>,
+    pub schema_version: u16,
+    /// The app name of the publisher when not set
+    pub producer: Option<String>,
+    pub device_id: Option<String>,
+    pub content_type: String,
+    /// Only honored by queues declared with `x-max-priority`
+    pub priority: Option<u8>,
+    pub compression: Option<Compression>,
+    /// Payloads up to this size are sent uncompressed
+    pub compress_above: usize,
 }
 
 impl PublishData {
@@ -89,10 +208,135 @@ impl PublishData {
             .map_err(|_| AmqpError::ParsePayloadError {})?
             .into_boxed_slice();
 
-        Ok(PublishData {
-            msg_type: payload.get_type().to_string(),
-            payload: serialized,
-        })
+        Ok(PublishData::raw(
+            &payload.get_type().to_string(),
+            serialized.into_vec(),
+        ))
+    }
+
+    /// Payload already serialized, e.g. RPC replies.
+    pub fn raw(msg_type: &str, payload: Vec<u8>) -> Self {
+        PublishData {
+            msg_type: msg_type.to_owned(),
+            payload: payload.into_boxed_slice(),
+            mandatory: false,
+            confirm: None,
+            delay: None,
+            headers: BTreeMap::new(),
+            correlation_id: None,
+            reply_to: None,
+            message_id: None,
+            schema_version: DEFAULT_SCHEMA_VERSION,
+            producer: None,
+            device_id: None,
+            content_type: DEFAULT_CONTENT_TYPE.to_owned(),
+            priority: None,
+            compression: None,
+            compress_above: 0,
+        }
+    }
+
+    /// Ask the broker to return the message if it can't be routed to any queue.
+    /// Returned messages are only observable through publisher confirms, so
+    /// this also enables them with the default timeout when none was set.
+    pub fn mandatory(mut self) -> Self {
+        self.mandatory = true;
+        if self.confirm.is_none() {
+            self.confirm = Some(DEFAULT_CONFIRM_TIMEOUT);
+        }
+        self
+    }
+
+    /// Wait for the broker ack/nack for this message, failing after `timeout`.
+    pub fn with_confirm(mut self, timeout: Duration) -> Self {
+        self.confirm = Some(timeout);
+        self
+    }
+
+    /// Deliver the message only after `delay`. The exchange must be declared
+    /// as delayed or the topology must hold a `DelayedDefinition` for this
+    /// exact exchange, routing key and delay, and be installed or registered
+    /// with the client.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// Custom header, used by headers exchange bindings to route the message.
+    pub fn header(mut self, key: &str, value: &str) -> Self {
+        self.headers.insert(key.to_owned(), value.to_owned());
+        self
+    }
+
+    pub fn correlation_id(mut self, id: &str) -> Self {
+        self.correlation_id = Some(id.to_owned());
+        self
+    }
+
+    pub fn reply_to(mut self, queue: &str) -> Self {
+        self.reply_to = Some(queue.to_owned());
+        self
+    }
+
+    pub fn message_id(mut self, id: &str) -> Self {
+        self.message_id = Some(id.to_owned());
+        self
+    }
+
+    /// Version of the payload schema, consumers pick the handler for it.
+    pub fn schema_version(mut self, version: u16) -> Self {
+        self.schema_version = version;
+        self
+    }
+
+    pub fn producer(mut self, producer: &str) -> Self {
+        self.producer = Some(producer.to_owned());
+        self
+    }
+
+    pub fn device_id(mut self, device_id: &str) -> Self {
+        self.device_id = Some(device_id.to_owned());
+        self
+    }
+
+    pub fn content_type(mut self, content_type: &str) -> Self {
+        self.content_type = content_type.to_owned();
+        self
+    }
+
+    /// Higher priorities are delivered first, values above the queue
+    /// `x-max-priority` are treated as the max.
+    pub fn priority(mut self, priority: u8) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Compress payloads bigger than `threshold` bytes, consumers decompress
+    /// them before calling the handler.
+    pub fn compress(mut self, compression: Compression, threshold: usize) -> Self {
+        self.compression = Some(compression);
+        self.compress_above = threshold;
+        self
+    }
+
+    /// `content_encoding` of the body, none when sent uncompressed.
+    pub fn encoding(&self) -> Option<&'static str> {
+        match self.compression {
+            Some(compression) if self.payload.len() > self.compress_above => {
+                Some(compression.encoding())
+            }
+            _ => None,
+        }
+    }
+
+    /// Payload as sent to the broker.
+    pub fn body(&self) -> Result<Cow<'_, [u8]>, AmqpError> {
+        match self.compression {
+            Some(compression) if self.encoding().is_some() => {
+                compression.compress(&self.payload).map(Cow::Owned)
+            }
+            _ => Ok(Cow::Borrowed(&self.payload)),
+        }
     }
 }
 
@@ -132,6 +376,56 @@ mod tests {
         assert_eq!(re.msg_type, "msg_type");
     }
 
+    fn x_death(queue: &str, reason: &str, count: i64) -> AMQPValue {
+        let mut death = BTreeMap::new();
+        death.insert(
+            ShortString::from("queue"),
+            AMQPValue::LongString(LongString::from(queue)),
+        );
+        death.insert(
+            ShortString::from("reason"),
+            AMQPValue::LongString(LongString::from(reason)),
+        );
+        death.insert(ShortString::from("count"), AMQPValue::LongLongInt(count));
+        death.insert(
+            ShortString::from("exchange"),
+            AMQPValue::LongString(LongString::from("")),
+        );
+        death.insert(
+            ShortString::from("routing-keys"),
+            AMQPValue::FieldArray(FieldArray::from(vec![AMQPValue::LongString(
+                LongString::from(queue),
+            )])),
+        );
+        death.insert(ShortString::from("time"), AMQPValue::Timestamp(1000));
+
+        AMQPValue::FieldTable(FieldTable::from(death))
+    }
+
+    #[test]
+    fn test_metadata_attempts() {
+        let mut metadata = BTreeMap::new();
+        metadata.insert(
+            ShortString::from("x-death"),
+            AMQPValue::FieldArray(FieldArray::from(vec![
+                x_death("queue-retry-10000", "expired", 1),
+                x_death("queue-retry-1000", "expired", 1),
+                x_death("queue", "rejected", 2),
+                x_death("other-retry", "expired", 3),
+                x_death(".queue.delay-5000", "expired", 1),
+            ])),
+        );
+
+        let re = Metadata::extract(&FieldTable::from(metadata));
+        assert_eq!(re.deaths.len(), 5);
+        assert_eq!(re.deaths[0].queue, "queue-retry-10000");
+        assert_eq!(re.deaths[0].routing_keys, vec!["queue-retry-10000"]);
+        assert_eq!(re.deaths[0].time, Some(1000));
+        assert_eq!(re.attempts("queue"), 3);
+        assert_eq!(re.attempts("other"), 3);
+        assert_eq!(re.attempts("unknown"), 0);
+    }
+
     #[test]
     fn test_metadata_extract_wrong() {
         let mut metadata = BTreeMap::new();
@@ -160,4 +454,95 @@ mod tests {
         assert_eq!(re.count, 0);
         assert_eq!(re.traceparent, "");
     }
+
+    #[test]
+    fn test_metadata_from_properties() {
+        let properties = AMQPProperties::default()
+            .with_message_id(ShortString::from("id"))
+            .with_kind(ShortString::from("Temp"));
+
+        let re = Metadata::from_properties(&properties);
+        assert_eq!(re.msg_type, "Temp");
+        assert_eq!(re.envelope.message_id, "id");
+        assert_eq!(re.envelope.schema_version, DEFAULT_SCHEMA_VERSION);
+    }
+
+    #[derive(Serialize)]
+    struct Payload {}
+
+    impl PublishPayload for Payload {
+        fn get_type(&self) -> AmqpMessageType {
+            AmqpMessageType::Temp
+        }
+    }
+
+    #[test]
+    fn test_publish_data_confirm_options() {
+        let data = PublishData::new(Payload {}).unwrap();
+        assert!(!data.mandatory);
+        assert_eq!(data.confirm, None);
+
+        let data = data.mandatory();
+        assert!(data.mandatory);
+        assert_eq!(data.confirm, Some(DEFAULT_CONFIRM_TIMEOUT));
+
+        let data = PublishData::new(Payload {})
+            .unwrap()
+            .with_confirm(Duration::from_millis(100))
+            .mandatory();
+        assert!(data.mandatory);
+        assert_eq!(data.confirm, Some(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn test_publish_data_delay() {
+        let data = PublishData::new(Payload {}).unwrap();
+        assert_eq!(data.delay, None);
+
+        let data = data.delay(Duration::from_secs(30));
+        assert_eq!(data.delay, Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_publish_data_envelope() {
+        let data = PublishData::new(Payload {}).unwrap();
+        assert_eq!(data.msg_type, "Temp");
+        assert_eq!(data.schema_version, DEFAULT_SCHEMA_VERSION);
+        assert_eq!(data.content_type, DEFAULT_CONTENT_TYPE);
+        assert_eq!(data.message_id, None);
+
+        let data = data
+            .message_id("id")
+            .schema_version(2)
+            .producer("amqp")
+            .device_id("device");
+        assert_eq!(data.message_id, Some("id".to_owned()));
+        assert_eq!(data.schema_version, 2);
+        assert_eq!(data.producer, Some("amqp".to_owned()));
+        assert_eq!(data.device_id, Some("device".to_owned()));
+        assert_eq!(data.priority, None);
+        assert_eq!(data.priority(9).priority, Some(9));
+    }
+
+    #[test]
+    fn test_publish_data_body() {
+        let data = PublishData::raw("Temp", b"{}".repeat(100));
+        assert_eq!(data.body().unwrap().len(), 200);
+        assert_eq!(data.encoding(), None);
+
+        let data = data.compress(Compression::Zstd, 200);
+        assert_eq!(data.encoding(), None);
+
+        let data = data.compress(Compression::Zstd, 100);
+        assert!(data.body().unwrap().len() < 200);
+        assert_eq!(data.encoding(), Some("zstd"));
+    }
+
+    #[test]
+    fn test_publish_data_header() {
+        let data = PublishData::new(Payload {})
+            .unwrap()
+            .header("device-id", "device");
+        assert_eq!(data.headers.get("device-id"), Some(&"device".to_owned()));
+    }
 }
@@ -7,6 +7,17 @@ pub enum Environment {
     Prod,
 }
 
+/// Bus the services publish device data to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum MessagingBackend {
+    #[default]
+    Amqp,
+    /// Requires the `kafka` feature
+    Kafka,
+    /// NATS JetStream, requires the `nats` feature
+    Nats,
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Config {
     pub app_name: &'static str,
@@ -26,6 +37,17 @@ pub struct Config {
     pub amqp_user: &'static str,
     pub amqp_password: &'static str,
     pub amqp_vhost: &'static str,
+    pub amqp_publisher_channels: usize,
+    /// Bytes after compression, bigger publishes fail. RabbitMQ rejects
+    /// messages over 128MiB unless its `max_message_size` is raised
+    pub amqp_max_message_size: usize,
+
+    pub messaging_backend: MessagingBackend,
+    /// Comma separated `host:port` list
+    pub kafka_brokers: &'static str,
+    pub nats_url: &'static str,
+    /// JetStream stream holding the subscribed subjects
+    pub nats_stream: &'static str,
 
     pub otlp_host: &'static str,
     pub otlp_key: &'static str,
@@ -59,6 +81,13 @@ impl Config {
             amqp_user: "admin",
             amqp_password: "password",
             amqp_vhost: "",
+            amqp_publisher_channels: 4,
+            amqp_max_message_size: 128 * 1024 * 1024,
+
+            messaging_backend: MessagingBackend::Amqp,
+            kafka_brokers: "localhost:9092",
+            nats_url: "localhost:4222",
+            nats_stream: "IOT",
 
             otlp_host: "https://otlp.nr-data.net:4317",
             otlp_key: "e84b3e41a69635447392533e627aac0c56c5NRAL",
@@ -109,6 +138,12 @@ impl Config {
             amqp_user: "admin",
             amqp_password: "password",
             amqp_vhost: "",
+            amqp_publisher_channels: 2,
+            amqp_max_message_size: 1024 * 1024,
+            messaging_backend: MessagingBackend::Amqp,
+            kafka_brokers: "localhost:9092",
+            nats_url: "localhost:4222",
+            nats_stream: "IOT",
             otlp_host: "https://otlp.nr-data.net:4317",
             otlp_key: "some_key",
             otlp_service_type: "MQTT",
@@ -1,3 +1,3 @@
 mod configs;
 
-pub use configs::{Config, Environment};
+pub use configs::{Config, Environment, MessagingBackend};
@@ -37,4 +37,67 @@ pub enum AmqpError {
 
     #[error("failure to publish to dlq")]
     PublishingToDQLError,
+
+    #[error("message was returned by the broker `{0}` - `{1}`")]
+    ReturnedMessageError(u16, String),
+
+    #[error("message was nacked by the broker")]
+    PublishNackedError,
+
+    #[error("timeout waiting for publisher confirm")]
+    ConfirmTimeoutError,
+
+    #[error("failure to cancel consumer `{0}`")]
+    CancelConsumerError(String),
+
+    #[error("consumer `{0}` is already running")]
+    DuplicateConsumerError(String),
+
+    #[error("failure to close the connection")]
+    CloseError,
+
+    #[error("no delayed exchange or holding queue declared for `{0}`")]
+    DelayNotDeclaredError(String),
+
+    #[error("failure to fetch messages from `{0}`")]
+    FetchMessageError(String),
+
+    #[error("failure to binding exchange `{0}` to exchange `{1}`")]
+    BindingExchangeToExchangeError(String, String),
+
+    #[error("invalid topology definition `{0}` - {1}")]
+    InvalidTopologyError(String, String),
+
+    #[error("failure to load the topology file - {0}")]
+    TopologyFileError(String),
+
+    #[error("failure to inspect `{0}` on the broker")]
+    InspectTopologyError(String),
+
+    #[error("failure to delete queue `{0}`")]
+    DeleteQueueError(String),
+
+    #[error("unsafe topology changes `{0}`")]
+    UnsafeTopologyChangeError(String),
+
+    #[error("failure to load or save the stream offset of `{0}`")]
+    OffsetStoreError(String),
+
+    #[error("rpc request failed - {0}")]
+    RpcError(String),
+
+    #[error("timeout waiting for the rpc reply")]
+    RpcTimeoutError,
+
+    #[error("failure to check or mark the processed message `{0}`")]
+    IdempotencyStoreError(String),
+
+    #[error("message of `{0}` bytes exceeds the max size of `{1}` bytes")]
+    MessageTooLargeError(usize, usize),
+
+    #[error("failure to compress or decompress with `{0}`")]
+    CompressionError(String),
+
+    #[error("outbox failure `{0}`")]
+    OutboxError(String),
 }
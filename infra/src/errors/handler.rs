@@ -0,0 +1,46 @@
+use super::AmqpError;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Failure returned by consumer handlers, classifying what should happen with
+/// the message.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum HandlerError {
+    #[error("retryable failure `{0}`")]
+    Retry(String),
+
+    #[error("retryable failure after `{0:?}` - `{1}`")]
+    RetryAfter(Duration, String),
+
+    #[error("unprocessable message `{0}`")]
+    Reject(String),
+
+    #[error("message dropped `{0}`")]
+    Drop(String),
+}
+
+impl From<AmqpError> for HandlerError {
+    fn from(err: AmqpError) -> Self {
+        match err {
+            AmqpError::ParsePayloadError => HandlerError::Reject(err.to_string()),
+            _ => HandlerError::Retry(err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_amqp_error() {
+        assert_eq!(
+            HandlerError::from(AmqpError::ParsePayloadError {}),
+            HandlerError::Reject("failure to parse payload".to_owned())
+        );
+        assert_eq!(
+            HandlerError::from(AmqpError::PublishingError {}),
+            HandlerError::Retry("failure to publish".to_owned())
+        );
+    }
+}
@@ -0,0 +1,23 @@
+use super::AmqpError;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum MessagingError {
+    #[error("failure to connect to `{0}`")]
+    ConnectionError(String),
+
+    #[error("failure to publish to `{0}`")]
+    PublishingError(String),
+
+    #[error("failure to subscribe to `{0}`")]
+    SubscribeError(String),
+
+    #[error("failure to parse payload")]
+    ParsePayloadError,
+
+    #[error("messaging backend `{0}` is not enabled")]
+    UnsupportedBackendError(String),
+
+    #[error("amqp failure - {0}")]
+    AmqpError(#[from] AmqpError),
+}
@@ -1,9 +1,13 @@
 mod amqp;
+mod handler;
 mod logging;
+mod messaging;
 mod mqtt;
 mod repositories;
 
 pub use amqp::AmqpError;
+pub use handler::HandlerError;
 pub use logging::LoggingError;
+pub use messaging::MessagingError;
 pub use mqtt::MqttError;
 pub use repositories::RepositoriesError;
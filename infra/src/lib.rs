@@ -3,6 +3,7 @@ pub mod database;
 pub mod env;
 pub mod errors;
 pub mod logging;
+pub mod messaging;
 pub mod mqtt;
 pub mod otel;
 pub mod repositories;
@@ -0,0 +1,170 @@
+use super::{
+    client::{MessageHandler, Publisher, Subscriber},
+    types::Message,
+};
+use crate::{
+    amqp::{
+        client::IAmqp,
+        dispatcher::AmqpDispatcher,
+        envelope::{Envelope, DEVICE_ID_HEADER, TYPE_HEADER},
+        router::{DeliveryHeaders, DeliveryType},
+        topology::{AmqpTopology, ConsumerHandler},
+        types::PublishData,
+    },
+    errors::{HandlerError, MessagingError},
+    otel::semconv,
+};
+use async_trait::async_trait;
+use opentelemetry::{baggage::BaggageExt, Context};
+use std::sync::Arc;
+
+/// Publishes to the exchange named by the topic with the message key as the
+/// routing key.
+pub struct AmqpPublisher {
+    amqp: Arc<dyn IAmqp + Send + Sync>,
+}
+
+impl AmqpPublisher {
+    pub fn new(amqp: Arc<dyn IAmqp + Send + Sync>) -> Arc<dyn Publisher + Send + Sync> {
+        Arc::new(AmqpPublisher { amqp })
+    }
+}
+
+/// The device id falls back to the one the MQTT bridge put in the baggage.
+fn publish_data(ctx: &Context, msg: &Message) -> PublishData {
+    let mut data = PublishData::raw(&msg.msg_type, msg.payload.clone());
+    data.headers = msg.headers.clone();
+    data.device_id = msg
+        .headers
+        .get(DEVICE_ID_HEADER)
+        .cloned()
+        .or_else(|| Some(ctx.baggage().get(semconv::DEVICE_ID)?.to_string()));
+    data
+}
+
+#[async_trait]
+impl Publisher for AmqpPublisher {
+    async fn publish(
+        &self,
+        ctx: &Context,
+        topic: &str,
+        msg: &Message,
+    ) -> Result<(), MessagingError> {
+        let key = msg.key.as_deref().unwrap_or_default();
+
+        self.amqp
+            .publish(ctx, topic, key, &publish_data(ctx, msg))
+            .await
+            .map_err(MessagingError::from)
+    }
+}
+
+/// Consumes the queue named by the topic with the consumer definition of the
+/// topology, so retries and DLQ work as with `AmqpDispatcher`.
+pub struct AmqpSubscriber {
+    amqp: Arc<dyn IAmqp + Send + Sync>,
+    topology: Box<AmqpTopology>,
+}
+
+impl AmqpSubscriber {
+    pub fn new(
+        amqp: Arc<dyn IAmqp + Send + Sync>,
+        topology: Box<AmqpTopology>,
+    ) -> Arc<dyn Subscriber + Send + Sync> {
+        Arc::new(AmqpSubscriber { amqp, topology })
+    }
+}
+
+#[async_trait]
+impl Subscriber for AmqpSubscriber {
+    async fn subscribe(
+        &self,
+        topic: &str,
+        handler: Arc<dyn MessageHandler + Send + Sync>,
+    ) -> Result<(), MessagingError> {
+        let def = self
+            .topology
+            .get_consumers_def(topic)
+            .ok_or_else(|| MessagingError::SubscribeError(topic.to_owned()))?;
+
+        AmqpDispatcher::new(self.amqp.clone())
+            .register(def, Arc::new(MessageConsumer { handler }))
+            .run()
+            .await
+            .map_err(MessagingError::from)
+    }
+}
+
+struct MessageConsumer {
+    handler: Arc<dyn MessageHandler + Send + Sync>,
+}
+
+#[async_trait]
+impl ConsumerHandler for MessageConsumer {
+    async fn exec(&self, ctx: &Context, data: &[u8]) -> Result<(), HandlerError> {
+        self.handler.exec(ctx, &received(ctx, data)).await
+    }
+}
+
+/// Rebuilds the message from what `IAmqp::consume` carries in the context.
+fn received(ctx: &Context, data: &[u8]) -> Message {
+    let mut msg = Message::raw("", data.to_vec());
+    if let Some(DeliveryHeaders(headers)) = ctx.get::<DeliveryHeaders>() {
+        msg.headers = headers.clone();
+        msg.headers.remove(TYPE_HEADER);
+    }
+    if let Some(DeliveryType(msg_type)) = ctx.get::<DeliveryType>() {
+        msg.msg_type = msg_type.clone();
+    }
+    if let Some(device_id) = ctx.get::<Envelope>().and_then(|e| e.device_id.clone()) {
+        msg.headers.insert(DEVICE_ID_HEADER.to_owned(), device_id);
+    }
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::KeyValue;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_publish_data() {
+        let msg = Message::raw("Temp", b"{}".to_vec())
+            .key("routing")
+            .header(DEVICE_ID_HEADER, "device");
+
+        let data = publish_data(&Context::new(), &msg);
+        assert_eq!(data.msg_type, "Temp");
+        assert_eq!(data.payload.as_ref(), b"{}");
+        assert_eq!(data.device_id, Some("device".to_owned()));
+
+        let ctx = Context::new().with_baggage(vec![KeyValue::new(semconv::DEVICE_ID, "bridged")]);
+        let data = publish_data(&ctx, &Message::raw("Temp", vec![]));
+        assert_eq!(data.device_id, Some("bridged".to_owned()));
+    }
+
+    #[test]
+    fn test_received() {
+        let mut headers = BTreeMap::new();
+        headers.insert(TYPE_HEADER.to_owned(), "Temp".to_owned());
+        headers.insert("tenant".to_owned(), "acme".to_owned());
+        let ctx = Context::new()
+            .with_value(DeliveryType("Temp".to_owned()))
+            .with_value(DeliveryHeaders(headers))
+            .with_value(Envelope {
+                device_id: Some("device".to_owned()),
+                ..Envelope::default()
+            });
+
+        let msg = received(&ctx, b"{}");
+        assert_eq!(msg.msg_type, "Temp");
+        assert_eq!(msg.payload, b"{}".to_vec());
+        assert_eq!(
+            msg.headers.get(DEVICE_ID_HEADER),
+            Some(&"device".to_owned())
+        );
+        assert_eq!(msg.headers.get("tenant"), Some(&"acme".to_owned()));
+        assert!(!msg.headers.contains_key(TYPE_HEADER));
+    }
+}
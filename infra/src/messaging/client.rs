@@ -0,0 +1,52 @@
+use super::{amqp::AmqpPublisher, types::Message};
+use crate::{
+    amqp::client::Amqp,
+    env::{Config, MessagingBackend},
+    errors::{HandlerError, MessagingError},
+};
+use async_trait::async_trait;
+use opentelemetry::Context;
+use std::sync::Arc;
+
+/// Publishes to a topic of whichever bus the deployment uses: an exchange on
+/// AMQP, a topic on Kafka or a subject on NATS JetStream.
+#[async_trait]
+pub trait Publisher {
+    async fn publish(
+        &self,
+        ctx: &Context,
+        topic: &str,
+        msg: &Message,
+    ) -> Result<(), MessagingError>;
+}
+
+#[async_trait]
+pub trait MessageHandler {
+    async fn exec(&self, ctx: &Context, msg: &Message) -> Result<(), HandlerError>;
+}
+
+#[async_trait]
+pub trait Subscriber {
+    /// Handles the messages of `topic` until SIGTERM or Ctrl+C is received.
+    async fn subscribe(
+        &self,
+        topic: &str,
+        handler: Arc<dyn MessageHandler + Send + Sync>,
+    ) -> Result<(), MessagingError>;
+}
+
+/// Connects the publisher of `cfg.messaging_backend`.
+pub async fn publisher(cfg: &Config) -> Result<Arc<dyn Publisher + Send + Sync>, MessagingError> {
+    match &cfg.messaging_backend {
+        MessagingBackend::Amqp => Ok(AmqpPublisher::new(Amqp::new(cfg).await?)),
+        #[cfg(feature = "kafka")]
+        MessagingBackend::Kafka => super::kafka::KafkaPublisher::new(cfg),
+        #[cfg(feature = "nats")]
+        MessagingBackend::Nats => super::nats::NatsPublisher::new(cfg).await,
+        #[allow(unreachable_patterns)]
+        backend => Err(MessagingError::UnsupportedBackendError(format!(
+            "{:?}",
+            backend
+        ))),
+    }
+}
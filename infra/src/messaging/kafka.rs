@@ -0,0 +1,262 @@
+use super::{
+    client::{MessageHandler, Publisher, Subscriber},
+    types::{consumer_span, Message},
+};
+use crate::{
+    amqp::{client::FAILURE_REASON_HEADER, dispatcher::shutdown_signal},
+    env::Config,
+    errors::{HandlerError, MessagingError},
+    otel::semconv,
+};
+use async_trait::async_trait;
+use log::{debug, error, warn};
+use opentelemetry::{
+    global::{self, BoxedTracer},
+    trace::{FutureExt, StatusCode},
+    Context,
+};
+use rdkafka::{
+    consumer::{CommitMode, Consumer, StreamConsumer},
+    message::{BorrowedMessage, Headers, Message as _, OwnedHeaders},
+    producer::{FutureProducer, FutureRecord},
+    util::Timeout,
+    ClientConfig,
+};
+use std::{sync::Arc, time::Duration};
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Retries of a failed message before it goes to the DLQ topic, Kafka has no
+/// per message redelivery so later messages of the partition wait meanwhile.
+pub const DEFAULT_RETRIES: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+fn producer(cfg: &Config) -> Result<FutureProducer, MessagingError> {
+    ClientConfig::new()
+        .set("bootstrap.servers", cfg.kafka_brokers)
+        .set("client.id", cfg.app_name)
+        .set("message.timeout.ms", SEND_TIMEOUT.as_millis().to_string())
+        .create()
+        .map_err(|err| {
+            error!("failure to create kafka producer - {:?}", err);
+            MessagingError::ConnectionError(cfg.kafka_brokers.to_owned())
+        })
+}
+
+/// Topic the messages of `topic` rejected or out of retries are sent to.
+pub fn dlq_topic(topic: &str) -> String {
+    format!("{}-dlq", topic)
+}
+
+pub struct KafkaPublisher {
+    producer: FutureProducer,
+}
+
+impl KafkaPublisher {
+    pub fn new(cfg: &Config) -> Result<Arc<dyn Publisher + Send + Sync>, MessagingError> {
+        Ok(Arc::new(KafkaPublisher {
+            producer: producer(cfg)?,
+        }))
+    }
+}
+
+#[async_trait]
+impl Publisher for KafkaPublisher {
+    async fn publish(
+        &self,
+        ctx: &Context,
+        topic: &str,
+        msg: &Message,
+    ) -> Result<(), MessagingError> {
+        let headers = msg
+            .wire_headers(ctx)
+            .iter()
+            .fold(OwnedHeaders::new(), |headers, (key, value)| {
+                headers.add(key, value)
+            });
+
+        let record: FutureRecord<str, [u8]> = FutureRecord::to(topic)
+            .payload(msg.payload.as_slice())
+            .headers(headers);
+        let record = match &msg.key {
+            Some(key) => record.key(key.as_str()),
+            _ => record,
+        };
+
+        self.producer
+            .send(record, Timeout::After(SEND_TIMEOUT))
+            .await
+            .map(|_| ())
+            .map_err(|(err, _)| {
+                error!("failure to publish to kafka - {:?}", err);
+                MessagingError::PublishingError(topic.to_owned())
+            })
+    }
+}
+
+/// Consumes topics within the consumer group named after the app, offsets are
+/// committed once the handler is done with the message or once it is in the
+/// DLQ topic.
+pub struct KafkaSubscriber {
+    config: ClientConfig,
+    dlq: FutureProducer,
+    retries: u32,
+    tracer: BoxedTracer,
+}
+
+impl KafkaSubscriber {
+    pub fn new(
+        cfg: &Config,
+        retries: u32,
+    ) -> Result<Arc<dyn Subscriber + Send + Sync>, MessagingError> {
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", cfg.kafka_brokers)
+            .set("group.id", cfg.app_name)
+            .set("enable.auto.commit", "false")
+            .set("auto.offset.reset", "earliest");
+
+        Ok(Arc::new(KafkaSubscriber {
+            config,
+            dlq: producer(cfg)?,
+            retries,
+            tracer: global::tracer("kafka"),
+        }))
+    }
+
+    /// The failure reason of the messages to send to the DLQ topic.
+    async fn handle(
+        &self,
+        handler: &Arc<dyn MessageHandler + Send + Sync>,
+        topic: &str,
+        msg: &Message,
+    ) -> Option<String> {
+        let span = consumer_span(&self.tracer, msg, semconv::KAFKA, topic, "kafka consume");
+        let ctx = span.context();
+
+        let mut attempts = 0;
+        loop {
+            let (delay, reason) = match handler.exec(ctx, msg).with_context(ctx.clone()).await {
+                Ok(_) => {
+                    span.set_status(StatusCode::Ok, "success".to_owned());
+                    return None;
+                }
+                Err(HandlerError::Drop(reason)) => {
+                    warn!("dropping msg - {}", reason);
+                    return None;
+                }
+                Err(HandlerError::Reject(reason)) => {
+                    error!("unprocessable msg, sending to dlq - {}", reason);
+                    span.set_status(StatusCode::Error, reason.clone());
+                    return Some(reason);
+                }
+                Err(HandlerError::Retry(reason)) => (RETRY_DELAY, reason),
+                Err(HandlerError::RetryAfter(delay, reason)) => (delay, reason),
+            };
+
+            attempts += 1;
+            if attempts > self.retries {
+                error!("too many attempts, sending msg to dlq");
+                span.set_status(StatusCode::Error, "too many attempts".to_owned());
+                return Some(reason);
+            }
+
+            warn!("error whiling handling msg, retrying in {:?}", delay);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Keeps trying, the offset must not move past a message not in the DLQ.
+    async fn send_to_dlq(&self, topic: &str, received: &BorrowedMessage<'_>, reason: &str) {
+        let dlq = dlq_topic(topic);
+        let headers = received
+            .headers()
+            .map(|headers| headers.detach())
+            .unwrap_or_default()
+            .add(FAILURE_REASON_HEADER, reason);
+
+        loop {
+            let record: FutureRecord<[u8], [u8]> = FutureRecord::to(&dlq)
+                .payload(received.payload().unwrap_or_default())
+                .headers(headers.clone());
+            let record = match received.key() {
+                Some(key) => record.key(key),
+                _ => record,
+            };
+
+            match self.dlq.send(record, Timeout::After(SEND_TIMEOUT)).await {
+                Ok(_) => return,
+                Err((err, _)) => {
+                    error!("failure to send to kafka dlq {} - {:?}", dlq, err);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Subscriber for KafkaSubscriber {
+    async fn subscribe(
+        &self,
+        topic: &str,
+        handler: Arc<dyn MessageHandler + Send + Sync>,
+    ) -> Result<(), MessagingError> {
+        let consumer: StreamConsumer = self.config.create().map_err(|err| {
+            error!("failure to create kafka consumer - {:?}", err);
+            MessagingError::ConnectionError(topic.to_owned())
+        })?;
+        consumer.subscribe(&[topic]).map_err(|err| {
+            error!("failure to subscribe to kafka topic - {:?}", err);
+            MessagingError::SubscribeError(topic.to_owned())
+        })?;
+
+        let shutdown = shutdown_signal();
+        tokio::pin!(shutdown);
+
+        loop {
+            let received = tokio::select! {
+                _ = &mut shutdown => break,
+                received = consumer.recv() => received,
+            };
+
+            let received = match received {
+                Ok(received) => received,
+                Err(err) => {
+                    error!("failure to receive from kafka - {:?}", err);
+                    continue;
+                }
+            };
+
+            let msg = Message::from_wire(
+                received
+                    .key()
+                    .map(|key| String::from_utf8_lossy(key).to_string()),
+                received
+                    .headers()
+                    .map(|headers| {
+                        (0..headers.count())
+                            .filter_map(|idx| headers.get(idx))
+                            .map(|(key, value)| {
+                                (key.to_owned(), String::from_utf8_lossy(value).to_string())
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                received.payload().unwrap_or_default().to_vec(),
+            );
+            debug!("topic: {} - received message: {}", topic, msg.msg_type);
+
+            if let Some(reason) = self.handle(&handler, topic, &msg).await {
+                self.send_to_dlq(topic, &received, &reason).await;
+            }
+
+            if let Err(err) = consumer.commit_message(&received, CommitMode::Async) {
+                error!("failure to commit kafka offset - {:?}", err);
+            }
+        }
+
+        debug!("kafka subscriber of {} stopped", topic);
+        Ok(())
+    }
+}
@@ -0,0 +1,7 @@
+pub mod amqp;
+pub mod client;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "nats")]
+pub mod nats;
+pub mod types;
@@ -0,0 +1,272 @@
+use super::{
+    client::{MessageHandler, Publisher, Subscriber},
+    types::{consumer_span, Message},
+};
+use crate::{
+    amqp::dispatcher::shutdown_signal,
+    env::Config,
+    errors::{HandlerError, MessagingError},
+    otel::semconv,
+};
+use async_nats::{
+    jetstream::{self, consumer::pull, AckKind},
+    HeaderMap,
+};
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use log::{debug, error, warn};
+use opentelemetry::{
+    global::{self, BoxedTracer},
+    trace::{FutureExt, StatusCode},
+    Context,
+};
+use std::{collections::BTreeMap, sync::Arc};
+
+/// Redeliveries of a failed message before it is terminated.
+pub const DEFAULT_RETRIES: u32 = 3;
+
+/// Carries the message key, the subject is the topic so a single consumer
+/// filter matches every message of it.
+const KEY_HEADER: &str = "key";
+
+/// Publishes to the `<topic>` subject and waits for the stream ack.
+pub struct NatsPublisher {
+    jetstream: jetstream::Context,
+}
+
+impl NatsPublisher {
+    pub async fn new(cfg: &Config) -> Result<Arc<dyn Publisher + Send + Sync>, MessagingError> {
+        let client = async_nats::connect(cfg.nats_url).await.map_err(|err| {
+            error!("failure to connect to nats - {:?}", err);
+            MessagingError::ConnectionError(cfg.nats_url.to_owned())
+        })?;
+
+        Ok(Arc::new(NatsPublisher {
+            jetstream: jetstream::new(client),
+        }))
+    }
+}
+
+fn wire_headers(ctx: &Context, msg: &Message) -> BTreeMap<String, String> {
+    let mut headers = msg.wire_headers(ctx);
+    if let Some(key) = &msg.key {
+        headers.insert(KEY_HEADER.to_owned(), key.clone());
+    }
+    headers
+}
+
+fn from_wire(mut headers: BTreeMap<String, String>, payload: Vec<u8>) -> Message {
+    let key = headers.remove(KEY_HEADER);
+    Message::from_wire(key, headers, payload)
+}
+
+#[async_trait]
+impl Publisher for NatsPublisher {
+    async fn publish(
+        &self,
+        ctx: &Context,
+        topic: &str,
+        msg: &Message,
+    ) -> Result<(), MessagingError> {
+        let mut headers = HeaderMap::new();
+        for (key, value) in wire_headers(ctx, msg).iter() {
+            headers.insert(key.as_str(), value.as_str());
+        }
+
+        let publish_err = |err: String| {
+            error!("failure to publish to nats - {}", err);
+            MessagingError::PublishingError(topic.to_owned())
+        };
+
+        self.jetstream
+            .publish_with_headers(topic.to_owned(), headers, msg.payload.clone().into())
+            .await
+            .map_err(|err| publish_err(err.to_string()))?
+            .await
+            .map_err(|err| publish_err(err.to_string()))?;
+
+        Ok(())
+    }
+}
+
+/// Consumes subjects of the `cfg.nats_stream` stream with a durable pull
+/// consumer named after the app, retries are naked back to the server up to
+/// `retries` times and the message is terminated after that.
+pub struct NatsSubscriber {
+    jetstream: jetstream::Context,
+    stream: &'static str,
+    name: &'static str,
+    retries: u32,
+    tracer: BoxedTracer,
+}
+
+impl NatsSubscriber {
+    pub async fn new(
+        cfg: &Config,
+        retries: u32,
+    ) -> Result<Arc<dyn Subscriber + Send + Sync>, MessagingError> {
+        let client = async_nats::connect(cfg.nats_url).await.map_err(|err| {
+            error!("failure to connect to nats - {:?}", err);
+            MessagingError::ConnectionError(cfg.nats_url.to_owned())
+        })?;
+
+        Ok(Arc::new(NatsSubscriber {
+            jetstream: jetstream::new(client),
+            stream: cfg.nats_stream,
+            name: cfg.app_name,
+            retries,
+            tracer: global::tracer("nats"),
+        }))
+    }
+
+    async fn handle(
+        &self,
+        handler: &Arc<dyn MessageHandler + Send + Sync>,
+        received: &jetstream::Message,
+    ) {
+        let headers = received
+            .headers
+            .iter()
+            .flat_map(|headers| headers.iter())
+            .filter_map(|(key, values)| {
+                values.iter().next().map(|value| {
+                    (
+                        String::from_utf8_lossy(key.as_ref()).into_owned(),
+                        value.to_owned(),
+                    )
+                })
+            })
+            .collect();
+        let msg = from_wire(headers, received.payload.to_vec());
+        debug!(
+            "subject: {} - received message: {}",
+            received.subject, msg.msg_type
+        );
+
+        let span = consumer_span(
+            &self.tracer,
+            &msg,
+            semconv::NATS,
+            &received.subject,
+            "nats consume",
+        );
+        let ctx = span.context();
+        let delivered = received.info().map(|info| info.delivered).unwrap_or(1);
+        let exhausted = delivered > self.retries as i64;
+        let ack = match handler.exec(ctx, &msg).with_context(ctx.clone()).await {
+            Ok(_) => {
+                span.set_status(StatusCode::Ok, "success".to_owned());
+                AckKind::Ack
+            }
+            Err(HandlerError::Drop(reason)) => {
+                warn!("dropping msg - {}", reason);
+                AckKind::Ack
+            }
+            Err(HandlerError::Reject(reason)) => {
+                error!("unprocessable msg, terminating it - {}", reason);
+                span.set_status(StatusCode::Error, reason);
+                AckKind::Term
+            }
+            Err(HandlerError::Retry(reason) | HandlerError::RetryAfter(_, reason)) if exhausted => {
+                error!("too many attempts, terminating msg - {}", reason);
+                span.set_status(StatusCode::Error, "too many attempts".to_owned());
+                AckKind::Term
+            }
+            Err(HandlerError::Retry(reason)) => {
+                warn!("error whiling handling msg, redelivering - {}", reason);
+                AckKind::Nak
+            }
+            Err(HandlerError::RetryAfter(delay, reason)) => {
+                // the nak of async-nats 0.27 carries no delay
+                warn!(
+                    "error whiling handling msg, redelivering now instead of in {:?} - {}",
+                    delay, reason
+                );
+                AckKind::Nak
+            }
+        };
+
+        if let Err(err) = received.ack_with(ack).await {
+            error!("failure to ack nats message - {:?}", err);
+        }
+    }
+}
+
+#[async_trait]
+impl Subscriber for NatsSubscriber {
+    async fn subscribe(
+        &self,
+        topic: &str,
+        handler: Arc<dyn MessageHandler + Send + Sync>,
+    ) -> Result<(), MessagingError> {
+        let subscribe_err = |err: String| {
+            error!("failure to subscribe to nats - {}", err);
+            MessagingError::SubscribeError(topic.to_owned())
+        };
+
+        let stream = self
+            .jetstream
+            .get_stream(self.stream)
+            .await
+            .map_err(|err| subscribe_err(err.to_string()))?;
+
+        // durable names can't hold the subject dots and wildcards
+        let durable = format!("{}-{}", self.name, topic.replace(['.', '*', '>'], "_"));
+        let consumer = stream
+            .get_or_create_consumer(
+                &durable,
+                pull::Config {
+                    durable_name: Some(durable.clone()),
+                    filter_subject: topic.to_owned(),
+                    // the last delivery is terminated by `handle`, the
+                    // server cap only covers the messages never acked
+                    max_deliver: self.retries as i64 + 1,
+                    ..Default::default()
+                },
+            )
+            .await
+            .map_err(|err| subscribe_err(err.to_string()))?;
+
+        let mut messages = consumer
+            .messages()
+            .await
+            .map_err(|err| subscribe_err(err.to_string()))?;
+
+        let shutdown = shutdown_signal();
+        tokio::pin!(shutdown);
+
+        loop {
+            let received = tokio::select! {
+                _ = &mut shutdown => break,
+                received = messages.next() => received,
+            };
+
+            match received {
+                Some(Ok(received)) => self.handle(&handler, &received).await,
+                Some(Err(err)) => error!("failure to receive from nats - {:?}", err),
+                None => break,
+            }
+        }
+
+        debug!("nats subscriber of {} stopped", topic);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wire_key() {
+        let msg = Message::raw("Temp", b"{}".to_vec()).key("device");
+        let headers = wire_headers(&Context::new(), &msg);
+        assert_eq!(headers.get(KEY_HEADER), Some(&"device".to_owned()));
+        assert_eq!(from_wire(headers, b"{}".to_vec()), msg);
+
+        let msg = Message::raw("Temp", b"{}".to_vec());
+        let headers = wire_headers(&Context::new(), &msg);
+        assert!(!headers.contains_key(KEY_HEADER));
+        assert_eq!(from_wire(headers, b"{}".to_vec()), msg);
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{
+    amqp::{envelope::TYPE_HEADER, types::PublishPayload},
+    errors::MessagingError,
+    otel::{
+        propagation::{self, MapExtractor, MapInjector},
+        semconv,
+        tracing::SpanGuard,
+    },
+};
+use opentelemetry::{global::BoxedTracer, trace::SpanKind, Context};
+use serde::Serialize;
+use std::{borrow::Cow, collections::BTreeMap};
+
+/// Message published and received the same way on every backend.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Message {
+    pub msg_type: String,
+    /// Routing key on AMQP, partition key on Kafka and a header on NATS
+    pub key: Option<String>,
+    pub headers: BTreeMap<String, String>,
+    pub payload: Vec<u8>,
+}
+
+impl Message {
+    pub fn new<T>(payload: T) -> Result<Self, MessagingError>
+    where
+        T: PublishPayload + Serialize,
+    {
+        let serialized =
+            serde_json::to_vec::<T>(&payload).map_err(|_| MessagingError::ParsePayloadError)?;
+
+        Ok(Message::raw(&payload.get_type().to_string(), serialized))
+    }
+
+    pub fn raw(msg_type: &str, payload: Vec<u8>) -> Self {
+        Message {
+            msg_type: msg_type.to_owned(),
+            payload,
+            ..Message::default()
+        }
+    }
+
+    pub fn key(mut self, key: &str) -> Self {
+        self.key = Some(key.to_owned());
+        self
+    }
+
+    pub fn header(mut self, key: &str, value: &str) -> Self {
+        self.headers.insert(key.to_owned(), value.to_owned());
+        self
+    }
+
+    /// Headers sent by the backends without native ones for the type and the
+    /// trace, i.e. the custom headers plus `type`, `traceparent`, `tracestate`
+    /// and `baggage`.
+    pub fn wire_headers(&self, ctx: &Context) -> BTreeMap<String, String> {
+        let mut headers = self.headers.clone();
+        headers.insert(TYPE_HEADER.to_owned(), self.msg_type.clone());
+        propagation::inject(ctx, &mut MapInjector(&mut headers));
+        headers
+    }
+
+    /// Reverts `wire_headers`, the trace context is kept in the headers.
+    pub fn from_wire(
+        key: Option<String>,
+        mut headers: BTreeMap<String, String>,
+        payload: Vec<u8>,
+    ) -> Message {
+        Message {
+            msg_type: headers.remove(TYPE_HEADER).unwrap_or_default(),
+            key,
+            headers,
+            payload,
+        }
+    }
+}
+
+/// Span of a received message, child of the producer span when the message
+/// carries a valid `traceparent` and the root of a new trace otherwise.
+pub fn consumer_span<N>(
+    tracer: &BoxedTracer,
+    msg: &Message,
+    system: &'static str,
+    destination: &str,
+    name: N,
+) -> SpanGuard
+where
+    N: Into<Cow<'static, str>>,
+{
+    let span = propagation::remote_span(
+        tracer,
+        &MapExtractor(&msg.headers),
+        name,
+        SpanKind::Consumer,
+    );
+    span.set_attributes(semconv::messaging(
+        system,
+        destination,
+        semconv::PROCESS,
+        None,
+    ));
+    span
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amqp::{envelope::TRACEPARENT_HEADER, types::AmqpMessageType};
+    use opentelemetry::{
+        global,
+        trace::{TraceContextExt, TraceId},
+    };
+
+    const TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    #[derive(Serialize)]
+    struct Temp {
+        temp: f32,
+    }
+
+    impl PublishPayload for Temp {
+        fn get_type(&self) -> AmqpMessageType {
+            AmqpMessageType::Temp
+        }
+    }
+
+    #[test]
+    fn test_wire_round_trip() {
+        let msg = Message::new(Temp { temp: 21.5 })
+            .unwrap()
+            .key("device")
+            .header("device-id", "device");
+        assert_eq!(msg.msg_type, "Temp");
+        assert_eq!(msg.payload, br#"{"temp":21.5}"#.to_vec());
+
+        let headers = msg.wire_headers(&Context::new());
+        assert_eq!(headers.get(TYPE_HEADER), Some(&"Temp".to_owned()));
+        assert_eq!(headers.get(TRACEPARENT_HEADER), None);
+
+        let received = Message::from_wire(msg.key.clone(), headers, msg.payload.clone());
+        assert_eq!(received, msg);
+    }
+
+    #[test]
+    fn test_consumer_span() {
+        let tracer = global::tracer("test");
+        let trace_id = TraceId::from_hex("0af7651916cd43dd8448eb211c80319c").unwrap();
+
+        let msg = Message::raw("Temp", vec![]).header(TRACEPARENT_HEADER, TRACEPARENT);
+        let span = consumer_span(&tracer, &msg, semconv::KAFKA, "iot", "consume");
+        assert_eq!(span.context().span().span_context().trace_id(), trace_id);
+
+        let msg = Message::raw("Temp", vec![]).header(TRACEPARENT_HEADER, "broken");
+        let span = consumer_span(&tracer, &msg, semconv::KAFKA, "iot", "consume");
+        assert_ne!(span.context().span().span_context().trace_id(), trace_id);
+    }
+}
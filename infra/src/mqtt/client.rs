@@ -1,12 +1,17 @@
 use super::types::{Controller, Message, MessageMetadata, MetadataKind};
-use crate::{env::Config, errors::MqttError, otel};
+use crate::{
+    env::Config,
+    errors::MqttError,
+    otel::{self, semconv},
+};
 use async_trait::async_trait;
+use bytes::Bytes;
 use log::{debug, error};
 #[cfg(test)]
 use mockall::predicate::*;
 use opentelemetry::{
     global::{self, BoxedTracer},
-    trace::FutureExt,
+    trace::{FutureExt, SpanKind},
     Context,
 };
 use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS};
@@ -31,6 +36,16 @@ pub trait IMQTT {
         payload: &[u8],
     ) -> Result<(), MqttError>;
     async fn handle_event(&self, event: &Event) -> Result<(), MqttError>;
+    /// Dispatches a received message. The client speaks MQTT 3.1.1, which has
+    /// no user properties, so `handle_event` passes none and the trace context
+    /// of the device is read from the payload. `user_properties` is only set
+    /// by callers receiving from an MQTT 5 client.
+    async fn handle_publish(
+        &self,
+        topic: String,
+        payload: &Bytes,
+        user_properties: &[(String, String)],
+    ) -> Result<(), MqttError>;
 }
 
 pub struct MQTT {
@@ -113,50 +128,72 @@ impl IMQTT for MQTT {
     ) -> Result<(), MqttError> {
         debug!("publishing in a topic {:?}", topic);
 
-        let cx = otel::tracing::ctx_from_ctx(&self.tracer, ctx, "mqtt publish");
-
-        self.client
+        let span =
+            otel::tracing::span_from_ctx(&self.tracer, ctx, "mqtt publish", SpanKind::Producer);
+        span.set_attributes(semconv::messaging(
+            semconv::MQTT,
+            topic,
+            semconv::PUBLISH,
+            None,
+        ));
+
+        let result = self
+            .client
             .clone()
             .unwrap()
             .publish(topic, qos, retain, payload)
-            .with_context(cx)
+            .with_context(span.context().clone())
             .await
-            .map_err(|_| MqttError::PublishingError {})?;
+            .map_err(|_| MqttError::PublishingError {});
 
         debug!("message published");
-        Ok(())
+        span.finish(result)
     }
 
     async fn handle_event(&self, event: &Event) -> Result<(), MqttError> {
         if let Event::Incoming(Packet::Publish(msg)) = event.to_owned() {
-            debug!("message received in a topic {:?}", msg.topic);
+            return self.handle_publish(msg.topic, &msg.payload, &[]).await;
+        }
+        Ok(())
+    }
+
+    async fn handle_publish(
+        &self,
+        topic: String,
+        payload: &Bytes,
+        user_properties: &[(String, String)],
+    ) -> Result<(), MqttError> {
+        debug!("message received in a topic {:?}", topic);
 
-            let metadata = MessageMetadata::from_topic(msg.topic)?;
+        let metadata = MessageMetadata::from_topic(topic)?;
 
-            let name = format!("mqtt::event::{:?}", metadata.kind);
-            let ctx = otel::tracing::new_ctx(&self.tracer, Box::leak(name.into_boxed_str()));
+        let name = format!("mqtt::event::{:?}", metadata.kind);
+        let span = otel::mqtt::get_span(&self.tracer, &metadata, user_properties, payload, name);
 
-            let data = Message::from_payload(&metadata.kind, &msg.payload)?;
+        let data = match Message::from_payload(&metadata.kind, payload) {
+            Ok(data) => data,
+            Err(err) => return span.finish(Err(err)),
+        };
 
-            let controller = self.dispatchers.get(&metadata.kind);
-            if controller.is_none() {
-                return Err(MqttError::InternalError {});
-            }
+        let controller = self.dispatchers.get(&metadata.kind);
+        if controller.is_none() {
+            return span.finish(Err(MqttError::InternalError {}));
+        }
 
-            return match controller.unwrap().exec(&ctx, &metadata, &data).await {
-                Ok(_) => {
-                    debug!("event processed successfully");
-                    // span.set_status(StatusCode::Ok, format!("event processed successfully"));
-                    Ok(())
-                }
-                Err(e) => {
-                    error!("failed to handle the event - {:?}", e);
-                    // span.set_status(StatusCode::Error, format!("failed to handle the event"));
-                    Err(e)
-                }
-            };
+        match controller
+            .unwrap()
+            .exec(span.context(), &metadata, &data)
+            .await
+        {
+            Ok(_) => {
+                debug!("event processed successfully");
+                span.finish(Ok(()))
+            }
+            Err(e) => {
+                error!("failed to handle the event - {:?}", e);
+                span.finish(Err(e))
+            }
         }
-        Ok(())
     }
 }
 
@@ -164,7 +201,6 @@ impl IMQTT for MQTT {
 mod tests {
     use super::*;
     use crate::mqtt::types::{Controller, IoTServiceKind, MockController};
-    use bytes::Bytes;
     use rumqttc::Publish;
 
     #[test]
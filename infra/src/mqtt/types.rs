@@ -32,6 +32,7 @@ impl Display for MetadataKind {
 pub struct MessageMetadata {
     pub kind: MetadataKind,
     pub topic: String,
+    pub device_id: Option<String>,
 }
 
 impl Display for MessageMetadata {
@@ -57,6 +58,7 @@ impl MessageMetadata {
 
                 Ok(MessageMetadata {
                     kind: MetadataKind::IoT(IoTServiceKind::Temp),
+                    device_id: Some(splitted[3].to_owned()),
                     topic: topic.clone(),
                 })
             }
@@ -120,6 +122,7 @@ mod tests {
         assert!(res.is_ok());
 
         let res = res.unwrap();
+        assert_eq!(res.device_id, Some("device_id".to_owned()));
         let kind = res.kind;
         assert_eq!(kind, MetadataKind::IoT(IoTServiceKind::Temp));
     }
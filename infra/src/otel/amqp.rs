@@ -1,73 +1,110 @@
+use super::{propagation, tracing::SpanGuard};
+use lapin::types::{AMQPValue, FieldTable, LongString, ShortString};
 use opentelemetry::{
-    global::{BoxedSpan, BoxedTracer},
-    trace::{
-        SpanContext, SpanId, SpanKind, TraceContextExt, TraceFlags, TraceId, TraceState, Tracer,
-    },
+    global::BoxedTracer,
+    propagation::{Extractor, Injector},
+    trace::SpanKind,
     Context,
 };
+use std::{borrow::Cow, collections::BTreeMap};
 
-const TRACE_VERSION: u8 = 0;
+/// Headers of an AMQP message, values are written as long strings.
+pub struct FieldTableInjector<'a>(pub &'a mut BTreeMap<ShortString, AMQPValue>);
 
-pub struct Traceparent {
-    pub trace_id: String,
-    pub span_id: String,
-    pub trace_flags: u8,
+impl<'a> Injector for FieldTableInjector<'a> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.insert(
+            ShortString::from(key.to_owned()),
+            AMQPValue::LongString(LongString::from(value)),
+        );
+    }
 }
 
-///traceparent is compos from {trace-version}-{trace-id}-{parent-id}-{trace-flags}
-impl Traceparent {
-    pub fn from_string(traceparent: String) -> Traceparent {
-        let splitted: Vec<&str> = traceparent.split("-").collect();
-
-        let trace_id = splitted[1].to_string();
-        let span_id = splitted[2].to_string();
-        let trace_flags = splitted[3].as_bytes()[0];
+pub struct FieldTableExtractor<'a>(pub &'a FieldTable);
 
-        Traceparent {
-            trace_id,
-            span_id,
-            trace_flags,
+impl<'a> Extractor for FieldTableExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        match self.0.inner().get(key)? {
+            AMQPValue::LongString(value) => std::str::from_utf8(value.as_bytes()).ok(),
+            AMQPValue::ShortString(value) => Some(value.as_str()),
+            _ => None,
         }
     }
 
-    pub fn string_from_ctx(ctx: &Context) -> String {
-        let trace_id = ctx.get::<TraceId>().unwrap();
-        let parent_id = ctx.get::<SpanId>().unwrap();
-        let trace_flags = ctx.get::<TraceFlags>().unwrap();
-
-        format!(
-            "{:02x}-{:032x}-{:016x}-{:02x}",
-            TRACE_VERSION, trace_id, parent_id, trace_flags
-        )
+    fn keys(&self) -> Vec<&str> {
+        self.0.inner().keys().map(ShortString::as_str).collect()
     }
 }
 
-pub fn get_span(
-    tracer: &BoxedTracer,
-    traceparent: String,
-    span_name: &'static str,
-) -> (Context, BoxedSpan) {
-    let parsed = Traceparent::from_string(traceparent);
+/// Writes the trace context and the baggage of `ctx` into the headers.
+pub fn inject(ctx: &Context, headers: &mut BTreeMap<ShortString, AMQPValue>) {
+    propagation::inject(ctx, &mut FieldTableInjector(headers));
+}
 
-    let trace_id = TraceId::from_hex(&parsed.trace_id).unwrap();
-    let span_id = SpanId::from_hex(&parsed.span_id).unwrap();
-    let trace_flags = TraceFlags::new(parsed.trace_flags);
+/// Consumer span continuing the trace of the headers, a new trace is started
+/// when they carry none or a malformed one.
+pub fn get_span<N>(tracer: &BoxedTracer, headers: &FieldTable, span_name: N) -> SpanGuard
+where
+    N: Into<Cow<'static, str>>,
+{
+    propagation::remote_span(
+        tracer,
+        &FieldTableExtractor(headers),
+        span_name,
+        SpanKind::Consumer,
+    )
+}
 
-    let ctx = Context::new().with_remote_span_context(SpanContext::new(
-        trace_id,
-        span_id,
-        trace_flags,
-        true,
-        TraceState::default(),
-    ));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::{global, trace::TraceContextExt};
 
-    let span = tracer
-        .span_builder(span_name)
-        .with_kind(SpanKind::Consumer)
-        .start_with_context(tracer, &ctx);
+    #[test]
+    fn test_field_table_round_trip() {
+        let mut source = BTreeMap::new();
+        source.insert(
+            ShortString::from("traceparent"),
+            AMQPValue::LongString(LongString::from(
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            )),
+        );
+        source.insert(
+            ShortString::from("tracestate"),
+            AMQPValue::ShortString(ShortString::from("vendor=value")),
+        );
+        source.insert(
+            ShortString::from("baggage"),
+            AMQPValue::LongString(LongString::from("device-id=device")),
+        );
 
-    let ctx = ctx.with_value(trace_id);
-    let ctx = ctx.with_value(span_id);
+        let ctx = propagation::extract(&FieldTableExtractor(&FieldTable::from(source.clone())));
+        let mut headers = BTreeMap::new();
+        inject(&ctx, &mut headers);
 
-    (ctx.with_value(trace_flags), span)
+        for key in ["traceparent", "tracestate", "baggage"] {
+            let extracted = FieldTable::from(headers.clone());
+            let source = FieldTable::from(source.clone());
+            assert_eq!(
+                FieldTableExtractor(&extracted).get(key),
+                FieldTableExtractor(&source).get(key)
+            );
+        }
+    }
+
+    #[test]
+    fn test_get_span() {
+        let tracer = global::tracer("test");
+
+        let span = get_span(&tracer, &FieldTable::default(), "consume");
+        assert!(!span.context().span().span_context().is_remote());
+
+        let mut headers = BTreeMap::new();
+        headers.insert(
+            ShortString::from("traceparent"),
+            AMQPValue::LongString(LongString::from("00-zz")),
+        );
+        let span = get_span(&tracer, &FieldTable::from(headers), "consume");
+        assert!(!span.context().span().span_context().is_remote());
+    }
 }
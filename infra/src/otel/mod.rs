@@ -1,3 +1,6 @@
 pub mod amqp;
 pub mod metrics;
+pub mod mqtt;
+pub mod propagation;
+pub mod semconv;
 pub mod tracing;
@@ -0,0 +1,116 @@
+use super::{
+    propagation::{self, MapExtractor, UserPropertiesExtractor},
+    semconv,
+    tracing::{span_from_ctx, SpanGuard},
+};
+use crate::{amqp::envelope::TRACEPARENT_HEADER, mqtt::types::MessageMetadata};
+use opentelemetry::{
+    baggage::BaggageExt, global::BoxedTracer, propagation::Extractor, trace::SpanKind, KeyValue,
+};
+use serde::Deserialize;
+use std::{borrow::Cow, collections::BTreeMap};
+
+/// Trace context sent along the payload, the only carrier over MQTT 3.1.1,
+/// e.g. `{"temp": 21.5, "trace": {"traceparent": "00-..."}}`.
+#[derive(Debug, Default, Deserialize)]
+struct PayloadEnvelope {
+    #[serde(default)]
+    trace: BTreeMap<String, String>,
+}
+
+/// Bridge span of a device message, child of the device span when the MQTT 5
+/// user properties, if any, or else the payload carry a valid `traceparent`.
+///
+/// The device id and the topic go to the baggage, so they are propagated
+/// with the trace down to the AMQP consumers.
+pub fn get_span<N>(
+    tracer: &BoxedTracer,
+    metadata: &MessageMetadata,
+    user_properties: &[(String, String)],
+    payload: &[u8],
+    span_name: N,
+) -> SpanGuard
+where
+    N: Into<Cow<'static, str>>,
+{
+    let envelope = serde_json::from_slice::<PayloadEnvelope>(payload).unwrap_or_default();
+    let from_payload = MapExtractor(&envelope.trace);
+    let from_properties = UserPropertiesExtractor(user_properties);
+
+    let extractor: &dyn Extractor = match from_properties.get(TRACEPARENT_HEADER) {
+        Some(_) => &from_properties,
+        None => &from_payload,
+    };
+
+    let mut baggage = vec![KeyValue::new(semconv::MQTT_TOPIC, metadata.topic.clone())];
+    if let Some(device_id) = &metadata.device_id {
+        baggage.push(KeyValue::new(semconv::DEVICE_ID, device_id.clone()));
+    }
+    let remote = propagation::extract(extractor).with_baggage(baggage);
+
+    let span = span_from_ctx(tracer, &remote, span_name, SpanKind::Consumer);
+    span.set_attributes(semconv::messaging(
+        semconv::MQTT,
+        &metadata.topic,
+        semconv::PROCESS,
+        None,
+    ));
+    span
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mqtt::types::MessageMetadata;
+    use opentelemetry::{
+        global,
+        trace::{TraceContextExt, TraceId},
+        Context,
+    };
+
+    const TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    fn trace_id(ctx: &Context) -> TraceId {
+        ctx.span().span_context().trace_id()
+    }
+
+    #[test]
+    fn test_get_span() {
+        let tracer = global::tracer("test");
+        let metadata =
+            MessageMetadata::from_topic("iot/data/temp/device_id/location".to_owned()).unwrap();
+        let expected = TraceId::from_hex("0af7651916cd43dd8448eb211c80319c").unwrap();
+
+        let properties = vec![("traceparent".to_owned(), TRACEPARENT.to_owned())];
+        let span = get_span(&tracer, &metadata, &properties, b"{}", "mqtt");
+        let ctx = span.context();
+        assert_eq!(trace_id(ctx), expected);
+        assert_eq!(
+            ctx.baggage().get(semconv::DEVICE_ID).map(|v| v.to_string()),
+            Some("device_id".to_owned())
+        );
+        assert_eq!(
+            ctx.baggage()
+                .get(semconv::MQTT_TOPIC)
+                .map(|v| v.to_string()),
+            Some("iot/data/temp/device_id/location".to_owned())
+        );
+
+        let payload = format!(
+            r#"{{"temp": 21.5, "trace": {{"traceparent": "{}"}}}}"#,
+            TRACEPARENT
+        );
+        let span = get_span(&tracer, &metadata, &[], payload.as_bytes(), "mqtt");
+        let ctx = span.context();
+        assert_eq!(trace_id(ctx), expected);
+
+        let properties = vec![("traceparent".to_owned(), "broken".to_owned())];
+        let span = get_span(&tracer, &metadata, &properties, payload.as_bytes(), "mqtt");
+        let ctx = span.context();
+        assert_ne!(trace_id(ctx), expected);
+
+        let span = get_span(&tracer, &metadata, &[], b"not json", "mqtt");
+        let ctx = span.context();
+        assert_ne!(trace_id(ctx), expected);
+    }
+}
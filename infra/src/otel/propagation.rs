@@ -0,0 +1,210 @@
+use super::tracing::{span_from_ctx, SpanGuard};
+use http::{header::HeaderName, HeaderMap, HeaderValue};
+use opentelemetry::{
+    global::BoxedTracer,
+    propagation::{Extractor, Injector, TextMapPropagator},
+    sdk::propagation::{BaggagePropagator, TextMapCompositePropagator, TraceContextPropagator},
+    trace::SpanKind,
+    Context,
+};
+use std::{borrow::Cow, collections::BTreeMap};
+
+/// W3C `traceparent` and `tracestate` along with the W3C `baggage`.
+pub fn propagator() -> TextMapCompositePropagator {
+    TextMapCompositePropagator::new(vec![
+        Box::new(TraceContextPropagator::new()),
+        Box::new(BaggagePropagator::new()),
+    ])
+}
+
+/// Writes the span context and the baggage of `ctx` into the carrier, nothing
+/// is written for the trace when `ctx` has no span.
+pub fn inject(ctx: &Context, injector: &mut dyn Injector) {
+    propagator().inject_context(ctx, injector);
+}
+
+/// Context sent along the carrier, without a span when the carrier has no
+/// `traceparent` or a malformed one.
+pub fn extract(extractor: &dyn Extractor) -> Context {
+    propagator().extract_with_context(&Context::new(), extractor)
+}
+
+/// Starts the span handling a received message or request, child of the span
+/// the carrier holds or the root of a new trace otherwise. The received
+/// baggage is kept in the context of the span.
+pub fn remote_span<N>(
+    tracer: &BoxedTracer,
+    extractor: &dyn Extractor,
+    name: N,
+    kind: SpanKind,
+) -> SpanGuard
+where
+    N: Into<Cow<'static, str>>,
+{
+    span_from_ctx(tracer, &extract(extractor), name, kind)
+}
+
+/// Headers of the transports without native ones, i.e. Kafka and NATS.
+pub struct MapInjector<'a>(pub &'a mut BTreeMap<String, String>);
+
+impl<'a> Injector for MapInjector<'a> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.insert(key.to_owned(), value);
+    }
+}
+
+pub struct MapExtractor<'a>(pub &'a BTreeMap<String, String>);
+
+impl<'a> Extractor for MapExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(String::as_str).collect()
+    }
+}
+
+/// MQTT 5 user properties, the MQTT 3.1.1 client of `mqtt` has none.
+pub struct UserPropertiesInjector<'a>(pub &'a mut Vec<(String, String)>);
+
+impl<'a> Injector for UserPropertiesInjector<'a> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.retain(|(k, _)| k != key);
+        self.0.push((key.to_owned(), value));
+    }
+}
+
+pub struct UserPropertiesExtractor<'a>(pub &'a [(String, String)]);
+
+impl<'a> Extractor for UserPropertiesExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.iter().map(|(key, _)| key.as_str()).collect()
+    }
+}
+
+/// HTTP headers, gRPC metadata goes through them with tonic's
+/// `MetadataMap::into_headers` and `MetadataMap::from_headers`.
+pub struct HeaderInjector<'a>(pub &'a mut HeaderMap);
+
+impl<'a> Injector for HeaderInjector<'a> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+pub struct HeaderExtractor<'a>(pub &'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::{
+        baggage::BaggageExt,
+        global,
+        trace::{TraceContextExt, TraceId},
+        KeyValue,
+    };
+
+    const TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    #[test]
+    fn test_round_trip() {
+        let mut headers = BTreeMap::new();
+        headers.insert("traceparent".to_owned(), TRACEPARENT.to_owned());
+        headers.insert("tracestate".to_owned(), "vendor=value".to_owned());
+        headers.insert("baggage".to_owned(), "device-id=device".to_owned());
+
+        let ctx = extract(&MapExtractor(&headers));
+        let span_ctx = ctx.span().span_context().clone();
+        assert!(span_ctx.is_remote());
+        assert_eq!(
+            span_ctx.trace_id(),
+            TraceId::from_hex("0af7651916cd43dd8448eb211c80319c").unwrap()
+        );
+        assert_eq!(span_ctx.trace_state().get("vendor"), Some("value"));
+        assert_eq!(
+            ctx.baggage().get("device-id").map(|v| v.to_string()),
+            Some("device".to_owned())
+        );
+
+        let mut properties = vec![];
+        inject(&ctx, &mut UserPropertiesInjector(&mut properties));
+        let extracted = UserPropertiesExtractor(&properties);
+        assert_eq!(extracted.get("traceparent"), Some(TRACEPARENT));
+        assert_eq!(extracted.get("tracestate"), Some("vendor=value"));
+        assert_eq!(extracted.get("baggage"), Some("device-id=device"));
+
+        let mut headers = HeaderMap::new();
+        inject(
+            &ctx.with_baggage(vec![KeyValue::new("tenant", "acme")]),
+            &mut HeaderInjector(&mut headers),
+        );
+        assert_eq!(
+            HeaderExtractor(&headers).get("traceparent"),
+            Some(TRACEPARENT)
+        );
+        assert!(headers["baggage"].to_str().unwrap().contains("tenant=acme"));
+    }
+
+    #[test]
+    fn test_malformed() {
+        for traceparent in ["", "broken", "00-0af7651916cd43dd", "00-xyz-abc-zz"] {
+            let mut headers = BTreeMap::new();
+            headers.insert("traceparent".to_owned(), traceparent.to_owned());
+
+            let ctx = extract(&MapExtractor(&headers));
+            assert!(!ctx.span().span_context().is_valid());
+
+            let mut injected = BTreeMap::new();
+            inject(&ctx, &mut MapInjector(&mut injected));
+            assert!(!injected.contains_key("traceparent"));
+        }
+    }
+
+    #[test]
+    fn test_remote_span() {
+        let tracer = global::tracer("test");
+        let trace_id = TraceId::from_hex("0af7651916cd43dd8448eb211c80319c").unwrap();
+
+        let mut headers = BTreeMap::new();
+        headers.insert("traceparent".to_owned(), TRACEPARENT.to_owned());
+        let span = remote_span(
+            &tracer,
+            &MapExtractor(&headers),
+            "consume",
+            SpanKind::Consumer,
+        );
+        assert_eq!(span.context().span().span_context().trace_id(), trace_id);
+
+        headers.insert("traceparent".to_owned(), "broken".to_owned());
+        let span = remote_span(
+            &tracer,
+            &MapExtractor(&headers),
+            "consume",
+            SpanKind::Consumer,
+        );
+        assert_ne!(span.context().span().span_context().trace_id(), trace_id);
+    }
+}
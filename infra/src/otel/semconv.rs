@@ -0,0 +1,42 @@
+use opentelemetry::{Key, KeyValue};
+
+/// Device the message comes from.
+pub const DEVICE_ID: Key = Key::from_static_str("device.id");
+/// Topic the device published the message to.
+pub const MQTT_TOPIC: Key = Key::from_static_str("mqtt.topic");
+
+/// Baggage entries set by the MQTT bridge, recorded as attributes of every
+/// span continuing the trace.
+pub const PROPAGATED: [Key; 2] = [DEVICE_ID, MQTT_TOPIC];
+
+pub const MESSAGING_SYSTEM: Key = Key::from_static_str("messaging.system");
+pub const MESSAGING_DESTINATION: Key = Key::from_static_str("messaging.destination");
+pub const MESSAGING_OPERATION: Key = Key::from_static_str("messaging.operation");
+pub const MESSAGING_MESSAGE_ID: Key = Key::from_static_str("messaging.message_id");
+
+pub const RABBITMQ: &str = "rabbitmq";
+pub const KAFKA: &str = "kafka";
+pub const NATS: &str = "nats";
+pub const MQTT: &str = "mqtt";
+
+pub const PUBLISH: &str = "publish";
+pub const PROCESS: &str = "process";
+
+/// Messaging attributes of a span publishing to or consuming from
+/// `destination`, i.e. an exchange, a queue, a topic or a subject.
+pub fn messaging(
+    system: &'static str,
+    destination: &str,
+    operation: &'static str,
+    message_id: Option<&str>,
+) -> Vec<KeyValue> {
+    let mut attributes = vec![
+        KeyValue::new(MESSAGING_SYSTEM, system),
+        KeyValue::new(MESSAGING_DESTINATION, destination.to_owned()),
+        KeyValue::new(MESSAGING_OPERATION, operation),
+    ];
+    if let Some(id) = message_id {
+        attributes.push(KeyValue::new(MESSAGING_MESSAGE_ID, id.to_owned()));
+    }
+    attributes
+}
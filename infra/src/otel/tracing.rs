@@ -1,16 +1,18 @@
+use super::{propagation, semconv};
 use crate::env::Config;
 use log::debug;
 use opentelemetry::{
-    global::{BoxedSpan, BoxedTracer},
+    baggage::BaggageExt,
+    global::{self, BoxedTracer},
     sdk::{
         trace::{self, IdGenerator, Sampler},
         Resource,
     },
-    trace::{Span, SpanKind, TraceContextExt, Tracer},
+    trace::{SpanKind, StatusCode, TraceContextExt, Tracer},
     Context, KeyValue,
 };
 use opentelemetry_otlp::{Protocol, WithExportConfig};
-use std::{error::Error, time::Duration};
+use std::{borrow::Cow, error::Error, fmt::Display, time::Duration};
 use tonic::metadata::*;
 // use tracing_opentelemetry::OpenTelemetrySpanExt;
 
@@ -20,6 +22,8 @@ pub fn setup(cfg: &Config) -> Result<(), Box<dyn Error>> {
     let mut map = MetadataMap::with_capacity(3);
     map.insert("api-key", cfg.otlp_key.parse().unwrap());
 
+    global::set_text_map_propagator(propagation::propagator());
+
     debug!("telemetry :: creating the tracer...");
 
     opentelemetry_otlp::new_pipeline()
@@ -49,62 +53,115 @@ pub fn setup(cfg: &Config) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-pub fn new_span(tracer: &BoxedTracer, name: &'static str) -> (Context, BoxedSpan) {
-    let span = tracer
-        .span_builder(name)
-        .with_kind(SpanKind::Consumer)
-        .start(tracer);
-
-    let span_ctx = span.span_context();
-    let trace_id = span_ctx.trace_id();
-    let span_id = span_ctx.span_id();
-    let flags = span_ctx.trace_flags();
-
-    let ctx = Context::current_with_span(span);
-    let ctx = ctx.with_value(trace_id);
-    let ctx = ctx.with_value(span_id);
-
-    (
-        ctx.with_value(flags),
-        tracer
-            .span_builder(name)
-            .with_kind(SpanKind::Consumer)
-            .start_with_context(tracer, &ctx),
-    )
+/// Span ended once the guard is dropped, the context holds the span so the
+/// spans started from it are its children.
+pub struct SpanGuard {
+    cx: Context,
 }
 
-pub fn new_ctx(tracer: &BoxedTracer, name: &'static str) -> Context {
-    let span = tracer
-        .span_builder(name)
-        .with_kind(SpanKind::Consumer)
-        .start(tracer);
-
-    let span_ctx = span.span_context();
-    let trace_id = span_ctx.trace_id();
-    let span_id = span_ctx.span_id();
-    let flags = span_ctx.trace_flags();
+impl SpanGuard {
+    pub fn context(&self) -> &Context {
+        &self.cx
+    }
+
+    pub fn set_attribute(&self, attribute: KeyValue) {
+        self.cx.span().set_attribute(attribute);
+    }
+
+    pub fn set_attributes(&self, attributes: Vec<KeyValue>) {
+        for attribute in attributes {
+            self.set_attribute(attribute);
+        }
+    }
+
+    pub fn set_status(&self, code: StatusCode, message: String) {
+        self.cx.span().set_status(code, message);
+    }
+
+    /// `Ok` status on success, the error is recorded as an exception event
+    /// and becomes the `Error` status otherwise.
+    pub fn record<T, E: Display>(&self, result: &Result<T, E>) {
+        match result {
+            Ok(_) => self.set_status(StatusCode::Ok, "".to_owned()),
+            Err(err) => self.record_error(err),
+        }
+    }
+
+    pub fn record_error<E: Display + ?Sized>(&self, err: &E) {
+        self.cx.span().add_event(
+            "exception",
+            vec![KeyValue::new("exception.message", err.to_string())],
+        );
+        self.set_status(StatusCode::Error, err.to_string());
+    }
+
+    /// Records `result` and ends the span.
+    pub fn finish<T, E: Display>(self, result: Result<T, E>) -> Result<T, E> {
+        self.record(&result);
+        result
+    }
+}
 
-    let ctx = Context::current_with_span(span);
-    let ctx = ctx.with_value(trace_id);
-    let ctx = ctx.with_value(span_id);
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        self.cx.span().end();
+    }
+}
 
-    ctx.with_value(flags)
+/// Root span of a new trace.
+pub fn new_span<N>(tracer: &BoxedTracer, name: N, kind: SpanKind) -> SpanGuard
+where
+    N: Into<Cow<'static, str>>,
+{
+    span_from_ctx(tracer, &Context::new(), name, kind)
 }
 
-pub fn ctx_from_ctx(tracer: &BoxedTracer, ctx: &Context, name: &'static str) -> Context {
+/// Child of the span of `ctx`, or root when it has none. The baggage of `ctx`
+/// is kept and its `semconv::PROPAGATED` entries become span attributes.
+pub fn span_from_ctx<N>(tracer: &BoxedTracer, ctx: &Context, name: N, kind: SpanKind) -> SpanGuard
+where
+    N: Into<Cow<'static, str>>,
+{
+    let attributes = semconv::PROPAGATED
+        .iter()
+        .filter_map(|key| {
+            let value = ctx.baggage().get(key.clone())?;
+            Some(KeyValue::new(key.clone(), value.clone()))
+        })
+        .collect();
+
     let span = tracer
         .span_builder(name)
-        .with_kind(SpanKind::Consumer)
+        .with_kind(kind)
+        .with_attributes(attributes)
         .start_with_context(tracer, ctx);
 
-    let span_ctx = span.span_context();
-    let trace_id = span_ctx.trace_id();
-    let span_id = span_ctx.span_id();
-    let flags = span_ctx.trace_flags();
-
-    let ctx = Context::current_with_span(span);
-    let ctx = ctx.with_value(trace_id);
-    let ctx = ctx.with_value(span_id);
+    SpanGuard {
+        cx: ctx.with_span(span),
+    }
+}
 
-    ctx.with_value(flags)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::AmqpError;
+    use opentelemetry::{baggage::BaggageExt, global};
+
+    #[test]
+    fn test_span_guard() {
+        let tracer = global::tracer("test");
+
+        let span = new_span(&tracer, format!("owned {}", "name"), SpanKind::Internal);
+        assert!(span.finish(Ok::<_, AmqpError>(())).is_ok());
+
+        let ctx = Context::new().with_baggage(vec![KeyValue::new(semconv::DEVICE_ID, "device")]);
+        let span = span_from_ctx(&tracer, &ctx, "child", SpanKind::Producer);
+        assert_eq!(
+            span.context().baggage().get(semconv::DEVICE_ID),
+            Some(&"device".into())
+        );
+        assert!(span
+            .finish(Err::<(), _>(AmqpError::PublishingError {}))
+            .is_err());
+    }
 }
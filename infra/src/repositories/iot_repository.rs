@@ -1,18 +1,43 @@
-use crate::{errors::RepositoriesError, otel};
+use crate::{
+    amqp::{outbox, types::PublishData},
+    errors::RepositoriesError,
+    mqtt::types::TempMessage,
+    otel,
+};
 use async_trait::async_trait;
 use deadpool_postgres::Pool;
+use log::error;
 use opentelemetry::{
     global::{self, BoxedTracer},
-    trace::FutureExt,
+    trace::{FutureExt, SpanKind},
     Context,
 };
 use std::{sync::Arc, time::Duration};
 
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS temperatures (
+    id BIGSERIAL PRIMARY KEY,
+    temp REAL NOT NULL,
+    time BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+)";
+
+const INSERT_TEMP: &str = "INSERT INTO temperatures (temp, time) VALUES ($1, $2)";
+
 #[async_trait]
 pub trait IoTRepository {
     async fn get(&self, ctx: &Context) -> Result<(), RepositoriesError>;
     async fn save(&self, ctx: &Context) -> Result<(), RepositoriesError>;
     async fn find(&self, ctx: &Context) -> Result<(), RepositoriesError>;
+    /// Saves `msg` and writes `data` to the outbox in the same transaction,
+    /// the outbox relay publishes it to `exchange` once committed.
+    async fn save_and_publish(
+        &self,
+        ctx: &Context,
+        msg: &TempMessage,
+        exchange: &str,
+        key: &str,
+        data: &PublishData,
+    ) -> Result<(), RepositoriesError>;
 }
 
 pub struct IoTRepositoryImpl {
@@ -23,40 +48,92 @@ pub struct IoTRepositoryImpl {
 #[async_trait]
 impl IoTRepository for IoTRepositoryImpl {
     async fn get(&self, ctx: &Context) -> Result<(), RepositoriesError> {
-        let cx = otel::tracing::ctx_from_ctx(&self.tracer, ctx, "sql get");
+        let span = otel::tracing::span_from_ctx(&self.tracer, ctx, "sql get", SpanKind::Client);
 
         tokio::time::sleep(Duration::from_millis(50))
-            .with_context(cx)
+            .with_context(span.context().clone())
             .await;
-        Ok(())
+        span.finish(Ok(()))
     }
 
     async fn save(&self, ctx: &Context) -> Result<(), RepositoriesError> {
-        let cx = otel::tracing::ctx_from_ctx(&self.tracer, ctx, "sql save");
+        let span = otel::tracing::span_from_ctx(&self.tracer, ctx, "sql save", SpanKind::Client);
 
         tokio::time::sleep(Duration::from_millis(100))
-            .with_context(cx)
+            .with_context(span.context().clone())
             .await;
 
-        Ok(())
+        span.finish(Ok(()))
     }
 
     async fn find(&self, ctx: &Context) -> Result<(), RepositoriesError> {
-        let _cx = otel::tracing::ctx_from_ctx(&self.tracer, ctx, "sql find");
+        let _span = otel::tracing::span_from_ctx(&self.tracer, ctx, "sql find", SpanKind::Client);
 
         let mut _client = self.pool.get().await.unwrap();
 
-        // let statement = client
-        //     .prepare("SELECT * FROM foo WHERE bar = $1")
-        //     .await
-        //     .unwrap();
-        // let rows = client.query(&statement, &[&""]).await.unwrap();
-
         Ok(())
     }
+
+    async fn save_and_publish(
+        &self,
+        ctx: &Context,
+        msg: &TempMessage,
+        exchange: &str,
+        key: &str,
+        data: &PublishData,
+    ) -> Result<(), RepositoriesError> {
+        let span = otel::tracing::span_from_ctx(
+            &self.tracer,
+            ctx,
+            "sql save and publish",
+            SpanKind::Client,
+        );
+
+        let result = async {
+            let mut client = self.pool.get().await.map_err(|err| {
+                error!("failure to get a db connection - {:?}", err);
+                RepositoriesError::InternalError
+            })?;
+            let tx = client.transaction().await.map_err(|err| {
+                error!("failure to begin transaction - {:?}", err);
+                RepositoriesError::InternalError
+            })?;
+
+            tx.execute(INSERT_TEMP, &[&msg.temp, &(msg.time as i64)])
+                .await
+                .map_err(|err| {
+                    error!("failure to save temperature - {:?}", err);
+                    RepositoriesError::InternalError
+                })?;
+
+            outbox::enqueue(&*tx, span.context(), exchange, key, data)
+                .await
+                .map_err(|_| RepositoriesError::InternalError)?;
+
+            tx.commit().await.map_err(|err| {
+                error!("failure to commit transaction - {:?}", err);
+                RepositoriesError::InternalError
+            })
+        }
+        .await;
+        span.finish(result)
+    }
 }
 
 impl IoTRepositoryImpl {
+    /// Creates the `temperatures` table when missing.
+    pub async fn migrate(pool: &Pool) -> Result<(), RepositoriesError> {
+        let client = pool.get().await.map_err(|err| {
+            error!("failure to get a db connection - {:?}", err);
+            RepositoriesError::InternalError
+        })?;
+
+        client.batch_execute(CREATE_TABLE).await.map_err(|err| {
+            error!("failure to create temperatures table - {:?}", err);
+            RepositoriesError::InternalError
+        })
+    }
+
     pub fn new(pool: Arc<Pool>) -> Arc<dyn IoTRepository + Send + Sync> {
         Arc::new(IoTRepositoryImpl {
             tracer: global::tracer("iot_repository"),
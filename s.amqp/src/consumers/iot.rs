@@ -1,6 +1,6 @@
 use app::ConsumeIotMessageService;
 use async_trait::async_trait;
-use infra::{amqp::topology::ConsumerHandler, errors::AmqpError};
+use infra::{amqp::topology::TypedConsumerHandler, errors::HandlerError, mqtt::types::TempMessage};
 use opentelemetry::Context;
 use std::sync::Arc;
 
@@ -9,17 +9,14 @@ pub struct IoTConsumer {
 }
 
 #[async_trait]
-impl ConsumerHandler for IoTConsumer {
-    async fn exec(&self, ctx: &Context, data: &[u8]) -> Result<(), AmqpError> {
+impl TypedConsumerHandler<TempMessage> for IoTConsumer {
+    async fn exec(&self, ctx: &Context, msg: &TempMessage) -> Result<(), HandlerError> {
         println!("Consumer");
 
-        // let msg =
-        //     serde_json::from_slice::<Message>(data).map_err(|_| AmqpError::ParsePayloadError {})?;
-
         self.service
-            .consume(ctx, data)
+            .consume(ctx, msg)
             .await
-            .map_err(|_| AmqpError::PublishingError)?;
+            .map_err(|_| HandlerError::Retry("failure to consume iot msg".to_owned()))?;
 
         Ok(())
     }
@@ -28,7 +25,7 @@ impl ConsumerHandler for IoTConsumer {
 impl IoTConsumer {
     pub fn new(
         service: Arc<dyn ConsumeIotMessageService + Send + Sync>,
-    ) -> Arc<dyn ConsumerHandler + Send + Sync> {
+    ) -> Arc<dyn TypedConsumerHandler<TempMessage> + Send + Sync> {
         Arc::new(IoTConsumer { service })
     }
 }
@@ -2,15 +2,19 @@ mod consumers;
 
 use app::ConsumeIoTMessageServiceImpl;
 use consumers::iot::IoTConsumer;
-use futures_util::StreamExt;
 use infra::{
-    amqp::client::Amqp,
-    amqp::topology::{AmqpTopology, ExchangeDefinition, QueueBindingDefinition, QueueDefinition},
+    amqp::{
+        client::Amqp,
+        dispatcher::{shutdown_signal, AmqpDispatcher},
+        outbox::{self, OutboxConfig, OutboxRelay},
+        topology::AmqpTopology,
+    },
+    database,
     env::Config,
     logging, otel,
+    repositories::iot_repository::IoTRepositoryImpl,
 };
-use log::error;
-use std::error::Error;
+use std::{error::Error, sync::Arc};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -22,46 +26,26 @@ async fn main() -> Result<(), Box<dyn Error>> {
     otel::tracing::setup(&cfg)?;
     let amqp = Amqp::new(&cfg).await?;
 
-    let topology = AmqpTopology::new()
-        .exchange(ExchangeDefinition::name("exchange_top_test1").direct())
-        .queue(
-            QueueDefinition::name("queue_top_test1")
-                .with_dlq()
-                .with_retry(18000, 3)
-                .binding(QueueBindingDefinition::new(
-                    "exchange_top_test1",
-                    "queue_top_test1",
-                    "exchange_top_test1_queue_top_test1",
-                )),
-        )
-        .boxed();
+    let topology = AmqpTopology::from_yaml(include_str!("../topology.yaml"))?.boxed();
 
     amqp.clone().install_topology(&topology).await?;
 
     let def = topology.get_consumers_def("queue_top_test1").unwrap();
-    let mut consumer = amqp.consumer(def.queue, def.queue).await?;
-    let spawn_iot = tokio::spawn({
-        let cloned = amqp.clone();
 
-        let service = ConsumeIoTMessageServiceImpl::new(amqp.clone());
-        let handler = IoTConsumer::new(service);
+    let pool = Arc::new(database::conn(&cfg).await);
+    outbox::migrate(&pool).await?;
+    IoTRepositoryImpl::migrate(&pool).await?;
 
-        async move {
-            while let Some(delivery) = consumer.next().await {
-                match delivery {
-                    Ok(d) => match cloned.consume(&def, handler.clone(), &d).await {
-                        Ok(_) => {}
-                        _ => error!("errors consume msg"),
-                    },
-                    _ => error!("error receiving delivery msg"),
-                };
-            }
-        }
-    });
+    let relay = OutboxRelay::new(amqp.clone(), pool.clone(), OutboxConfig::default());
+    let relay = tokio::spawn(relay.run_until(shutdown_signal()));
 
-    let (tk1,) = tokio::join!(spawn_iot);
+    let service = ConsumeIoTMessageServiceImpl::new(IoTRepositoryImpl::new(pool));
 
-    tk1?;
+    AmqpDispatcher::new(amqp.clone())
+        .register_typed(def, IoTConsumer::new(service))
+        .run()
+        .await?;
 
+    relay.await?;
     Ok(())
 }
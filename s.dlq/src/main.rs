@@ -0,0 +1,155 @@
+use clap::{Parser, Subcommand};
+use infra::{
+    amqp::{
+        client::Amqp,
+        dlq::{DlqFilter, DlqInspector, DlqMessage},
+    },
+    env::Config,
+    logging,
+};
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+#[derive(Parser)]
+#[clap(
+    name = "dlq",
+    about = "Inspect, dump and replay dead-lettered messages"
+)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List the messages with their headers
+    List(Args),
+    /// Dump the messages as JSONL
+    Dump {
+        #[clap(flatten)]
+        args: Args,
+        /// Output file, stdout when omitted
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Publish the messages back to their original exchange and routing key
+    Replay(Args),
+}
+
+#[derive(clap::Args)]
+struct Args {
+    /// DLQ name, e.g. queue_top_test1-dlq
+    queue: String,
+    /// Only messages with this type header
+    #[clap(long = "type")]
+    msg_type: Option<String>,
+    /// Only messages from this device
+    #[clap(long)]
+    device: Option<String>,
+    /// Only messages dead-lettered at or after this unix timestamp
+    #[clap(long)]
+    since: Option<u64>,
+    /// Only messages dead-lettered at or before this unix timestamp
+    #[clap(long)]
+    until: Option<u64>,
+    /// Max messages read from the queue
+    #[clap(long, default_value = "1000")]
+    limit: usize,
+}
+
+impl Args {
+    fn filter(&self) -> DlqFilter {
+        DlqFilter {
+            msg_type: self.msg_type.clone(),
+            device_id: self.device.clone(),
+            since: self.since,
+            until: self.until,
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    std::env::set_var("RUST_LOG", "warn");
+
+    let cli = Cli::parse();
+
+    let mut cfg = Config::new();
+    cfg.app_name = "dlq";
+    cfg.log_level = "warn";
+
+    logging::setup(&cfg)?;
+
+    let amqp = Amqp::new(&cfg).await?;
+    let inspector = DlqInspector::new(&cfg, amqp.clone()).await?;
+
+    match cli.command {
+        Command::List(args) => {
+            let messages = inspector
+                .list(&args.queue, &args.filter(), args.limit)
+                .await?;
+            for msg in messages.iter() {
+                print_message(msg);
+            }
+            println!("{} messages", messages.len());
+        }
+        Command::Dump { args, output } => {
+            let messages = inspector
+                .list(&args.queue, &args.filter(), args.limit)
+                .await?;
+
+            let mut writer: Box<dyn Write> = match output {
+                Some(path) => Box::new(File::create(path)?),
+                _ => Box::new(io::stdout()),
+            };
+            for msg in messages {
+                writeln!(writer, "{}", serde_json::to_string(&msg)?)?;
+            }
+        }
+        Command::Replay(args) => {
+            let replayed = inspector
+                .replay(&args.queue, &args.filter(), args.limit)
+                .await?;
+            for msg in replayed.iter() {
+                println!(
+                    "replayed {} to {}/{}",
+                    msg.message_id,
+                    msg.exchange.clone().unwrap_or_default(),
+                    msg.routing_key.clone().unwrap_or_default()
+                );
+            }
+            println!("{} messages replayed", replayed.len());
+        }
+    }
+
+    amqp.close().await?;
+
+    Ok(())
+}
+
+fn print_message(msg: &DlqMessage) {
+    println!("message: {}", msg.message_id);
+    println!("  type: {}", msg.msg_type);
+    println!("  traceparent: {}", msg.traceparent);
+    println!("  failure reason: {}", msg.failure_reason);
+    println!("  device: {}", msg.device_id.clone().unwrap_or_default());
+    println!(
+        "  origin: {}/{}",
+        msg.exchange.clone().unwrap_or_default(),
+        msg.routing_key.clone().unwrap_or_default()
+    );
+    for death in msg.deaths.iter() {
+        println!(
+            "  x-death: queue={} reason={} count={} time={}",
+            death.queue,
+            death.reason,
+            death.count,
+            death.time.unwrap_or_default()
+        );
+    }
+    println!("  payload: {}", msg.payload);
+}
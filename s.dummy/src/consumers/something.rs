@@ -1,5 +1,5 @@
 use async_trait::async_trait;
-use infra::{amqp::topology::ConsumerHandler, errors::AmqpError};
+use infra::{amqp::topology::ConsumerHandler, errors::HandlerError};
 use opentelemetry::Context;
 use std::sync::Arc;
 
@@ -9,7 +9,7 @@ pub struct SomethingConsumer {
 
 #[async_trait]
 impl ConsumerHandler for SomethingConsumer {
-    async fn exec(&self, _ctx: &Context, _data: &[u8]) -> Result<(), AmqpError> {
+    async fn exec(&self, _ctx: &Context, _data: &[u8]) -> Result<(), HandlerError> {
         println!("{}", self.msg);
 
         Ok(())
@@ -1,5 +1,6 @@
 use app::ExampleService;
-use opentelemetry::{trace::FutureExt, Context};
+use infra::otel::propagation::{self, HeaderExtractor};
+use opentelemetry::trace::FutureExt;
 use protos::iot::{
     iot_data_server::IotData, GetIoTDataRequest, GetIoTDataResponse, IoTDataMessage,
 };
@@ -20,9 +21,10 @@ impl IoTGrpcService {
 impl IotData for IoTGrpcService {
     async fn get_io_t_data(
         &self,
-        _request: Request<GetIoTDataRequest>,
+        request: Request<GetIoTDataRequest>,
     ) -> Result<Response<GetIoTDataResponse>, Status> {
-        let ctx = Context::new();
+        let headers = request.metadata().clone().into_headers();
+        let ctx = propagation::extract(&HeaderExtractor(&headers));
 
         match self.service.get(&ctx.clone()).with_context(ctx).await {
             Ok(_) => Ok(Response::new(GetIoTDataResponse {
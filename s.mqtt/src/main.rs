@@ -2,9 +2,8 @@ mod controllers;
 
 use app::DeliveryIoTMessageServiceImpl;
 use infra::{
-    amqp::client::Amqp,
     env::Config,
-    logging,
+    logging, messaging,
     mqtt::{
         client::MQTT,
         types::{IoTServiceKind, MetadataKind},
@@ -22,9 +21,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     logging::setup(&cfg)?;
     otel::tracing::setup(&cfg)?;
-    let amqp = Amqp::new(&cfg).await?;
+    let publisher = messaging::client::publisher(&cfg).await?;
 
-    let delivery_service = DeliveryIoTMessageServiceImpl::new(amqp.clone());
+    let delivery_service = DeliveryIoTMessageServiceImpl::new(publisher);
 
     let mut mqtt = MQTT::new(cfg);
     let mut eventloop = mqtt.connect();
@@ -0,0 +1,127 @@
+use clap::{Parser, Subcommand};
+use infra::{
+    amqp::{client::Amqp, migration::TopologyPlanner, topology::AmqpTopology},
+    env::Config,
+    logging,
+};
+use std::{error::Error, fs, path::PathBuf, process};
+
+#[derive(Parser)]
+#[clap(
+    name = "topology",
+    about = "Compare, migrate and export the RabbitMQ topology"
+)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the differences between the topology file and the broker
+    Plan {
+        /// YAML or TOML topology file
+        file: PathBuf,
+    },
+    /// Apply the topology file when every change is safe
+    Migrate {
+        /// YAML or TOML topology file
+        file: PathBuf,
+    },
+    /// Export the topology file as RabbitMQ definitions JSON
+    Export {
+        /// YAML or TOML topology file
+        file: PathBuf,
+        /// Virtual host of the exported definitions
+        #[clap(long, default_value = "/")]
+        vhost: String,
+        /// Output file, stdout when omitted
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Draw the topology file as a Graphviz or Mermaid diagram
+    Graph {
+        /// YAML or TOML topology file
+        file: PathBuf,
+        /// Diagram format
+        #[clap(long, default_value = "dot", possible_values = ["dot", "mermaid"])]
+        format: String,
+        /// Output file, stdout when omitted
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    std::env::set_var("RUST_LOG", "warn");
+
+    let cli = Cli::parse();
+
+    let mut cfg = Config::new();
+    cfg.app_name = "topology";
+    cfg.log_level = "warn";
+
+    logging::setup(&cfg)?;
+
+    match cli.command {
+        Command::Plan { file } => {
+            let topology = AmqpTopology::from_file(file)?;
+            let amqp = Amqp::new(&cfg).await?;
+
+            let plan = TopologyPlanner::new(amqp.clone()).plan(&topology).await?;
+            print!("{}", plan);
+            amqp.close().await?;
+
+            if !plan.is_safe() {
+                eprintln!("unsafe changes found, they must be migrated manually");
+                process::exit(2);
+            }
+            if plan.has_changes() {
+                process::exit(1);
+            }
+        }
+        Command::Migrate { file } => {
+            let topology = AmqpTopology::from_file(file)?;
+            let amqp = Amqp::new(&cfg).await?;
+
+            let plan = TopologyPlanner::new(amqp.clone())
+                .migrate(&topology)
+                .await?;
+            print!("{}", plan);
+            amqp.close().await?;
+        }
+        Command::Export {
+            file,
+            vhost,
+            output,
+        } => {
+            let topology = AmqpTopology::from_file(file)?;
+            let definitions = serde_json::to_string_pretty(&topology.to_definitions(&vhost))?;
+            write(output, &definitions)?;
+        }
+        Command::Graph {
+            file,
+            format,
+            output,
+        } => {
+            let topology = AmqpTopology::from_file(file)?;
+            let graph = match format.as_str() {
+                "mermaid" => topology.to_mermaid(),
+                _ => topology.to_graphviz(),
+            };
+            write(output, &graph)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write(output: Option<PathBuf>, content: &str) -> Result<(), Box<dyn Error>> {
+    match output {
+        Some(path) => fs::write(path, content)?,
+        _ => println!("{}", content),
+    }
+
+    Ok(())
+}